@@ -1,2 +0,0 @@
-use crate::primitives::NijikaControlBlockT;
-
@@ -1,4 +1,4 @@
 mod stage;
 pub use stage::NijikaPBFTStageApi;
 mod message;
-pub use message::NijikaPBFTMessageApi;
\ No newline at end of file
+pub use message::{NijikaPBFTMessageApi, NIJIKA_PENDING_ROUNDS};
\ No newline at end of file
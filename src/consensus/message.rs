@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug};
 use serde::Serialize;
 
 use crate::primitives::{
@@ -6,104 +6,372 @@ use crate::primitives::{
     NijikaResult,
     NijikaPBFTMessage,
     NijikaPBFTMessageType,
-    NijikaPBFTStage,
+    NijikaPrepared,
     NijikaError,
     HashValue,
+    NijikaDigest,
     NijikaControlBlockT,
-    NijikaDataBlockT
+    NijikaDataBlockT,
+    NijikaIdT,
+    NIJIKA_PBFT_MSG_QUEUE
 };
 
+use crate::hash::hash;
+use crate::evidence::{NijikaEvidence, is_conflicting, NIJIKA_EVIDENCE_ROUNDS};
+use crate::network::{NijikaMessage, NijikaMessageType, NijikaMessageDataType};
+
 use super::NijikaPBFTStageApi;
 
-pub trait NijikaPBFTMessageApi<'a, CB: NijikaControlBlockT + Serialize + Debug + Clone  + 'a, DB: NijikaDataBlockT + Serialize + Debug + Clone + 'a, ID: Clone + Copy + Debug + Serialize + 'a>: NijikaPBFTStageApi<'a, CB, DB, ID> {
-    fn handle_pbft_message(&mut self, peer_id: HashValue, message: &'a NijikaPBFTMessage<CB, ID>) -> NijikaResult<()> {
+/// how many rounds ahead of ours pbft messages are kept for replay, a node further behind catches up with sync
+pub const NIJIKA_PENDING_ROUNDS: u64 = 4;
+
+pub trait NijikaPBFTMessageApi<'a, CB: NijikaControlBlockT + Serialize + Debug + Clone  + 'a, DB: NijikaDataBlockT<Hasher = CB::Hasher> + Serialize + Debug + Clone + 'a, ID: Clone + Copy + Debug + Serialize + NijikaIdT + 'a>: NijikaPBFTStageApi<'a, CB, DB, ID> {
+    /// reject messages that are unsigned, whose signature was not made by their source node,
+    /// or whose payload does not match what was signed
+    fn verify_pbft_message(&self, message: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<()> {
+        if !message.is_signed() {
+            return Err(NijikaError::InvalidSignature(format!("unsigned pbft message from {:?}", message.get_source())));
+        }
+        let content = message.signing_bytes()?;
+        if !self.verify_signature(message.get_source(), &content, message.get_signature())? {
+            return Err(NijikaError::InvalidSignature(format!("bad signature on pbft message from {:?}", message.get_source())));
+        }
+        match message.get_type() {
+            NijikaPBFTMessageType::PrePrepare | NijikaPBFTMessageType::NewView | NijikaPBFTMessageType::Reply => {
+                match message.get_control_block() {
+                    Some(control_block) if control_block.header_hash()? == message.get_control_block_hash() => Ok(()),
+                    Some(_) => Err(NijikaError::InvalidControlBlock(format!("control block does not match the signed hash {}", message.get_control_block_hash()))),
+                    None => Err(NijikaError::InvalidControlBlock(format!("Missing control block from a message: {:#?}", message)))
+                }
+            },
+            NijikaPBFTMessageType::Prepare | NijikaPBFTMessageType::Commit => {
+                match message.get_vote() {
+                    Some(vote) if hash::serialized(&vote.get_id())? != hash::serialized(&message.get_source())? => {
+                        Err(NijikaError::InvalidPBFTMessage(format!("vote of {:?} carried by a message from {:?}", vote.get_id(), message.get_source())))
+                    },
+                    Some(vote) if vote.get_result() == vote.get_reason().is_some() => {
+                        Err(NijikaError::InvalidPBFTMessage(format!("a vote of {:?} must carry a reason if and only if it is false", vote.get_id())))
+                    },
+                    Some(_) => Ok(()),
+                    None => Err(NijikaError::InvalidPBFTMessage(String::from("An invalid pbft message with no nijika vote")))
+                }
+            },
+            NijikaPBFTMessageType::ViewChange => Ok(()),
+        }?;
+        // the proof is not signed itself, only its hashes are
+        if !message.get_proof().is_empty() {
+            let hashes = message.get_proof().iter().map(|proof| proof.hash()).collect::<NijikaResult<Vec<_>>>()?;
+            if hashes != message.get_proof_hashes() {
+                return Err(NijikaError::InvalidPBFTMessage(format!("proof of a message from {:?} does not match the signed hashes", message.get_source())));
+            }
+            for proof in message.get_proof() {
+                self.verify_pbft_message(proof)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// check a message carried as proof as if it had just been received in its round, whose sortition ran on the given seed
+    fn verify_proof_message(&self, message: &NijikaPBFTMessage<CB, ID>, seed: u64) -> NijikaResult<()> {
+        let credential = message.get_credential();
+        if !message.get_type().sender_roles().contains(&credential.role) {
+            return Err(NijikaError::InvalidCredential(format!("a {:?} message cannot be sent by a {:?}", message.get_type(), credential.role)));
+        }
+        self.verify_credential_with_seed(message.get_source(), message.get_round_num(), message.get_view(), seed, credential)
+    }
+
+    /// the weight of the distinct senders of the messages, a sender counted once with its highest weight
+    fn sender_weight(&self, messages: &[NijikaPBFTMessage<CB, ID>]) -> NijikaResult<u64> {
+        let mut weights: HashMap<HashValue, u64> = HashMap::new();
+        for message in messages {
+            let weight = weights.entry(hash::serialized(&message.get_source())?).or_insert(0);
+            *weight = (*weight).max(message.get_weight());
+        }
+        Ok(weights.values().sum())
+    }
+
+    /// the commit votes carried by a Reply must reach a quorum for its block, in its round view
+    fn verify_commit_certificate(&self, reply: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<()> {
+        let certificate = reply.get_proof();
+        if certificate.len() != reply.get_proof_hashes().len() {
+            return Err(NijikaError::InvalidPBFTMessage(format!("Reply of {:?} without its commit certificate", reply.get_source())));
+        }
+        self.verify_commits(reply.get_round_num(), reply.get_view(), reply.get_control_block_hash(), self.get_vrf_seed(), certificate)
+    }
+
+    /// the given signed commit votes must reach a quorum for the block in one view of its round, whose sortition ran on the given seed
+    fn verify_commits(&self, round_num: u64, view: u64, block_hash: NijikaDigest<CB>, seed: u64, commits: &[NijikaPBFTMessage<CB, ID>]) -> NijikaResult<()> {
+        for message in commits {
+            let is_commit = matches!(message.get_type(), NijikaPBFTMessageType::Commit) && message.get_vote().as_ref().is_some_and(|vote| vote.get_result());
+            if !is_commit || message.get_round_num() != round_num || message.get_view() != view || message.get_control_block_hash() != block_hash {
+                return Err(NijikaError::InvalidPBFTMessage(format!("a message of {:?} does not commit block {}", message.get_source(), block_hash)));
+            }
+            self.verify_pbft_message(message)?;
+            self.verify_proof_message(message, seed)?;
+        }
+        if !self.get_round().has_quorum(self.sender_weight(commits)?) {
+            return Err(NijikaError::InvalidPBFTMessage(format!("no commit quorum for block {}", block_hash)));
+        }
+        Ok(())
+    }
+
+    /// the prepared certificate carried by a ViewChange: the proposal of a block and the prepare votes that reached a quorum
+    /// for it, all in one view of the ViewChange's round. None if the sender had prepared nothing
+    fn verify_prepared_certificate(&self, view_change: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<Option<NijikaPrepared<NijikaDigest<CB>>>> {
+        let certificate = view_change.get_proof();
+        if certificate.len() != view_change.get_proof_hashes().len() {
+            return Err(NijikaError::InvalidPBFTMessage(format!("ViewChange of {:?} without its prepared certificate", view_change.get_source())));
+        }
+        let first = match certificate.first() {
+            Some(first) => first,
+            None => return Ok(None)
+        };
+        let (view, block_hash) = (first.get_view(), first.get_control_block_hash());
+        if view > view_change.get_view() {
+            return Err(NijikaError::InvalidPBFTMessage(format!("certificate of view {} carried by a ViewChange of view {}", view, view_change.get_view())));
+        }
+        let mut proposer_key = None;
+        for message in certificate {
+            if message.get_round_num() != view_change.get_round_num() || message.get_view() != view || message.get_control_block_hash() != block_hash {
+                return Err(NijikaError::InvalidPBFTMessage(format!("certificate of {:?} mixes rounds, views or blocks", view_change.get_source())));
+            }
+            self.verify_proof_message(message, self.get_vrf_seed())?;
+            match (message.get_type(), message.get_vote()) {
+                // the proposal carries the block but no vote
+                (NijikaPBFTMessageType::PrePrepare | NijikaPBFTMessageType::NewView, _) => {
+                    proposer_key = Some(message.get_credential().public_key.clone());
+                },
+                (NijikaPBFTMessageType::Prepare, Some(vote)) if vote.get_result() => (),
+                _ => return Err(NijikaError::InvalidPBFTMessage(format!("a {:?} message cannot prepare a block", message.get_type())))
+            }
+        }
+        let proposer_key = proposer_key
+            .ok_or_else(|| NijikaError::InvalidPBFTMessage(format!("certificate of {:?} without the proposal", view_change.get_source())))?;
+        let votes = certificate.iter().filter(|message| matches!(message.get_type(), NijikaPBFTMessageType::Prepare)).cloned().collect::<Vec<_>>();
+        if !self.get_round().has_quorum(self.sender_weight(&votes)?) {
+            return Err(NijikaError::InvalidPBFTMessage(format!("certificate of {:?} without a prepare quorum", view_change.get_source())));
+        }
+        let messages = certificate.iter().map(|message| message.hash()).collect::<NijikaResult<_>>()?;
+        Ok(Some(NijikaPrepared { view, block_hash, proposer_key, messages }))
+    }
+
+    /// a NewView must carry the ViewChange messages of a quorum for the view before it, and propose again the block of
+    /// the highest prepared certificate among them if there is one. Returns that certificate
+    fn verify_new_view(&self, new_view: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<Option<NijikaPrepared<NijikaDigest<CB>>>> {
+        let view = new_view.get_view();
+        if view == 0 || new_view.get_proof().len() != new_view.get_proof_hashes().len() {
+            return Err(NijikaError::InvalidPBFTMessage(format!("NewView of {:?} without the ViewChanges proving it", new_view.get_source())));
+        }
+        let mut highest: Option<NijikaPrepared<NijikaDigest<CB>>> = None;
+        for view_change in new_view.get_proof() {
+            if !matches!(view_change.get_type(), NijikaPBFTMessageType::ViewChange)
+            || view_change.get_round_num() != new_view.get_round_num() || view_change.get_view() != view - 1 {
+                return Err(NijikaError::InvalidPBFTMessage(format!("NewView of {:?} carries a message that does not end view {}", new_view.get_source(), view - 1)));
+            }
+            self.verify_proof_message(view_change, self.get_vrf_seed())?;
+            if let Some(prepared) = self.verify_prepared_certificate(view_change)? {
+                if highest.as_ref().is_none_or(|h| prepared.view > h.view) {
+                    highest = Some(prepared);
+                }
+            }
+        }
+        if !self.get_round().has_quorum(self.sender_weight(new_view.get_proof())?) {
+            return Err(NijikaError::InvalidPBFTMessage(format!("NewView of {:?} without a ViewChange quorum", new_view.get_source())));
+        }
+        match highest {
+            Some(prepared) if prepared.block_hash != new_view.get_control_block_hash() => {
+                Err(NijikaError::InvalidControlBlock(format!("NewView of {:?} does not propose the prepared block {}", new_view.get_source(), prepared.block_hash)))
+            },
+            highest => Ok(highest)
+        }
+    }
+
+    /// keep a prepared certificate learnt from a ViewChange if it is later than ours, with its messages,
+    /// so that our own ViewChange carries it on
+    fn adopt_prepared(&mut self, view_change: &NijikaPBFTMessage<CB, ID>, prepared: NijikaPrepared<NijikaDigest<CB>>) -> NijikaResult<()> {
+        if !self.get_round_mut().set_prepared(prepared) {
+            return Ok(());
+        }
+        for message in view_change.get_proof() {
+            if self.get_pbft_message(&message.hash()?).is_none() {
+                self.store_pbft_message(message.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// verify and store a message from the given peer, act on it, then announce it to the other peers.
+    /// Messages of past rounds and of rounds too far ahead are dropped. Those of later rounds are kept for
+    /// replay_pbft_messages but only announced once their credential could be checked
+    fn handle_pbft_message(&mut self, peer_id: HashValue, message: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<()> {
+        self.verify_pbft_message(message)?;
+        let round_num = message.get_round_num();
+        if round_num < self.get_round_num() || round_num > self.get_round_num().saturating_add(NIJIKA_PENDING_ROUNDS) {
+            return Ok(());
+        }
+        let message_hash = self.store_pbft_message(message.clone())?;
+        if let Some(evidence) = self.detect_equivocation(message_hash, message)? {
+            println!("[Equivocation] {:?} in round {}", evidence.get_offender(), evidence.get_round_num());
+            self.broadcast_evidence(&evidence, None)?;
+        }
+        if !self.apply_pbft_message(message)? {
+            return Ok(());
+        }
+        self.broadcast_hash_message(NijikaMessageDataType::PBFTMsgHash, message_hash, Some(peer_id))
+    }
+
+    /// check both messages of the evidence as if they had just been received, and that they conflict
+    fn verify_evidence(&self, evidence: &NijikaEvidence<CB, ID>) -> NijikaResult<()> {
+        self.verify_pbft_message(evidence.get_first())?;
+        self.verify_pbft_message(evidence.get_second())?;
+        if !is_conflicting(evidence.get_first(), evidence.get_second())? {
+            return Err(NijikaError::InvalidPBFTMessage(format!("evidence against {:?} without a conflict", evidence.get_offender())));
+        }
+        Ok(())
+    }
+
+    /// look for an earlier message of the same sender that the verified one contradicts,
+    /// and return the evidence if it is new
+    fn detect_equivocation(&mut self, message_hash: NijikaDigest<CB>, message: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<Option<NijikaEvidence<CB, ID>>> {
+        let first = match self.get_evidence_pool_mut() {
+            Some(pool) => pool.observe(message_hash, message)?,
+            None => return Ok(None)
+        };
+        let first = match first.and_then(|hash| self.get_pbft_message(&hash)) {
+            Some(first) => first.clone(),
+            None => return Ok(None)
+        };
+        // the same vote on the same block signed again, e.g. with another credential, is left to apply_pbft_message
+        if !is_conflicting(&first, message)? {
+            return Ok(None);
+        }
+        let evidence = NijikaEvidence::new(first, message.clone())?;
+        let is_new = match self.get_evidence_pool_mut() {
+            Some(pool) => pool.insert(evidence.clone())?.is_some(),
+            None => false
+        };
+        Ok(if is_new { Some(evidence) } else { None })
+    }
+
+    /// push the evidence to every peer but the source, it is too rare to be worth an Invite round trip
+    fn broadcast_evidence(&self, evidence: &NijikaEvidence<CB, ID>, source: Option<HashValue>) -> NijikaResult<()> {
+        let content = evidence.encode()?;
+        let transport = self.get_transport();
+        let message = NijikaMessage::new_message(transport.get_local_id(), NijikaMessageType::Data, NijikaMessageDataType::Evidence, content);
+        transport.broadcast(&message, source)
+    }
+
+    /// act on a verified message. Only messages of the current round can be checked against our seed,
+    /// so the others are left in the pool until replay_pbft_messages picks them up.
+    /// Returns whether the sender's credential was checked, i.e. whether the message may be relayed
+    fn apply_pbft_message(&mut self, message: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<bool> {
         let message_type = message.get_type();
         let round_num = message.get_round_num();
-        let message_source = message.get_source();
+        let view = message.get_view();
         let control_block_hash = message.get_control_block_hash();
+        if round_num != self.get_round_num() {
+            return Ok(false);
+        }
+        let credential = message.get_credential();
+        if !message_type.sender_roles().contains(&credential.role) {
+            return Err(NijikaError::InvalidCredential(format!("a {:?} message cannot be sent by a {:?}", message_type, credential.role)));
+        }
+        self.verify_credential(message.get_source(), round_num, view, credential)?;
+        // late messages of a finished round are still worth relaying to the peers that did not finish it
+        if self.get_round().is_end() {
+            return Ok(true);
+        }
+        let voter = hash::serialized(&message.get_source())?;
+        // stage messages only count in the view they were sent in
+        let current_view = view == self.get_round().get_view();
 
-        match message_type {
-            NijikaPBFTMessageType::PrePrepare => {
-                if let Some(control_block) = message.get_control_block() {
-                    let message_hash = message.hash()?;
-                    self.append_pbft_message_queue(message_hash)?;
-                    self.insert_pbft_message_pool(message_hash, message.clone())?;
-                    if round_num != self.get_round_num() ||
-                    self.get_round().get_stage() != NijikaPBFTStage::WaitPrePrepare ||
-                    self.get_role() != NijikaNodeRole::VALIDATOR {
-                        self.set_vrf_seed(control_block.get_seed());
-                        self.set_round_control_block(control_block.clone())?;
-                    } else {
-                        self.handle_pre_prepare(control_block.clone())?;
-                    }
-                    self.broadcast_hash_message(message_hash, Some(peer_id))?;
-                    Ok(())
-                } else {
-                    Err(NijikaError::InvalidControlBlock(format!("Missing control block from a message: {:#?}", message)))
+        let applied = match (message_type, message.get_control_block(), message.get_vote()) {
+            (NijikaPBFTMessageType::PrePrepare, Some(_), _) if view != 0 => {
+                Err(NijikaError::InvalidPBFTMessage(format!("PrePrepare of {:?} in view {}, only a NewView may open it", message.get_source(), view)))
+            },
+            (NijikaPBFTMessageType::PrePrepare | NijikaPBFTMessageType::NewView, Some(control_block), _) => {
+                // the block of a prepared certificate was proven by the proposer of the view it was prepared in
+                let proposer_key = match message_type {
+                    NijikaPBFTMessageType::NewView => self.verify_new_view(message)?.map(|prepared| prepared.proposer_key),
+                    _ => None
+                }.unwrap_or_else(|| credential.public_key.clone());
+                if current_view {
+                    self.handle_proposal(control_block.clone(), proposer_key)?;
                 }
-            }
-            NijikaPBFTMessageType::Prepare => {
-                if let Some(vote) = message.get_vote() {
-                    let pbft_msg = NijikaPBFTMessage::<CB, ID>::new_vote_message(
-                        message_source,
-                        round_num,
-                        message_type,
-                        control_block_hash,
-                        vote,
-                    );
-                    let pbft_msg_hash = pbft_msg.hash()?;
-                    self.append_pbft_message_queue(pbft_msg_hash)?;
-                    if round_num == self.get_round_num() &&
-                    (self.get_role() == NijikaNodeRole::VALIDATOR ||
-                    self.get_role() == NijikaNodeRole::PROPOSER) {
-                        self.handle_prepare(vote.get_result())?;
-                    }
-                    self.broadcast_hash_message(pbft_msg_hash, Some(peer_id))?;
-                    Ok(())
-                } else {
-                    Err(NijikaError::InvalidPBFTMessage(format!("An invalid pbft message with no nijika vote")))
+                Ok(())
+            },
+            (NijikaPBFTMessageType::Prepare, _, Some(vote)) => {
+                if current_view &&
+                (self.get_role() == NijikaNodeRole::VALIDATOR ||
+                self.get_role() == NijikaNodeRole::PROPOSER) {
+                    self.handle_prepare(voter, control_block_hash, message.get_weight(), vote.get_result())?;
                 }
+                Ok(())
             },
-            NijikaPBFTMessageType::Commit => {
-                if let Some(vote) = message.get_vote() {
-                    let pbft_msg = NijikaPBFTMessage::<CB, ID>::new_vote_message(
-                        message_source,
-                        round_num,
-                        message_type,
-                        control_block_hash,
-                        vote,
-                    );
-                    let pbft_msg_hash = pbft_msg.hash()?;
-                    self.append_pbft_message_queue(pbft_msg_hash)?;
-                    if round_num == self.get_round_num() &&
-                    (self.get_role() == NijikaNodeRole::VALIDATOR ||
-                    self.get_role() == NijikaNodeRole::PROPOSER) {
-                        self.handle_commit(vote.get_result())?;
-                    }
-                    self.broadcast_hash_message(pbft_msg_hash, Some(peer_id))?;
-                    Ok(())
-                } else {
-                    Err(NijikaError::InvalidPBFTMessage(format!("An invalid pbft message with no nijika vote")))
+            (NijikaPBFTMessageType::Commit, _, Some(vote)) => {
+                if current_view &&
+                (self.get_role() == NijikaNodeRole::VALIDATOR ||
+                self.get_role() == NijikaNodeRole::PROPOSER) {
+                    self.handle_commit(voter, control_block_hash, message.get_weight(), vote.get_result())?;
                 }
+                Ok(())
+            },
+            (NijikaPBFTMessageType::Reply, Some(control_block), _) => {
+                // a reply of any view is final once the commit quorum it carries holds, for every node:
+                // a committee member holding another proposal of the view learns the committed block from it
+                self.verify_commit_certificate(message)?;
+                self.handle_reply(voter, control_block, message.get_weight())
             },
-            NijikaPBFTMessageType::Reply => {
-                if let Some(control_block) = message.get_control_block() {
-                    let message_hash = message.hash()?;
-                    self.append_pbft_message_queue(message_hash)?;
-                    if round_num == self.get_round_num() &&
-                    (self.get_role() == NijikaNodeRole::PACKER
-                    || self.get_role() == NijikaNodeRole::NORMAL
-                    || self.get_round().get_stage() == NijikaPBFTStage::WaitReply) {
-                        self.handle_reply(control_block)?;
-                    }
-                    self.broadcast_hash_message(message_hash, Some(peer_id))?;
-                    Ok(())
-                } else {
-                    Err(NijikaError::InvalidControlBlock(format!("Missing control block from a message: {:#?}", message)))
+            (NijikaPBFTMessageType::ViewChange, _, _) => {
+                if let Some(prepared) = self.verify_prepared_certificate(message)? {
+                    self.adopt_prepared(message, prepared)?;
                 }
+                self.handle_view_change(voter, view, message.get_weight(), message.hash()?)
             },
-            #[allow(unreachable_patterns)]
-            _ => Err(NijikaError::InvalidPBFTMessage(format!("an invalid message containing an unknown type")))
+            _ => Err(NijikaError::InvalidPBFTMessage(format!("a {:?} message without its payload", message_type)))
+        };
+        applied.map(|_| true)
+    }
+
+    /// start the given round: run the sortition, pack if we are a packer, then act on the messages that arrived early.
+    /// thresh is the percentage of the expected validator weight each stage needs
+    fn enter_round(&mut self, round_num: u64, thresh: u64) -> NijikaResult<()> {
+        println!("[Round Start] {}", round_num);
+        if let Some(pool) = self.get_evidence_pool_mut() {
+            pool.prune(round_num.saturating_sub(NIJIKA_EVIDENCE_ROUNDS));
+        }
+        self.start_a_new_round(round_num, thresh)?;
+        if self.get_round().has_role(NijikaNodeRole::PACKER) {
+            self.pack()?;
+        }
+        self.replay_pbft_messages()
+    }
+
+    /// jump to a later round the network is already in, keeping the quorum threshold of the current round
+    fn fast_forward(&mut self, round_num: u64) -> NijikaResult<()> {
+        if round_num <= self.get_round_num() {
+            return Ok(());
+        }
+        let thresh = self.get_round().get_thresh();
+        self.enter_round(round_num, thresh)
+    }
+
+    /// act on the pooled messages of the current round, e.g. those that arrived before the round started,
+    /// and announce the ones whose credential holds now that it could be checked
+    fn replay_pbft_messages(&mut self) -> NijikaResult<()> {
+        let round_num = self.get_round_num();
+        let hashes = self.get_hash_queue(Some(NIJIKA_PBFT_MSG_QUEUE))?.clone();
+        for message_hash in hashes {
+            let message = match self.get_pbft_message(&message_hash) {
+                Some(message) if message.get_round_num() == round_num => message.clone(),
+                _ => continue
+            };
+            match self.apply_pbft_message(&message) {
+                Ok(true) => self.broadcast_hash_message(NijikaMessageDataType::PBFTMsgHash, message_hash, None)?,
+                Ok(false) => (),
+                Err(e) => println!("replay error: {:?}", e)
+            }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -1,9 +1,8 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Instant};
 
-use rug::Integer;
 use serde::Serialize;
 
-use crate::{primitives::{
+use crate::{hash::hash, primitives::{
     NijikaNodeT,
     NijikaNodeRole,
     NijikaResult,
@@ -13,61 +12,304 @@ use crate::{primitives::{
     NijikaControlBlockT,
     NijikaError,
     NijikaVote,
+    NijikaRejectReason,
     NijikaRound,
-    NijikaDataBlockT
-}, vrf::{self, NijikaVRFParams, NijikaVRFClientS}};
+    NijikaDataBlockT,
+    NijikaIdT,
+    NijikaVRFCredential,
+    NijikaSortition,
+    NijikaPrepared,
+    NijikaStakeChange,
+    NijikaSignedStakeChange,
+    HashValue,
+    NijikaDigest,
+    NIJIKA_PBFT_MSG_QUEUE
+}, network::NijikaMessageDataType, storage::NijikaRecordKind, vrf::{NijikaVRFParams, NijikaVRFClientS}};
 
-pub trait NijikaPBFTStageApi<'a, CB: NijikaControlBlockT + Serialize + Debug + Clone + 'a, DB: NijikaDataBlockT + Serialize + Debug + Clone + 'a, ID: Clone + Copy + Debug + Serialize  + 'a>: NijikaNodeT<'a, CB, DB, ID> {
-    fn vrf_selection (&mut self) -> NijikaResult<NijikaNodeRole> {
-        let (expected, total) = self.get_vrf_params();
-        let mut vrf_client = NijikaVRFClientS::new(self.get_weight(), expected, total);
+/// the proposal of the highest prepared certificate carried by the ViewChange messages, whose block a NewView must propose again
+pub fn highest_prepared_proposal<CB: NijikaControlBlockT + Serialize + Debug, ID: Clone + Copy + Debug + Serialize + NijikaIdT>(view_changes: &[NijikaPBFTMessage<CB, ID>]) -> Option<&NijikaPBFTMessage<CB, ID>> {
+    view_changes.iter()
+        .flat_map(|view_change| view_change.get_proof())
+        .filter(|message| message.get_control_block().is_some())
+        .max_by_key(|message| message.get_view())
+}
+
+pub trait NijikaPBFTStageApi<'a, CB: NijikaControlBlockT + Serialize + Debug + Clone + 'a, DB: NijikaDataBlockT<Hasher = CB::Hasher> + Serialize + Debug + Clone + 'a, ID: Clone + Copy + Debug + Serialize + NijikaIdT  + 'a>: NijikaNodeT<'a, CB, DB, ID> {
+    /// run the sortition of every role, each with its own expected size, and return all the roles won with their credentials.
+    /// The weights are those of the given round, as verify_credential_with_seed looks them up
+    fn vrf_selection (&mut self, round_num: u64, view: u64) -> NijikaResult<NijikaSortition> {
+        let weight = self.get_weight_of_at(self.get_id(), round_num);
+        let total = self.get_total_weight_at(round_num);
         let seed = self.get_vrf_seed();
-        // e.g. {NijikaNodeRole::Packer: (vrf_hash, vrf_proof)}
-        let role_keys = [NijikaNodeRole::PACKER, NijikaNodeRole::PROPOSER, NijikaNodeRole::VALIDATOR];
-        // let mut role_map : HashMap<NijikaNodeRole, (Vec<u8>, Vec<u8>)>= HashMap::new();
-        for role in role_keys {
-            let params = NijikaVRFParams {
-                weight: self.get_weight(),
-                round: self.get_round_num(),
-                seed: seed,
-                role
-            };
-            if let Ok((proof, hash)) = vrf_client.prove(self.get_secret_key(), &params) {
-                // role_map.insert(role, (hash, proof));
-                // let hash_value: Float = Integer::from_digits(&hash, rug::integer::Order::Lsf) / Integer::i_pow_u(2, 256);
-                let (index, _) = vrf_client.sortition(&hash);
-                if index > 0 {
-                    match self.update_proof(proof, hash) {
-                        Ok(_) => {
-                            return Ok(role);
-                        },
-                        Err(e) => {
-                            return Err(e);
-                        }
-                    }
-                }
-            } else {
-                return Err(NijikaError::VRFError(format!("Error when generating node's hash and proof in role: {:?}", role)));
+        let mut sortition = NijikaSortition::new();
+        for role in [NijikaNodeRole::PACKER, NijikaNodeRole::PROPOSER, NijikaNodeRole::VALIDATOR] {
+            let (expected, _) = self.get_vrf_params(role);
+            let mut vrf_client = NijikaVRFClientS::new(weight, expected, total);
+            let params = NijikaVRFParams { weight, round: round_num, view, seed, role };
+            let (proof, hash) = vrf_client.prove(self.get_secret_key(), &params)
+                .map_err(|_| NijikaError::VRFError(format!("Error when generating node's hash and proof in role: {:?}", role)))?;
+            let (index, _) = vrf_client.sortition(&hash);
+            if index > 0 {
+                let public_key = self.get_public_key().to_vec();
+                sortition.insert(NijikaVRFCredential { role, sub_users: index, proof, hash, public_key });
             }
         }
-        Ok(NijikaNodeRole::NORMAL)
-    }
-    fn start_a_new_round(&mut self, round_num: u64, thresh: u64, expected: u64) -> NijikaResult<()> {
-        let role = self.vrf_selection()?;
-        match role {
-            NijikaNodeRole::NORMAL => self.set_round(NijikaRound::new(thresh, expected, round_num, role, NijikaPBFTStage::WaitReply)),
-            NijikaNodeRole::PACKER => self.set_round(NijikaRound::new(thresh, expected, round_num, role, NijikaPBFTStage::Packing)),
-            NijikaNodeRole::VALIDATOR => self.set_round(NijikaRound::new(thresh, expected, round_num, role, NijikaPBFTStage::WaitPrePrepare)),
-            NijikaNodeRole::PROPOSER => {
-                self.set_round(NijikaRound::new(thresh, expected, round_num, role, NijikaPBFTStage::PrePrepare))?;
-                self.prepare()
+        if let Some(credential) = sortition.get(sortition.primary_role()) {
+            self.update_proof(credential.proof.clone(), credential.hash.clone())?;
+        }
+        Ok(sortition)
+    }
+    /// re-run the sortition of the credential's claimed role for the sender of a message in the given view of the current round
+    fn verify_credential(&self, sender: ID, round_num: u64, view: u64, credential: &NijikaVRFCredential) -> NijikaResult<()> {
+        self.verify_credential_with_seed(sender, round_num, view, self.get_vrf_seed(), credential)
+    }
+    /// as verify_credential, for a round drawn from another seed, e.g. that of a block received by sync.
+    /// The sender's weight is looked up as it was in the given round
+    fn verify_credential_with_seed(&self, sender: ID, round_num: u64, view: u64, seed: u64, credential: &NijikaVRFCredential) -> NijikaResult<()> {
+        match self.get_public_key_of(sender) {
+            Some(public_key) if public_key == credential.public_key.as_slice() => (),
+            _ => return Err(NijikaError::InvalidCredential(format!("unknown VRF public key of {:?}", sender)))
+        }
+        let (expected, _) = self.get_vrf_params(credential.role);
+        let total = self.get_total_weight_at(round_num);
+        let weight = self.get_weight_of_at(sender, round_num);
+        let mut vrf_client = NijikaVRFClientS::new(weight, expected, total);
+        let params = NijikaVRFParams {
+            weight,
+            round: round_num,
+            view,
+            seed,
+            role: credential.role,
+        };
+        match vrf_client.verify(&credential.public_key, &credential.proof, &params, &credential.hash) {
+            Ok(true) => (),
+            Ok(false) => return Err(NijikaError::InvalidCredential(format!("VRF hash does not match the proof of {:?}", sender))),
+            Err(e) => return Err(NijikaError::VRFError(format!("{:?}", e)))
+        }
+        let (index, _) = vrf_client.sortition(&credential.hash);
+        if index == 0 || index != credential.sub_users {
+            return Err(NijikaError::InvalidCredential(format!(
+                "{:?} claims {} sub-users as {:?} but won {}", sender, credential.sub_users, credential.role, index
+            )));
+        }
+        Ok(())
+    }
+    /// thresh is the percentage of the expected validator weight, get_vrf_params(VALIDATOR), each stage needs, e.g. NIJIKA_DEFAULT_QUORUM
+    fn start_a_new_round(&mut self, round_num: u64, thresh: u64) -> NijikaResult<()> {
+        // whatever the last round committed was evicted already, the rest may be packed again
+        self.release_packed_transactions()?;
+        // the sortition of a round runs on the seed of the last committed block
+        let seed = self.get_ledger().get_tip_block().get_seed();
+        self.set_vrf_seed(seed);
+        let sortition = self.vrf_selection(round_num, 0)?;
+        let role = sortition.primary_role();
+        let stage = match role {
+            NijikaNodeRole::NORMAL => NijikaPBFTStage::WaitReply,
+            NijikaNodeRole::PACKER => NijikaPBFTStage::Packing,
+            NijikaNodeRole::VALIDATOR => NijikaPBFTStage::WaitPrePrepare,
+            NijikaNodeRole::PROPOSER => NijikaPBFTStage::PrePrepare,
+        };
+        let (expected, _) = self.get_vrf_params(NijikaNodeRole::VALIDATOR);
+        let mut round = NijikaRound::new(thresh, expected, round_num, role, stage);
+        round.set_sortition(sortition);
+        self.set_round(round)?;
+        if role == NijikaNodeRole::PROPOSER {
+            self.pre_prepare()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// enter the given view of the current round: run the sortition again and restart from its first stage
+    fn start_a_new_view(&mut self, view: u64) -> NijikaResult<()> {
+        println!("[View Change] round {} enters view {}", self.get_round_num(), view);
+        self.release_packed_transactions()?;
+        let sortition = self.vrf_selection(self.get_round_num(), view)?;
+        let role = sortition.primary_role();
+        let stage = match role {
+            // data blocks packed in view 0 are still waiting to be referenced
+            NijikaNodeRole::NORMAL | NijikaNodeRole::PACKER => NijikaPBFTStage::WaitReply,
+            NijikaNodeRole::VALIDATOR => NijikaPBFTStage::WaitPrePrepare,
+            NijikaNodeRole::PROPOSER => NijikaPBFTStage::PrePrepare,
+        };
+        let mut round = self.get_round().new_view(view, role, stage);
+        round.set_sortition(sortition);
+        self.set_round(round)?;
+        if role == NijikaNodeRole::PROPOSER {
+            self.pre_prepare()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// should be called periodically; asks for a view change once the current stage has passed its deadline.
+    /// A node that reached Reply has committed the round's block and has nothing left to ask for
+    fn check_timeout(&mut self) -> NijikaResult<bool> {
+        let round = self.get_round();
+        let committee = round.get_role() == NijikaNodeRole::VALIDATOR || round.get_role() == NijikaNodeRole::PROPOSER;
+        if committee && round.get_stage() != NijikaPBFTStage::Reply && round.is_timeout(Instant::now()) {
+            self.view_change()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// ask to leave the current view, carrying the highest prepared certificate known so that the next view proposes that block again
+    /// A proposer without a validator seat has no say and keeps waiting for the others
+    fn view_change(&mut self) -> NijikaResult<()> {
+        let view = self.get_round().get_view();
+        let credential = match self.get_round().get_vote_credential() {
+            Some(credential) => credential.clone(),
+            None => return Ok(())
+        };
+        let weight = credential.sub_users;
+        let mut pbft_msg = NijikaPBFTMessage::new_view_change_message(self.get_id(), self.get_round_num(), view, credential);
+        pbft_msg.set_proof(self.prepared_certificate())?;
+        self.sign_pbft_message(&mut pbft_msg)?;
+        let voter = hash::serialized(&self.get_id())?;
+        let pbft_msg_hash = pbft_msg.hash()?;
+        self.get_round_mut().add_view_change_vote(view, voter, weight, pbft_msg_hash);
+        // wait a whole stage again before asking once more
+        let stage = self.get_round().get_stage();
+        self.get_round_mut().set_stage(stage);
+        // asking again with the same certificate signs the very same message, which is only announced once more
+        if self.get_pbft_message(&pbft_msg_hash).is_none() {
+            self.store_pbft_message(pbft_msg)?;
+        }
+        self.broadcast_hash_message(NijikaMessageDataType::PBFTMsgHash, pbft_msg_hash, None)?;
+        println!("[Request ViewChange] round {} view {}", self.get_round_num(), view);
+        self.try_change_view(view)
+    }
+    fn handle_view_change(&mut self, voter: HashValue, view: u64, weight: u64, message_hash: NijikaDigest<CB>) -> NijikaResult<()> {
+        println!("[Handle ViewChange]");
+        if view < self.get_round().get_view() {
+            return Ok(());
+        }
+        if !self.get_round_mut().add_view_change_vote(view, voter, weight, message_hash) {
+            return Ok(());
+        }
+        self.try_change_view(view)
+    }
+    /// leave the given view once enough weight has asked for it
+    fn try_change_view(&mut self, view: u64) -> NijikaResult<()> {
+        let round = self.get_round();
+        if view >= round.get_view() && round.has_quorum(round.get_view_change_weight(view)) {
+            self.start_a_new_view(view + 1)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// the proposal and prepare messages of the highest prepared block known, without their own proofs
+    fn prepared_certificate(&self) -> Vec<NijikaPBFTMessage<CB, ID>> {
+        match self.get_round().get_prepared() {
+            Some(prepared) => prepared.messages.iter()
+                .filter_map(|hash| self.get_pbft_message(hash))
+                .map(|message| message.without_proof())
+                .collect(),
+            None => vec![]
+        }
+    }
+
+    /// the pooled messages of the current round view that were counted for the round's control block at the given stage:
+    /// for Prepare the proposal carrying the block and the prepare votes, for Commit the commit votes
+    fn counted_messages(&self, stage: NijikaPBFTStage) -> NijikaResult<Vec<NijikaDigest<CB>>> {
+        let round = self.get_round();
+        let (round_num, view) = (round.get_round_num(), round.get_view());
+        let block_hash = match round.get_control_block() {
+            Some(block) => block.header_hash()?,
+            None => return Ok(vec![])
+        };
+        let mut messages = vec![];
+        for message_hash in self.get_hash_queue(Some(NIJIKA_PBFT_MSG_QUEUE))? {
+            let message = match self.get_pbft_message(message_hash) {
+                Some(message) if message.get_round_num() == round_num && message.get_view() == view
+                    && message.get_control_block_hash() == block_hash => message,
+                _ => continue
+            };
+            let counted = match (message.get_type(), message.get_vote()) {
+                (NijikaPBFTMessageType::PrePrepare | NijikaPBFTMessageType::NewView, _) => {
+                    stage == NijikaPBFTStage::Prepare && message.get_control_block().is_some()
+                },
+                (NijikaPBFTMessageType::Prepare, Some(vote)) | (NijikaPBFTMessageType::Commit, Some(vote)) => {
+                    let vote_stage = match message.get_type() {
+                        NijikaPBFTMessageType::Prepare => NijikaPBFTStage::Prepare,
+                        _ => NijikaPBFTStage::Commit
+                    };
+                    let voter = hash::serialized(&message.get_source())?;
+                    vote_stage == stage && vote.get_result()
+                    && round.get_vote(stage, &voter) == Some(&(block_hash, message.get_weight()))
+                },
+                _ => false
+            };
+            if counted {
+                messages.push(*message_hash);
             }
         }
+        Ok(messages)
+    }
+
+    /// remember the round's control block as prepared in the current view, with the pooled messages of the prepare quorum
+    fn record_prepared(&mut self) -> NijikaResult<()> {
+        let round = self.get_round();
+        let view = round.get_view();
+        let block_hash = match round.get_control_block() {
+            Some(block) => block.header_hash()?,
+            None => return Ok(())
+        };
+        let messages = self.counted_messages(NijikaPBFTStage::Prepare)?;
+        // the key the block's seed was proven with, which is not the sender's for a block proposed again
+        let proposer_key = self.get_round().get_proposer_key().to_vec();
+        self.get_round_mut().set_prepared(NijikaPrepared { view, block_hash, proposer_key, messages });
+        Ok(())
     }
 
     fn commit_round(&mut self) -> NijikaResult<()> {
-        let block = self.get_round().get_control_block().expect("empty block in the round");
-        self.commit_control_block(block.clone())
+        let block = self.get_round().get_control_block().expect("empty block in the round").clone();
+        self.persist(NijikaRecordKind::ControlBlock, &block)?;
+        self.commit_control_block(block)
+    }
+
+    /// write the value to the node's storage, if it has one
+    fn persist<T: Serialize>(&mut self, kind: NijikaRecordKind, value: &T) -> NijikaResult<()> {
+        if self.get_storage_mut().is_none() {
+            return Ok(());
+        }
+        let content = bincode::serialize(value).map_err(|e| NijikaError::ParseError(format!("{}", e)))?;
+        self.persist_bytes(kind, &content)
+    }
+
+    /// write an already encoded record to the node's storage, if it has one
+    fn persist_bytes(&mut self, kind: NijikaRecordKind, content: &[u8]) -> NijikaResult<()> {
+        match self.get_storage_mut() {
+            Some(storage) => storage.append(kind, content),
+            None => Ok(())
+        }
+    }
+
+    /// persist a pbft message, then put it into the hash queue and the pool
+    fn store_pbft_message(&mut self, message: NijikaPBFTMessage<CB, ID>) -> NijikaResult<NijikaDigest<CB>> {
+        let message_hash = message.hash()?;
+        if self.get_storage_mut().is_some() {
+            self.persist_bytes(NijikaRecordKind::PBFTMessage, &message.encode()?)?;
+        }
+        self.append_pbft_message_queue(message_hash)?;
+        self.insert_pbft_message_pool(message_hash, message)?;
+        Ok(message_hash)
+    }
+
+    /// persist a data block, then put it into the hash queue and the pool
+    fn store_data_block(&mut self, block: DB) -> NijikaResult<NijikaDigest<CB>> {
+        let block_hash = block.hash()?;
+        self.persist(NijikaRecordKind::DataBlock, &block)?;
+        self.append_data_block_hash_queue(block_hash)?;
+        self.insert_data_block_pool(block_hash, block)?;
+        // the vote on the control block may have been waiting for this block
+        if self.get_round_mut().data_block_arrived(&block_hash) && self.get_round().get_stage() == NijikaPBFTStage::Prepare {
+            self.prepare()?;
+        }
+        Ok(block_hash)
     }
 
     fn end_round(&mut self) -> NijikaResult<()> {
@@ -85,14 +327,24 @@ pub trait NijikaPBFTStageApi<'a, CB: NijikaControlBlockT + Serialize + Debug + C
     }
 
     fn try_set_stage(&mut self, next: NijikaPBFTStage) -> NijikaResult<()> {
+        // a node never commits a block it has rejected, it waits for the view change instead
+        if next == NijikaPBFTStage::Commit && self.get_round().get_rejection().is_some() {
+            return Ok(());
+        }
         let next = self.get_round_mut().try_set_stage(next);
         match next {
             Ok(stage) => {
                 if stage == NijikaPBFTStage::Commit {
+                    self.record_prepared()?;
                     self.commit()
                 } else if stage == NijikaPBFTStage::Reply {
                     self.commit_round()?;
-                    self.reply()
+                    if self.get_role() == NijikaNodeRole::PROPOSER {
+                        self.reply()
+                    } else {
+                        println!("[Round Completed]");
+                        self.end_round()
+                    }
                 } else {
                     println!("stage error: cannot enter next stage");
                     Ok(())
@@ -105,6 +357,13 @@ pub trait NijikaPBFTStageApi<'a, CB: NijikaControlBlockT + Serialize + Debug + C
         }
     }
 
+    fn sign_pbft_message(&self, message: &mut NijikaPBFTMessage<CB, ID>) -> NijikaResult<()> {
+        let content = message.signing_bytes()?;
+        let signature = self.sign(&content)?;
+        message.set_signature(signature);
+        Ok(())
+    }
+
     fn check(&self, role: NijikaNodeRole, stage: NijikaPBFTStage) -> NijikaResult<()> {
         let current_round = self.get_round();
         let current_role = current_round.get_role();
@@ -119,57 +378,223 @@ pub trait NijikaPBFTStageApi<'a, CB: NijikaControlBlockT + Serialize + Debug + C
     }
     fn pre_prepare(&mut self) -> NijikaResult<()> {
         self.check(NijikaNodeRole::PROPOSER, NijikaPBFTStage::PrePrepare)?;
-        let control_block = self.new_control_block();
-        let control_block_hash = control_block.hash()?;
-        let pbft_msg = NijikaPBFTMessage::new_control_block_message(
+        let view = self.get_round().get_view();
+        // a new view is proven by the ViewChange messages that ended the previous one
+        let view_changes = match view {
+            0 => vec![],
+            _ => self.get_round().get_view_change_messages(view - 1).iter()
+                .filter_map(|hash| self.get_pbft_message(hash).cloned())
+                .collect::<Vec<NijikaPBFTMessage<CB, ID>>>()
+        };
+        // a block prepared in an earlier view may already be committed somewhere, so it is proposed again as it is
+        let (control_block, proposer_key) = match highest_prepared_proposal(&view_changes) {
+            Some(proposal) => (proposal.get_control_block().clone().unwrap(), proposal.get_credential().public_key.clone()),
+            None => {
+                let mut vrf_client = NijikaVRFClientS::new_raw();
+                let (seed, seed_proof) = vrf_client.prove_seed(self.get_secret_key(), self.get_vrf_seed(), self.get_round_num())
+                    .map_err(|e| NijikaError::VRFError(format!("{:?}", e)))?;
+                let mut block = self.new_control_block(seed, seed_proof);
+                self.load_control_block(&mut block)?;
+                (block, self.get_public_key().to_vec())
+            }
+        };
+        let control_block_hash = control_block.header_hash()?;
+        self.get_round_mut().set_proposer_key(proposer_key);
+        let message_type = if view == 0 {
+            NijikaPBFTMessageType::PrePrepare
+        } else {
+            NijikaPBFTMessageType::NewView
+        };
+        let mut pbft_msg = NijikaPBFTMessage::new_control_block_message(
             self.get_id(),
             self.get_round_num(),
-            NijikaPBFTMessageType::PrePrepare,
+            view,
+            message_type,
             control_block_hash,
-            control_block.clone()
+            control_block.clone(),
+            self.get_round().get_credential().clone()
         );
+        pbft_msg.set_proof(view_changes)?;
+        self.sign_pbft_message(&mut pbft_msg)?;
         self.set_round_control_block(control_block)?;
-        let pbft_msg_hash = pbft_msg.hash()?;
-        self.append_pbft_message_queue(pbft_msg_hash)?;
-        self.insert_pbft_message_pool(pbft_msg_hash, pbft_msg)?;
+        let pbft_msg_hash = self.store_pbft_message(pbft_msg)?;
+        self.broadcast_hash_message(NijikaMessageDataType::PBFTMsgHash, pbft_msg_hash, None)?;
+        // the pre-prepare carries no vote, a validator seat won as well is voted separately
+        if let Some(credential) = self.get_round().get_vote_credential().cloned() {
+            let voter = hash::serialized(&self.get_id())?;
+            let mut vote_msg = NijikaPBFTMessage::new_vote_message(
+                self.get_id(),
+                self.get_round_num(),
+                self.get_round().get_view(),
+                NijikaPBFTMessageType::Prepare,
+                control_block_hash,
+                NijikaVote::new_true(self.get_id()),
+                credential.clone()
+            );
+            self.sign_pbft_message(&mut vote_msg)?;
+            self.get_round_mut().add_vote(NijikaPBFTStage::Prepare, voter, control_block_hash, credential.sub_users)?;
+            let vote_msg_hash = self.store_pbft_message(vote_msg)?;
+            self.broadcast_hash_message(NijikaMessageDataType::PBFTMsgHash, vote_msg_hash, None)?;
+        }
         self.set_stage(NijikaPBFTStage::Prepare)?;
         println!("[Complete PrePrepare]");
-        Ok(())
+        // a proposer whose own validator seats are a quorum goes on at once, there may be no vote to wait for
+        self.try_set_stage(NijikaPBFTStage::Commit)
+    }
+    /// take the proposal of the current view. The sortition may pick more than one proposer, so the first block
+    /// taken in the view stays: a later one was never validated here and is dropped
+    fn handle_proposal(&mut self, control_block: CB, proposer_key: Vec<u8>) -> NijikaResult<()> {
+        if let Some(current) = self.get_round().get_control_block() {
+            if current.header_hash()? != control_block.header_hash()? {
+                println!("[Drop] another proposal for view {}", self.get_round().get_view());
+            }
+            return Ok(());
+        }
+        if self.get_round().get_stage() == NijikaPBFTStage::WaitPrePrepare && self.get_role() == NijikaNodeRole::VALIDATOR {
+            self.get_round_mut().set_proposer_key(proposer_key);
+            self.handle_pre_prepare(control_block)
+        } else {
+            self.set_round_control_block(control_block)?;
+            self.try_abort()?;
+            Ok(())
+        }
     }
     fn handle_pre_prepare(&mut self, control_block: CB) -> NijikaResult<()> {
         println!("[Handle PrePrepare]");
-        self.set_vrf_seed(control_block.get_seed());
         self.set_round_control_block(control_block)?;
         self.set_stage(NijikaPBFTStage::Prepare)?;
+        if self.try_abort()? {
+            return Ok(());
+        }
         self.prepare()
     }
 
 
 
 
+    /// check that the block's seed was derived by the proposer of the current view from the seed of the ledger tip
+    fn verify_seed(&self, block: &CB) -> NijikaResult<bool> {
+        let mut vrf_client = NijikaVRFClientS::new_raw();
+        let previous_seed = self.get_ledger().get_tip_block().get_seed();
+        let proposer_key = self.get_round().get_proposer_key();
+        match vrf_client.verify_seed(proposer_key, block.get_seed_proof(), previous_seed, block.get_round(), block.get_seed()) {
+            Ok(result) => Ok(result),
+            // a malformed proof
+            Err(_) => Ok(false)
+        }
+    }
+
+    /// check a proposed control block before voting on it; returns why it should be rejected, if it should
+    fn validate_control_block(&self, block: &CB) -> NijikaResult<Option<NijikaRejectReason>> {
+        if block.get_pre_hash() != &self.get_ledger().get_tip() {
+            return Ok(Some(NijikaRejectReason::WrongPreHash));
+        }
+        if block.get_round() != self.get_round_num() {
+            return Ok(Some(NijikaRejectReason::WrongRound));
+        }
+        if !self.verify_seed(block)? {
+            return Ok(Some(NijikaRejectReason::WrongSeed));
+        }
+        if !self.verify_control_block_signature(block)? {
+            return Ok(Some(NijikaRejectReason::InvalidSignature));
+        }
+        if block.get_data_block_pointers().iter().any(|hash| self.get_data_block(hash).is_none()) {
+            return Ok(Some(NijikaRejectReason::MissingDataBlock));
+        }
+        if !self.verify_stake_changes(block)? {
+            return Ok(Some(NijikaRejectReason::InvalidStakeChange));
+        }
+        Ok(None)
+    }
+
+    /// every stake change must be signed by the owner of the account it draws from for the block's round, a deposit
+    /// by the minting authority, and all of them must apply on the stake of the tip, so that committing the block cannot fail on them
+    fn verify_stake_changes(&self, block: &CB) -> NijikaResult<bool> {
+        for signed in block.get_stake_changes() {
+            let signer = match signed.change.get_owner() {
+                Some(account) => self.get_account_owner(&account),
+                None => self.get_minting_authority()
+            };
+            let owner = match signer {
+                Some(owner) => owner,
+                None => return Ok(false)
+            };
+            let content = NijikaSignedStakeChange::signing_bytes(&signed.change, block.get_round())?;
+            if !self.verify_signature(owner, &content, &signed.signature)? {
+                return Ok(false);
+            }
+        }
+        let changes = block.get_stake_changes().iter().map(|signed| signed.change).collect::<Vec<NijikaStakeChange>>();
+        match self.get_stake_registry() {
+            Some(registry) => Ok(registry.check(block.get_round(), &changes).is_ok()),
+            None => Ok(true)
+        }
+    }
+
     fn prepare(&mut self) -> NijikaResult<()> {
         self.check(NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Prepare)?;
-        let control_block = self.get_round_control_block();
-        let control_block_hash = control_block.hash()?;
-        let pbft_msg = NijikaPBFTMessage::new_vote_message(
+        let control_block = self.get_round_control_block().clone();
+        let control_block_hash = control_block.header_hash()?;
+        let rejection = self.validate_control_block(&control_block)?;
+        if rejection == Some(NijikaRejectReason::MissingDataBlock) {
+            return self.fetch_data_blocks(&control_block);
+        }
+        let vote = match rejection {
+            Some(reason) => {
+                println!("[Reject] {:?}", reason);
+                self.get_round_mut().reject(reason);
+                NijikaVote::new_false(self.get_id(), reason)
+            },
+            None => NijikaVote::new_true(self.get_id())
+        };
+        let mut pbft_msg = NijikaPBFTMessage::new_vote_message(
             self.get_id(),
             self.get_round_num(),
+            self.get_round().get_view(),
             NijikaPBFTMessageType::Prepare,
             control_block_hash,
-            NijikaVote::new_true(self.get_id())
+            vote,
+            self.get_round().get_credential().clone()
         );
-        let pbft_msg_hash = pbft_msg.hash()?;
-        self.append_pbft_message_queue(pbft_msg_hash)?;
-        self.insert_pbft_message_pool(pbft_msg_hash, pbft_msg)?;
-        self.broadcast_hash_message(pbft_msg_hash, None)?;
-        self.try_set_stage(NijikaPBFTStage::Commit)?;
+        self.sign_pbft_message(&mut pbft_msg)?;
+        let voter = hash::serialized(&self.get_id())?;
+        let weight = self.get_round().get_credential().sub_users;
+        if rejection.is_none() {
+            self.get_round_mut().add_vote(NijikaPBFTStage::Prepare, voter, control_block_hash, weight)?;
+        }
+        let pbft_msg_hash = self.store_pbft_message(pbft_msg)?;
+        self.broadcast_hash_message(NijikaMessageDataType::PBFTMsgHash, pbft_msg_hash, None)?;
+        match rejection {
+            Some(_) => self.handle_nay(NijikaPBFTStage::Prepare, voter, control_block_hash, weight),
+            None => self.try_set_stage(NijikaPBFTStage::Commit)
+        }
+    }
+    /// ask the peers for the data blocks the control block points to but the pool lacks.
+    /// The vote is cast once the last of them arrived, see store_data_block, or the stage times out
+    fn fetch_data_blocks(&mut self, block: &CB) -> NijikaResult<()> {
+        let missing = block.get_data_block_pointers().iter()
+            .filter(|hash| self.get_data_block(hash).is_none())
+            .copied()
+            .collect::<Vec<NijikaDigest<CB>>>();
+        println!("[Fetch] {} data blocks", missing.len());
+        for hash in missing.iter() {
+            self.request_data_block(*hash, None)?;
+        }
+        self.get_round_mut().await_data_blocks(missing);
         Ok(())
     }
-    fn handle_prepare(&mut self, vote_result: bool) -> NijikaResult<()> {
+    /// count the vote of the given voter with its sortition weight; duplicated votes are ignored
+    fn handle_prepare(&mut self, voter: HashValue, control_block_hash: NijikaDigest<CB>, weight: u64, vote_result: bool) -> NijikaResult<()> {
         println!("[Handle Prepare]");
-        if vote_result {
-            let current_round = self.get_round_mut();
-            current_round.vote_inc(NijikaPBFTStage::Prepare)?;
+        if !vote_result {
+            return self.handle_nay(NijikaPBFTStage::Prepare, voter, control_block_hash, weight);
+        }
+        let current_round = self.get_round_mut();
+        if !current_round.add_vote(NijikaPBFTStage::Prepare, voter, control_block_hash, weight)? {
+            return Ok(());
+        }
+        if self.try_abort()? {
+            return Ok(());
         }
         self.try_set_stage(NijikaPBFTStage::Commit)
     }
@@ -177,29 +602,78 @@ pub trait NijikaPBFTStageApi<'a, CB: NijikaControlBlockT + Serialize + Debug + C
 
 
 
+    /// count a vote against the control block, and leave the view as soon as the block cannot reach quorum any more
+    fn handle_nay(&mut self, stage: NijikaPBFTStage, voter: HashValue, control_block_hash: NijikaDigest<CB>, weight: u64) -> NijikaResult<()> {
+        if !self.get_round_mut().add_nay_vote(stage, voter, control_block_hash, weight)? {
+            return Ok(());
+        }
+        self.try_abort()?;
+        Ok(())
+    }
+    /// leave the view once the control block is rejected in a stage; returns whether the view was given up.
+    /// Checked on every vote and when the block itself arrives, since the nays may come before it
+    fn try_abort(&mut self) -> NijikaResult<bool> {
+        let round = self.get_round();
+        if round.is_aborted() {
+            return Ok(true);
+        }
+        if round.is_end() {
+            return Ok(false);
+        }
+        for stage in [NijikaPBFTStage::Prepare, NijikaPBFTStage::Commit] {
+            if self.get_round().is_rejected(stage)? {
+                println!("[Abort] the control block of view {} is rejected in {:?}", self.get_round().get_view(), stage);
+                self.get_round_mut().abort();
+                self.view_change()?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+
+
+
     fn commit(&mut self) -> NijikaResult<()> {
-        self.check(NijikaNodeRole::PROPOSER, NijikaPBFTStage::Commit)?;
-        let control_block = self.get_round_control_block();
-        let control_block_hash = control_block.hash()?;
-        let pbft_msg = NijikaPBFTMessage::new_vote_message(
-            self.get_id(),
-            self.get_round_num(),
-            NijikaPBFTMessageType::Commit,
-            control_block_hash,
-            NijikaVote::new_true(self.get_id())
-        );
-        let pbft_msg_hash = pbft_msg.hash()?;
-        self.append_pbft_message_queue(pbft_msg_hash)?;
-        self.insert_pbft_message_pool(pbft_msg_hash, pbft_msg)?;
-        self.broadcast_hash_message(pbft_msg_hash, None)?;
+        if self.get_role() == NijikaNodeRole::PROPOSER {
+            self.check(NijikaNodeRole::PROPOSER, NijikaPBFTStage::Commit)?;
+        } else {
+            self.check(NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Commit)?;
+        }
+        // a proposer without a validator seat only waits for the commit quorum
+        if let Some(credential) = self.get_round().get_vote_credential().cloned() {
+            let control_block_hash = self.get_round_control_block().header_hash()?;
+            let weight = credential.sub_users;
+            let mut pbft_msg = NijikaPBFTMessage::new_vote_message(
+                self.get_id(),
+                self.get_round_num(),
+                self.get_round().get_view(),
+                NijikaPBFTMessageType::Commit,
+                control_block_hash,
+                NijikaVote::new_true(self.get_id()),
+                credential
+            );
+            self.sign_pbft_message(&mut pbft_msg)?;
+            let voter = hash::serialized(&self.get_id())?;
+            self.get_round_mut().add_vote(NijikaPBFTStage::Commit, voter, control_block_hash, weight)?;
+            let pbft_msg_hash = self.store_pbft_message(pbft_msg)?;
+            self.broadcast_hash_message(NijikaMessageDataType::PBFTMsgHash, pbft_msg_hash, None)?;
+        }
         self.try_set_stage(NijikaPBFTStage::Reply)?;
         Ok(())
     }
-    fn handle_commit(&mut self, vote_result: bool) -> NijikaResult<()> {
+    /// count the vote of the given voter with its sortition weight; duplicated votes are ignored
+    fn handle_commit(&mut self, voter: HashValue, control_block_hash: NijikaDigest<CB>, weight: u64, vote_result: bool) -> NijikaResult<()> {
         println!("[Handle Commit]");
-        if vote_result {
-            let current_round = self.get_round_mut();
-            current_round.vote_inc(NijikaPBFTStage::Commit)?;
+        if !vote_result {
+            return self.handle_nay(NijikaPBFTStage::Commit, voter, control_block_hash, weight);
+        }
+        let current_round = self.get_round_mut();
+        if !current_round.add_vote(NijikaPBFTStage::Commit, voter, control_block_hash, weight)? {
+            return Ok(());
+        }
+        if self.try_abort()? {
+            return Ok(());
         }
         self.try_set_stage(NijikaPBFTStage::Reply)
     }
@@ -210,42 +684,60 @@ pub trait NijikaPBFTStageApi<'a, CB: NijikaControlBlockT + Serialize + Debug + C
     fn reply(&mut self) -> NijikaResult<()> {
         self.check(NijikaNodeRole::PROPOSER, NijikaPBFTStage::Reply)?;
         let control_block = self.get_round_control_block().clone();
-        let control_block_hash = control_block.hash()?;
-        let pbft_msg = NijikaPBFTMessage::new_control_block_message(
-            self.get_id().clone(),
+        let control_block_hash = control_block.header_hash()?;
+        let mut pbft_msg = NijikaPBFTMessage::new_control_block_message(
+            self.get_id(),
             self.get_round_num(),
-            NijikaPBFTMessageType::Prepare,
+            self.get_round().get_view(),
+            NijikaPBFTMessageType::Reply,
             control_block_hash,
-            control_block
+            control_block,
+            self.get_round().get_credential().clone()
         );
-        let pbft_msg_hash = pbft_msg.hash()?;
-        self.append_pbft_message_queue(pbft_msg_hash)?;
-        self.insert_pbft_message_pool(pbft_msg_hash, pbft_msg)?;
-        self.broadcast_hash_message(pbft_msg_hash, None)?;
+        // the reply carries the commit quorum, so that nodes outside the committee need not trust the proposer
+        let commits = self.counted_messages(NijikaPBFTStage::Commit)?.iter()
+            .filter_map(|hash| self.get_pbft_message(hash))
+            .map(|message| message.without_proof())
+            .collect();
+        pbft_msg.set_proof(commits)?;
+        self.sign_pbft_message(&mut pbft_msg)?;
+        let pbft_msg_hash = self.store_pbft_message(pbft_msg)?;
+        self.broadcast_hash_message(NijikaMessageDataType::PBFTMsgHash, pbft_msg_hash, None)?;
         println!("[Round Completed]");
         self.end_round()?;
         Ok(())
     }
-    fn handle_reply(&mut self, control_block: &'a CB) -> NijikaResult<()> {
+    /// nodes outside the committee learn the committed block from the proposer's reply, once its commit quorum is verified
+    fn handle_reply(&mut self, voter: HashValue, control_block: &CB, weight: u64) -> NijikaResult<()> {
         println!("[Handle Reply]");
-        if control_block.hash()? == self.get_round_control_block().hash()? {
-            let current_round = self.get_round_mut();
-            current_round.vote_inc(NijikaPBFTStage::Reply)?;
+        let current_round = self.get_round_mut();
+        if !current_round.add_vote(NijikaPBFTStage::Reply, voter, control_block.header_hash()?, weight)? || current_round.is_end() {
+            return Ok(());
         }
+        self.set_round_control_block(control_block.clone())?;
+        self.commit_round()?;
         self.try_end_round()
     }
 
 
 
 
+    /// a packer packs once per round, whatever other role it also won
     fn pack(&mut self) -> NijikaResult<()> {
-        self.check(NijikaNodeRole::PACKER, NijikaPBFTStage::Packing)?;
-        let data_block = self.new_data_block();
-        let data_block_hash = data_block.hash()?;
-        self.append_data_block_hash_queue(data_block_hash)?;
-        self.insert_data_block_pool(data_block_hash, data_block)?;
-        self.broadcast_hash_message(data_block_hash, None)?;
-        self.set_stage(NijikaPBFTStage::WaitReply)?;
+        if !self.get_round().has_role(NijikaNodeRole::PACKER) {
+            return Err(NijikaError::MismatchedRole(self.get_role(), NijikaNodeRole::PACKER));
+        }
+        let mut data_block = self.new_data_block();
+        self.load_data_block(&mut data_block)?;
+        // a round start tried again packs the same block as the try that failed after storing it
+        let data_block_hash = match self.get_data_block(&data_block.hash()?) {
+            Some(_) => data_block.hash()?,
+            None => self.store_data_block(data_block)?
+        };
+        self.broadcast_hash_message(NijikaMessageDataType::DataBlockHash, data_block_hash, None)?;
+        if self.get_round().get_stage() == NijikaPBFTStage::Packing {
+            self.set_stage(NijikaPBFTStage::WaitReply)?;
+        }
         println!("[Complete Pack]");
         Ok(())
     }
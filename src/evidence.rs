@@ -0,0 +1,257 @@
+use std::{collections::{HashMap, VecDeque}, fmt::Debug};
+
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::{hash::{hash, NijikaDigestT}, primitives::{
+    HashValue,
+    NijikaDigest,
+    NijikaControlBlockT,
+    NijikaPBFTMessage,
+    NijikaPBFTMessageType,
+    NijikaResult,
+    NijikaError,
+    NijikaIdT,
+    NijikaEncoder,
+    NijikaDecoder,
+    NijikaEncodingKind
+}};
+
+/// evidence kept at most, the oldest is dropped first
+pub const NIJIKA_MAX_EVIDENCE: usize = 1024;
+/// how many rounds back the first message of every slot is remembered
+pub const NIJIKA_EVIDENCE_ROUNDS: u64 = 64;
+
+/// what a sender may sign only once in a round view
+#[derive(Serialize)]
+enum NijikaSlotKind {
+    Proposal,
+    Prepare,
+    Commit,
+    Reply,
+}
+
+/// the hash of the sender, round, view and kind of a message, None for the kinds a sender may repeat
+pub fn slot_of<CB: NijikaControlBlockT + Serialize + Debug, ID: Clone + Copy + Debug + Serialize + NijikaIdT>(message: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<Option<HashValue>> {
+    let kind = match message.get_type() {
+        NijikaPBFTMessageType::PrePrepare | NijikaPBFTMessageType::NewView => NijikaSlotKind::Proposal,
+        NijikaPBFTMessageType::Prepare => NijikaSlotKind::Prepare,
+        NijikaPBFTMessageType::Commit => NijikaSlotKind::Commit,
+        NijikaPBFTMessageType::Reply => NijikaSlotKind::Reply,
+        NijikaPBFTMessageType::ViewChange => return Ok(None),
+    };
+    let slot = (hash::serialized(&message.get_source())?, message.get_round_num(), message.get_view(), kind);
+    hash::serialized(&slot).map(Some)
+}
+
+/// whether the two messages take the same slot of the same sender for different control blocks,
+/// or vote differently on the same one, e.g. for and against it or against it for two reasons
+pub fn is_conflicting<CB: NijikaControlBlockT + Serialize + Debug, ID: Clone + Copy + Debug + Serialize + NijikaIdT>(a: &NijikaPBFTMessage<CB, ID>, b: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<bool> {
+    let verdict = |message: &NijikaPBFTMessage<CB, ID>| message.get_vote().map(|vote| (vote.get_result(), vote.get_reason()));
+    match (slot_of(a)?, slot_of(b)?) {
+        (Some(x), Some(y)) => Ok(x == y && (a.get_control_block_hash() != b.get_control_block_hash() || verdict(a) != verdict(b))),
+        _ => Ok(false)
+    }
+}
+
+/// two signed messages of one sender that contradict each other.
+/// It carries all that is needed to check it, so that any node knowing the sender's signing key can judge it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NijikaEvidence<CB: NijikaControlBlockT, ID: Clone + Copy + Debug + Serialize + NijikaIdT> {
+    first: NijikaPBFTMessage<CB, ID>,
+    second: NijikaPBFTMessage<CB, ID>,
+}
+
+impl<CB: NijikaControlBlockT + Serialize + Debug, ID: Clone + Copy + Debug + Serialize + NijikaIdT> NijikaEvidence<CB, ID> {
+    /// the messages are ordered by hash, so that the same pair always makes the same evidence
+    pub fn new(a: NijikaPBFTMessage<CB, ID>, b: NijikaPBFTMessage<CB, ID>) -> NijikaResult<Self> {
+        if !is_conflicting(&a, &b)? {
+            return Err(NijikaError::InvalidPBFTMessage(format!("messages of {:?} and {:?} do not conflict", a.get_source(), b.get_source())));
+        }
+        let (first, second) = if a.hash()?.as_bytes() <= b.hash()?.as_bytes() { (a, b) } else { (b, a) };
+        Ok(NijikaEvidence { first, second })
+    }
+    pub fn get_first(&self) -> &NijikaPBFTMessage<CB, ID> {
+        &self.first
+    }
+    pub fn get_second(&self) -> &NijikaPBFTMessage<CB, ID> {
+        &self.second
+    }
+    /// the sender who signed both messages
+    pub fn get_offender(&self) -> ID {
+        self.first.get_source()
+    }
+    pub fn get_round_num(&self) -> u64 {
+        self.first.get_round_num()
+    }
+    pub fn hash(&self) -> NijikaResult<HashValue> {
+        hash::serialized(self)
+    }
+
+    /// the canonical form sent over the network: both messages in their own canonical form
+    pub fn encode(&self) -> NijikaResult<Vec<u8>> {
+        let mut encoder = NijikaEncoder::new(NijikaEncodingKind::Evidence);
+        encoder.put_bytes(&self.first.encode()?)?;
+        encoder.put_bytes(&self.second.encode()?)?;
+        Ok(encoder.finish())
+    }
+}
+
+impl<CB: NijikaControlBlockT + Serialize + DeserializeOwned + Debug, ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned> NijikaEvidence<CB, ID> {
+    /// read what encode wrote; the messages must still conflict
+    pub fn decode(bytes: &[u8]) -> NijikaResult<Self> {
+        let mut decoder = NijikaDecoder::new(bytes, NijikaEncodingKind::Evidence)?;
+        let first = NijikaPBFTMessage::decode(&decoder.get_bytes()?)?;
+        let second = NijikaPBFTMessage::decode(&decoder.get_bytes()?)?;
+        decoder.finish()?;
+        Self::new(first, second)
+    }
+}
+
+/// the first message seen in every slot, and the evidence found against them.
+/// New evidence waits in a queue until the embedder takes it, e.g. to slash or ban the offender
+#[derive(Debug)]
+pub struct NijikaEvidencePool<CB: NijikaControlBlockT, ID: Clone + Copy + Debug + Serialize + NijikaIdT> {
+    /// slot -> (its round, the hash of the first message seen for it)
+    slots: HashMap<HashValue, (u64, NijikaDigest<CB>)>,
+    evidence: HashMap<HashValue, NijikaEvidence<CB, ID>>,
+    order: VecDeque<HashValue>,
+    pending: Vec<HashValue>,
+}
+
+impl<CB: NijikaControlBlockT + Serialize + Debug + Clone, ID: Clone + Copy + Debug + Serialize + NijikaIdT> NijikaEvidencePool<CB, ID> {
+    pub fn new() -> Self {
+        NijikaEvidencePool { slots: HashMap::new(), evidence: HashMap::new(), order: VecDeque::new(), pending: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.evidence.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.evidence.is_empty()
+    }
+
+    pub fn contains(&self, hash: &HashValue) -> bool {
+        self.evidence.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &HashValue) -> Option<&NijikaEvidence<CB, ID>> {
+        self.evidence.get(hash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &NijikaEvidence<CB, ID>> {
+        self.evidence.values()
+    }
+
+    /// remember the first message of its slot, and return the hash of that first message
+    /// when the given one takes the same slot for another control block
+    pub fn observe(&mut self, message_hash: NijikaDigest<CB>, message: &NijikaPBFTMessage<CB, ID>) -> NijikaResult<Option<NijikaDigest<CB>>> {
+        let slot = match slot_of(message)? {
+            Some(slot) => slot,
+            None => return Ok(None)
+        };
+        match self.slots.get(&slot) {
+            Some((_, first)) if *first != message_hash => Ok(Some(*first)),
+            Some(_) => Ok(None),
+            None => {
+                self.slots.insert(slot, (message.get_round_num(), message_hash));
+                Ok(None)
+            }
+        }
+    }
+
+    /// keep the evidence and queue it for the embedder. Returns its hash, or None if it was known
+    pub fn insert(&mut self, evidence: NijikaEvidence<CB, ID>) -> NijikaResult<Option<HashValue>> {
+        let hash = evidence.hash()?;
+        if self.contains(&hash) {
+            return Ok(None);
+        }
+        self.evidence.insert(hash, evidence);
+        self.order.push_back(hash);
+        self.pending.push(hash);
+        while self.order.len() > NIJIKA_MAX_EVIDENCE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.evidence.remove(&oldest);
+                self.pending.retain(|h| *h != oldest);
+            }
+        }
+        Ok(Some(hash))
+    }
+
+    /// the evidence found since the last call
+    pub fn take_pending(&mut self) -> Vec<NijikaEvidence<CB, ID>> {
+        let pending = std::mem::take(&mut self.pending);
+        pending.iter().filter_map(|hash| self.evidence.get(hash).cloned()).collect()
+    }
+
+    /// forget the slots of the rounds before the given one
+    pub fn prune(&mut self, before_round: u64) {
+        self.slots.retain(|_, (round, _)| *round >= before_round);
+    }
+}
+
+impl<CB: NijikaControlBlockT + Serialize + Debug + Clone, ID: Clone + Copy + Debug + Serialize + NijikaIdT> Default for NijikaEvidencePool<CB, ID> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::primitives::{NijikaVote, NijikaVRFCredential, NijikaRejectReason};
+    use crate::testing::TestBlock;
+    use super::*;
+
+    fn prepare(voter: HashValue, view: u64, block: HashValue) -> NijikaPBFTMessage<TestBlock, HashValue> {
+        NijikaPBFTMessage::new_vote_message(voter, 1, view, NijikaPBFTMessageType::Prepare, block, NijikaVote::new_true(voter), NijikaVRFCredential::default())
+    }
+
+    #[test]
+    fn double_vote_makes_evidence() {
+        let mut pool: NijikaEvidencePool<TestBlock, HashValue> = NijikaEvidencePool::new();
+        let (voter, a, b) = (HashValue::random(), HashValue::random(), HashValue::random());
+        let first = prepare(voter, 0, a);
+        assert_eq!(pool.observe(first.hash().unwrap(), &first).unwrap(), None);
+        assert_eq!(pool.observe(first.hash().unwrap(), &first).unwrap(), None);
+        // another view is another slot
+        let next_view = prepare(voter, 1, b);
+        assert_eq!(pool.observe(next_view.hash().unwrap(), &next_view).unwrap(), None);
+        let second = prepare(voter, 0, b);
+        assert_eq!(pool.observe(second.hash().unwrap(), &second).unwrap(), Some(first.hash().unwrap()));
+
+        let evidence = NijikaEvidence::new(second.clone(), first.clone()).unwrap();
+        assert_eq!(evidence.get_offender(), voter);
+        assert_eq!(evidence.hash().unwrap(), NijikaEvidence::new(first.clone(), second).unwrap().hash().unwrap());
+        assert!(pool.insert(evidence.clone()).unwrap().is_some());
+        assert!(pool.insert(evidence).unwrap().is_none());
+        assert_eq!(pool.take_pending().len(), 1);
+        assert!(pool.take_pending().is_empty());
+        assert!(NijikaEvidence::new(first.clone(), first).is_err());
+    }
+
+    #[test]
+    fn votes_for_and_against_one_block_conflict() {
+        let (voter, block) = (HashValue::random(), HashValue::random());
+        let nay = |reason| NijikaPBFTMessage::<TestBlock, HashValue>::new_vote_message(
+            voter, 1, 0, NijikaPBFTMessageType::Prepare, block, NijikaVote::new_false(voter, reason), NijikaVRFCredential::default()
+        );
+        let yea = prepare(voter, 0, block);
+        assert!(is_conflicting(&yea, &nay(NijikaRejectReason::WrongSeed)).unwrap());
+        assert!(is_conflicting(&nay(NijikaRejectReason::WrongRound), &nay(NijikaRejectReason::WrongSeed)).unwrap());
+        assert!(!is_conflicting(&nay(NijikaRejectReason::WrongSeed), &nay(NijikaRejectReason::WrongSeed)).unwrap());
+    }
+
+    #[test]
+    fn evidence_round_trips_through_its_encoding() {
+        let (voter, a, b) = (HashValue::random(), HashValue::random(), HashValue::random());
+        let evidence = NijikaEvidence::new(prepare(voter, 0, a), prepare(voter, 0, b)).unwrap();
+        let decoded: NijikaEvidence<TestBlock, HashValue> = NijikaEvidence::decode(&evidence.encode().unwrap()).unwrap();
+        assert_eq!(decoded.hash().unwrap(), evidence.hash().unwrap());
+        // a pair that does not conflict is no evidence, however it is encoded
+        let mut encoder = NijikaEncoder::new(NijikaEncodingKind::Evidence);
+        encoder.put_bytes(&prepare(voter, 0, a).encode().unwrap()).unwrap();
+        encoder.put_bytes(&prepare(voter, 1, b).encode().unwrap()).unwrap();
+        assert!(NijikaEvidence::<TestBlock, HashValue>::decode(&encoder.finish()).is_err());
+    }
+}
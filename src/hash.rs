@@ -1,16 +1,116 @@
-use openssl::sha::Sha512;
+use std::{fmt::{Debug, Display}, hash::Hash, io::{Write, Result as IoResult}};
+use openssl::sha::{Sha256, Sha512};
+use serde::{Serialize, de::DeserializeOwned};
 
-use crate::primitives::HashValue;
+use crate::primitives::{ByteArray, HashValue, NijikaResult, NijikaError};
 
-pub struct hash {
-    hasher: Sha512
+/// the fixed-width output of a NijikaHasher, e.g. the key of a block in the ledger or of a vote in a round
+pub trait NijikaDigestT: Copy + Debug + Display + Default + Eq + Hash + Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// the width in bytes
+    const LEN: usize;
+    fn as_bytes(&self) -> &[u8];
+    /// exactly LEN bytes
+    fn from_bytes(bytes: &[u8]) -> NijikaResult<Self>;
 }
 
-impl hash {
-    pub fn new(content: &[u8]) -> HashValue {
-        let mut hasher = Sha512::new();
+impl<const L: usize> NijikaDigestT for ByteArray<L> {
+    const LEN: usize = L;
+    fn as_bytes(&self) -> &[u8] {
+        ByteArray::as_bytes(self)
+    }
+    fn from_bytes(bytes: &[u8]) -> NijikaResult<Self> {
+        ByteArray::try_from(bytes)
+    }
+}
+
+/// a streaming hash function. Content is fed in pieces with update, so that a large value
+/// never has to sit in one buffer just to be hashed
+pub trait NijikaHasher: Sized {
+    type Output: NijikaDigestT;
+
+    fn new() -> Self;
+    fn update(&mut self, content: &[u8]);
+    fn finish(self) -> Self::Output;
+
+    /// feed the bincode form of a value, the same bytes bincode::serialize would give
+    fn update_serialized<T: Serialize + ?Sized>(&mut self, value: &T) -> NijikaResult<()> {
+        bincode::serialize_into(NijikaHashWriter(self), value).map_err(|e| NijikaError::ParseError(format!("{}", e)))
+    }
+
+    fn digest(content: &[u8]) -> Self::Output {
+        let mut hasher = Self::new();
         hasher.update(content);
-        HashValue::new(hasher.finish())
+        hasher.finish()
+    }
+
+    fn digest_serialized<T: Serialize + ?Sized>(value: &T) -> NijikaResult<Self::Output> {
+        let mut hasher = Self::new();
+        hasher.update_serialized(value)?;
+        Ok(hasher.finish())
+    }
+}
+
+/// lets bincode write straight into a hasher
+struct NijikaHashWriter<'a, H: NijikaHasher>(&'a mut H);
+
+impl<H: NijikaHasher> Write for NijikaHashWriter<'_, H> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// the hasher behind HashValue and the hash helpers
+pub struct NijikaSha512(Sha512);
+
+impl NijikaHasher for NijikaSha512 {
+    type Output = HashValue;
+    fn new() -> Self {
+        NijikaSha512(Sha512::new())
+    }
+    fn update(&mut self, content: &[u8]) {
+        self.0.update(content)
+    }
+    fn finish(self) -> HashValue {
+        HashValue::new(self.0.finish())
+    }
+}
+
+/// 32 byte digests, e.g. for interop with systems keyed by SHA-256 or BLAKE3 sized hashes
+pub struct NijikaSha256(Sha256);
+
+impl NijikaHasher for NijikaSha256 {
+    type Output = ByteArray<32>;
+    fn new() -> Self {
+        NijikaSha256(Sha256::new())
+    }
+    fn update(&mut self, content: &[u8]) {
+        self.0.update(content)
+    }
+    fn finish(self) -> ByteArray<32> {
+        ByteArray::new(self.0.finish())
+    }
+}
+
+/// SHA-512 shorthands for what is always keyed by a HashValue: node IDs, accounts and network envelopes,
+/// called as hash::new and hash::serialized
+#[allow(clippy::module_inception)]
+pub mod hash {
+    use serde::Serialize;
+
+    use crate::primitives::{HashValue, NijikaResult};
+    use super::{NijikaHasher, NijikaSha512};
+
+    pub fn new(content: &[u8]) -> HashValue {
+        NijikaSha512::digest(content)
+    }
+
+    /// hash the bincode form of a value, e.g. to key a generic node ID
+    pub fn serialized<T: Serialize + ?Sized>(value: &T) -> NijikaResult<HashValue> {
+        NijikaSha512::digest_serialized(value)
     }
 
     /* pub fn has_target_hash(target: &HashValue, pool: &Vec<HashValue>) -> bool {
@@ -22,3 +122,29 @@ impl hash {
         return false;
     } */
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streaming_matches_one_shot() {
+        let value = (7u64, "nijika", vec![HashValue::default(); 3]);
+        let bytes = bincode::serialize(&value).unwrap();
+        assert_eq!(hash::serialized(&value).unwrap(), hash::new(&bytes));
+        let mut hasher = NijikaSha256::new();
+        bytes.chunks(5).for_each(|piece| hasher.update(piece));
+        assert_eq!(hasher.finish(), NijikaSha256::digest(&bytes));
+        assert_eq!(NijikaSha256::digest_serialized(&value).unwrap(), NijikaSha256::digest(&bytes));
+    }
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(
+            NijikaSha256::digest(b"abc").to_string(),
+            "0xba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(hash::new(b"abc").to_string().starts_with("0xddaf35a193617aba"));
+    }
+}
@@ -1,8 +1,17 @@
-mod api;
 mod primitives;
 pub use primitives::*;
 mod vrf;
 pub use crate::vrf::NijikaVRFClientS;
 mod consensus;
-pub use consensus::{NijikaPBFTMessageApi, NijikaPBFTStageApi};
+pub use consensus::{NijikaPBFTMessageApi, NijikaPBFTStageApi, NIJIKA_PENDING_ROUNDS};
 pub mod hash;
+pub mod merkle;
+pub mod mempool;
+pub mod evidence;
+pub mod simulation;
+pub mod network;
+pub mod storage;
+mod runtime;
+#[cfg(test)]
+mod testing;
+pub use runtime::{NijikaRuntime, NijikaEvent, NIJIKA_TICK};
@@ -0,0 +1,298 @@
+use std::{cmp::Reverse, collections::{BTreeMap, HashMap, HashSet, VecDeque}};
+
+use crate::primitives::{HashValue, NijikaTransactionT, NijikaResult, NijikaError};
+
+/// how many ids of committed transactions are remembered to reject replays
+pub const NIJIKA_COMMITTED_CACHE: usize = 100_000;
+
+#[derive(Debug, Clone)]
+pub struct NijikaMempoolConfig {
+    /// transactions kept at most, packed ones included
+    pub max_count: usize,
+    pub max_tx_size: u64,
+    pub min_fee: u64,
+}
+
+impl Default for NijikaMempoolConfig {
+    fn default() -> Self {
+        NijikaMempoolConfig { max_count: 10_000, max_tx_size: 64 * 1024, min_fee: 0 }
+    }
+}
+
+/// higher fee per byte first, then first come first served
+type NijikaPriority = (Reverse<u128>, u64);
+
+fn priority<T: NijikaTransactionT>(tx: &T, seq: u64) -> NijikaPriority {
+    (Reverse(tx.get_fee() as u128 * 1_000_000 / tx.get_size().max(1) as u128), seq)
+}
+
+/// pending transactions of a packer.
+/// Selected transactions are held back once a data block carries them, and selectable again when the round or view ends without that block.
+/// The transactions of every data block referenced by a committed control block are dropped, whoever packed it
+#[derive(Debug)]
+pub struct NijikaMempool<T: NijikaTransactionT> {
+    config: NijikaMempoolConfig,
+    transactions: HashMap<HashValue, (T, u64)>,
+    /// the transactions that can still be selected
+    queue: BTreeMap<NijikaPriority, HashValue>,
+    /// data block hash -> the transactions packed into it
+    packed: HashMap<HashValue, Vec<HashValue>>,
+    committed: HashSet<HashValue>,
+    committed_order: VecDeque<HashValue>,
+    /// committed data blocks whose transactions are not known yet
+    awaited: HashSet<HashValue>,
+    next_seq: u64,
+}
+
+impl<T: NijikaTransactionT> NijikaMempool<T> {
+    pub fn new(config: NijikaMempoolConfig) -> Self {
+        NijikaMempool {
+            config,
+            transactions: HashMap::new(),
+            queue: BTreeMap::new(),
+            packed: HashMap::new(),
+            committed: HashSet::new(),
+            committed_order: VecDeque::new(),
+            awaited: HashSet::new(),
+            next_seq: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn contains(&self, id: &HashValue) -> bool {
+        self.transactions.contains_key(id)
+    }
+
+    pub fn get(&self, id: &HashValue) -> Option<&T> {
+        self.transactions.get(id).map(|(tx, _)| tx)
+    }
+
+    /// check a new transaction and keep it. When the pool is full, the transaction with the lowest priority
+    /// is dropped to make room, unless the new one would be the lowest itself
+    pub fn admit(&mut self, tx: T) -> NijikaResult<()> {
+        let id = tx.get_id();
+        if self.contains(&id) || self.committed.contains(&id) {
+            return Err(NijikaError::InvalidTransaction(format!("duplicated transaction {}", id)));
+        }
+        let size = tx.get_size();
+        if size == 0 || size > self.config.max_tx_size {
+            return Err(NijikaError::InvalidTransaction(format!("transaction {} of {} bytes", id, size)));
+        }
+        if tx.get_fee() < self.config.min_fee {
+            return Err(NijikaError::InvalidTransaction(format!("transaction {} pays less than {}", id, self.config.min_fee)));
+        }
+        let key = priority(&tx, self.next_seq);
+        if self.len() >= self.config.max_count {
+            match self.queue.last_key_value() {
+                Some((lowest, _)) if key < *lowest => {
+                    let (_, lowest_id) = self.queue.pop_last().expect("a lowest transaction");
+                    self.transactions.remove(&lowest_id);
+                },
+                _ => return Err(NijikaError::InvalidTransaction(format!("mempool is full, drop {}", id)))
+            }
+        }
+        self.queue.insert(key, id);
+        self.transactions.insert(id, (tx, self.next_seq));
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// the transactions to pack next, by priority, up to the given total size
+    pub fn select(&self, max_block_size: u64) -> Vec<T> {
+        let mut selected = vec![];
+        let mut size = 0;
+        for id in self.queue.values() {
+            let (tx, _) = &self.transactions[id];
+            if size + tx.get_size() <= max_block_size {
+                size += tx.get_size();
+                selected.push(tx.clone());
+            }
+        }
+        selected
+    }
+
+    /// hold back the transactions carried by the data block, so that they are not selected again
+    pub fn mark_packed(&mut self, data_block: HashValue, ids: Vec<HashValue>) {
+        for id in ids.iter() {
+            if let Some((tx, seq)) = self.transactions.get(id) {
+                self.queue.remove(&priority(tx, *seq));
+            }
+        }
+        self.packed.entry(data_block).or_default().extend(ids);
+    }
+
+    /// make the transactions of a data block that will not be committed selectable again
+    pub fn release(&mut self, data_block: &HashValue) {
+        for id in self.packed.remove(data_block).unwrap_or_default() {
+            if let Some((tx, seq)) = self.transactions.get(&id) {
+                self.queue.insert(priority(tx, *seq), id);
+            }
+        }
+    }
+
+    /// make the transactions of every data block still held back selectable again, e.g. once the round or view it was packed in has ended
+    pub fn release_all(&mut self) {
+        let data_blocks = self.packed.keys().copied().collect::<Vec<HashValue>>();
+        for data_block in data_blocks.iter() {
+            self.release(data_block);
+        }
+    }
+
+    /// drop the given transactions of a committed data block and remember their ids. Returns how many were dropped
+    pub fn evict_committed(&mut self, data_block: &HashValue, ids: &[HashValue]) -> usize {
+        self.packed.remove(data_block);
+        self.awaited.remove(data_block);
+        let mut count = 0;
+        for id in ids {
+            if self.remove(id).is_some() {
+                count += 1;
+            }
+            if self.committed.insert(*id) {
+                self.committed_order.push_back(*id);
+            }
+        }
+        while self.committed_order.len() > NIJIKA_COMMITTED_CACHE {
+            if let Some(id) = self.committed_order.pop_front() {
+                self.committed.remove(&id);
+            }
+        }
+        count
+    }
+
+    /// remember a committed data block that has not arrived yet, its transactions are evicted once it does
+    pub fn await_committed(&mut self, data_block: HashValue) {
+        if self.awaited.len() < NIJIKA_COMMITTED_CACHE {
+            self.awaited.insert(data_block);
+        }
+    }
+
+    /// whether the data block was committed before it arrived
+    pub fn is_awaited(&self, data_block: &HashValue) -> bool {
+        self.awaited.contains(data_block)
+    }
+
+    pub fn remove(&mut self, id: &HashValue) -> Option<T> {
+        let (tx, seq) = self.transactions.remove(id)?;
+        self.queue.remove(&priority(&tx, seq));
+        Some(tx)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::hash;
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct TestTransaction {
+        nonce: u64,
+        size: u64,
+        fee: u64,
+    }
+    impl NijikaTransactionT for TestTransaction {
+        fn get_id(&self) -> HashValue {
+            hash::new(&self.nonce.to_be_bytes())
+        }
+        fn get_size(&self) -> u64 {
+            self.size
+        }
+        fn get_fee(&self) -> u64 {
+            self.fee
+        }
+    }
+
+    fn tx(nonce: u64, size: u64, fee: u64) -> TestTransaction {
+        TestTransaction { nonce, size, fee }
+    }
+
+    #[test]
+    fn admission_rejects_duplicates_and_bad_transactions() {
+        let mut pool = NijikaMempool::new(NijikaMempoolConfig { max_count: 10, max_tx_size: 100, min_fee: 1 });
+        pool.admit(tx(1, 10, 1)).unwrap();
+        assert!(pool.admit(tx(1, 10, 1)).is_err());
+        assert!(pool.admit(tx(2, 101, 1)).is_err());
+        assert!(pool.admit(tx(3, 0, 1)).is_err());
+        assert!(pool.admit(tx(4, 10, 0)).is_err());
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn full_pool_keeps_the_best_paying() {
+        let mut pool = NijikaMempool::new(NijikaMempoolConfig { max_count: 2, ..Default::default() });
+        pool.admit(tx(1, 10, 10)).unwrap();
+        pool.admit(tx(2, 10, 20)).unwrap();
+        assert!(pool.admit(tx(3, 10, 5)).is_err());
+        pool.admit(tx(4, 10, 30)).unwrap();
+        assert!(!pool.contains(&tx(1, 10, 10).get_id()));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn select_by_fee_rate_within_size() {
+        let mut pool = NijikaMempool::new(NijikaMempoolConfig::default());
+        pool.admit(tx(1, 100, 100)).unwrap();
+        pool.admit(tx(2, 10, 50)).unwrap();
+        pool.admit(tx(3, 50, 10)).unwrap();
+        let selected: Vec<u64> = pool.select(70).iter().map(|t| t.nonce).collect();
+        assert_eq!(selected, vec![2, 3]);
+    }
+
+    #[test]
+    fn packed_transactions_are_evicted_on_commit() {
+        let mut pool = NijikaMempool::new(NijikaMempoolConfig::default());
+        pool.admit(tx(1, 10, 1)).unwrap();
+        pool.admit(tx(2, 10, 1)).unwrap();
+        let block_a = HashValue::random();
+        pool.mark_packed(block_a, vec![tx(1, 10, 1).get_id()]);
+        assert_eq!(pool.select(100).len(), 1);
+        assert_eq!(pool.evict_committed(&block_a, &[tx(1, 10, 1).get_id()]), 1);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.admit(tx(1, 10, 1)).is_err());
+
+        let block_b = HashValue::random();
+        pool.mark_packed(block_b, vec![tx(2, 10, 1).get_id()]);
+        assert!(pool.select(100).is_empty());
+        pool.release(&block_b);
+        assert_eq!(pool.select(100).len(), 1);
+    }
+
+    #[test]
+    fn blocks_packed_elsewhere_evict_their_transactions() {
+        let mut pool = NijikaMempool::new(NijikaMempoolConfig::default());
+        pool.admit(tx(1, 10, 1)).unwrap();
+        pool.admit(tx(2, 10, 1)).unwrap();
+        // our own block carries the first one, the committed block of another packer both
+        let (ours, theirs) = (HashValue::random(), HashValue::random());
+        pool.mark_packed(ours, vec![tx(1, 10, 1).get_id()]);
+        assert_eq!(pool.evict_committed(&theirs, &[tx(1, 10, 1).get_id(), tx(2, 10, 1).get_id()]), 2);
+        assert!(pool.is_empty());
+        pool.release(&ours);
+        assert!(pool.select(100).is_empty());
+        assert!(pool.admit(tx(2, 10, 1)).is_err());
+        let late = HashValue::random();
+        pool.await_committed(late);
+        assert!(pool.is_awaited(&late));
+        pool.evict_committed(&late, &[]);
+        assert!(!pool.is_awaited(&late));
+    }
+
+    #[test]
+    fn reservations_of_an_ended_round_are_released() {
+        let mut pool = NijikaMempool::new(NijikaMempoolConfig::default());
+        pool.admit(tx(1, 10, 1)).unwrap();
+        pool.admit(tx(2, 10, 1)).unwrap();
+        pool.mark_packed(HashValue::random(), vec![tx(1, 10, 1).get_id()]);
+        pool.mark_packed(HashValue::random(), vec![tx(2, 10, 1).get_id()]);
+        assert!(pool.select(100).is_empty());
+        pool.release_all();
+        assert_eq!(pool.select(100).len(), 2);
+    }
+}
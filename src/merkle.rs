@@ -0,0 +1,172 @@
+use serde::{Serialize, Deserialize};
+
+use crate::{hash::{NijikaHasher, NijikaSha512}, primitives::{HashValue, Transaction}};
+
+/// prefixes keep a leaf from being passed off as an inner node
+const NIJIKA_MERKLE_LEAF: u8 = 0;
+const NIJIKA_MERKLE_NODE: u8 = 1;
+
+pub fn leaf_hash(content: &[u8]) -> HashValue {
+    let mut hasher = NijikaSha512::new();
+    hasher.update(&[NIJIKA_MERKLE_LEAF]);
+    hasher.update(content);
+    hasher.finish()
+}
+
+pub fn node_hash(left: &HashValue, right: &HashValue) -> HashValue {
+    let mut hasher = NijikaSha512::new();
+    hasher.update(&[NIJIKA_MERKLE_NODE]);
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    hasher.finish()
+}
+
+/// all levels of a Merkle tree, from the leaves up to the root.
+/// A node without a sibling is moved up a level as it is, instead of being paired with itself
+#[derive(Debug, Clone)]
+pub struct NijikaMerkleTree {
+    levels: Vec<Vec<HashValue>>,
+}
+
+impl NijikaMerkleTree {
+    pub fn new(transactions: &[Transaction]) -> Self {
+        Self::from_leaves(transactions.iter().map(|tx| leaf_hash(tx.as_bytes())).collect())
+    }
+
+    /// build the tree over leaves hashed with leaf_hash
+    pub fn from_leaves(leaves: Vec<HashValue>) -> Self {
+        let mut levels = vec![leaves];
+        while levels.last().is_some_and(|level| level.len() > 1) {
+            let level = levels.last().expect("at least one level");
+            let next = level.chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!()
+                })
+                .collect();
+            levels.push(next);
+        }
+        NijikaMerkleTree { levels }
+    }
+
+    /// the root, or the default hash for a tree without leaves
+    pub fn root(&self) -> HashValue {
+        match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => *root,
+            None => HashValue::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels[0].is_empty()
+    }
+
+    /// the inclusion proof of the leaf at the given index
+    pub fn proof(&self, index: usize) -> Option<NijikaMerkleProof> {
+        if index >= self.len() {
+            return None;
+        }
+        let mut siblings = vec![];
+        let mut i = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = i ^ 1;
+            if sibling < level.len() {
+                siblings.push(level[sibling]);
+            }
+            i /= 2;
+        }
+        Some(NijikaMerkleProof { index: index as u64, leaf_count: self.len() as u64, siblings })
+    }
+}
+
+/// the sibling hashes on the path from a leaf to the root. The side of each sibling,
+/// and the levels where the path has none, follow from the leaf index and the leaf count
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct NijikaMerkleProof {
+    pub index: u64,
+    pub leaf_count: u64,
+    pub siblings: Vec<HashValue>,
+}
+
+impl NijikaMerkleProof {
+    /// the root the proof leads to from the given leaf, None if the proof is malformed
+    pub fn compute_root(&self, leaf: HashValue) -> Option<HashValue> {
+        if self.index >= self.leaf_count {
+            return None;
+        }
+        let mut siblings = self.siblings.iter();
+        let (mut current, mut i, mut count) = (leaf, self.index, self.leaf_count);
+        while count > 1 {
+            if i % 2 == 1 {
+                current = node_hash(siblings.next()?, &current);
+            } else if i + 1 < count {
+                current = node_hash(&current, siblings.next()?);
+            }
+            i /= 2;
+            count = count.div_ceil(2);
+        }
+        match siblings.next() {
+            Some(_) => None,
+            None => Some(current)
+        }
+    }
+
+    pub fn verify(&self, root: &HashValue, transaction: &Transaction) -> bool {
+        self.compute_root(leaf_hash(transaction.as_bytes())).as_ref() == Some(root)
+    }
+}
+
+/// the Merkle root over the transactions
+pub fn root(transactions: &[Transaction]) -> HashValue {
+    NijikaMerkleTree::new(transactions).root()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transactions(n: usize) -> Vec<Transaction> {
+        (0..n).map(|_| Transaction::random()).collect()
+    }
+
+    #[test]
+    fn every_leaf_has_a_valid_proof() {
+        for n in 1..10 {
+            let txs = transactions(n);
+            let tree = NijikaMerkleTree::new(&txs);
+            for (i, tx) in txs.iter().enumerate() {
+                let proof = tree.proof(i).unwrap();
+                assert!(proof.verify(&tree.root(), tx), "leaf {} of {}", i, n);
+                assert!(!proof.verify(&tree.root(), &txs[(i + 1) % n]) || n == 1);
+            }
+            assert!(tree.proof(n).is_none());
+        }
+    }
+
+    #[test]
+    fn proof_is_bound_to_its_position() {
+        let txs = transactions(5);
+        let tree = NijikaMerkleTree::new(&txs);
+        let mut proof = tree.proof(2).unwrap();
+        proof.index = 3;
+        assert!(!proof.verify(&tree.root(), &txs[2]));
+        let mut proof = tree.proof(4).unwrap();
+        proof.siblings.push(HashValue::default());
+        assert!(!proof.verify(&tree.root(), &txs[4]));
+    }
+
+    #[test]
+    fn root_of_known_shapes() {
+        assert_eq!(root(&[]), HashValue::default());
+        let txs = transactions(3);
+        let leaves: Vec<HashValue> = txs.iter().map(|tx| leaf_hash(tx.as_bytes())).collect();
+        assert_eq!(root(&txs[..1]), leaves[0]);
+        assert_eq!(root(&txs), node_hash(&node_hash(&leaves[0], &leaves[1]), &leaves[2]));
+    }
+}
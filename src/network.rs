@@ -0,0 +1,8 @@
+mod message;
+pub use message::*;
+mod transport;
+pub use transport::*;
+mod sync;
+pub use sync::*;
+mod gossip;
+pub use gossip::*;
@@ -0,0 +1,213 @@
+use std::{collections::HashMap, fmt::Debug, time::{Duration, Instant}};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    evidence::NijikaEvidence,
+    hash::NijikaDigestT,
+    primitives::{
+        HashValue,
+        NijikaDigest,
+        NijikaResult,
+        NijikaError,
+        NijikaPBFTMessage,
+        NijikaControlBlockT,
+        NijikaDataBlockT,
+        NijikaIdT,
+        NIJIKA_DATA_BLOCK_QUEUE,
+        NIJIKA_PBFT_MSG_QUEUE
+    }
+};
+
+use super::{NijikaMessage, NijikaMessageType, NijikaMessageDataType, NijikaSyncApi, NIJIKA_SYNC_LAG};
+
+/// an unanswered GetData request for a data block is forgotten after this long, later answers to it are refused
+pub const NIJIKA_DATA_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the data blocks asked for with GetData and not received yet, by their digest.
+/// Any peer may answer, as a body whose hash is the digest asked for is the one wanted whoever sends it
+#[derive(Debug, Clone)]
+pub struct NijikaDataRequests<D> {
+    outstanding: HashMap<D, Instant>,
+}
+
+impl<D: NijikaDigestT> NijikaDataRequests<D> {
+    pub fn new() -> Self {
+        NijikaDataRequests { outstanding: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    pub fn insert(&mut self, hash: D) {
+        self.outstanding.insert(hash, Instant::now());
+    }
+
+    /// whether a body of the given hash was asked for, and settle the request if so
+    pub fn accept(&mut self, hash: &D) -> bool {
+        let now = Instant::now();
+        self.outstanding.retain(|_, sent| now.duration_since(*sent) < NIJIKA_DATA_REQUEST_TIMEOUT);
+        self.outstanding.remove(hash).is_some()
+    }
+}
+
+impl<D: NijikaDigestT> Default for NijikaDataRequests<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// read the hash carried by an Invite or GetData message
+pub fn parse_hash<D: NijikaDigestT>(content: &[u8]) -> NijikaResult<D> {
+    D::from_bytes(content)
+}
+
+/// inv/getdata/data gossip of data blocks and pbft messages.
+/// A node announces the hash of what it has (Invite), peers ask for the bodies they miss (GetData),
+/// and the bodies are served from the data block and pbft message pools (Data)
+pub trait NijikaGossipApi<'a,
+    CB: NijikaControlBlockT + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    DB: NijikaDataBlockT<Hasher = CB::Hasher> + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned + 'a
+>: NijikaSyncApi<'a, CB, DB, ID> {
+    /// whether the body behind the hash is already in one of the pools
+    fn is_known(&self, hash: &NijikaDigest<CB>) -> bool {
+        self.get_data_block(hash).is_some() || self.get_pbft_message(hash).is_some()
+    }
+
+    /// announce every hash of the hash queues, e.g. to a newly connected peer
+    fn announce_hash_queue(&self, peer: HashValue) -> NijikaResult<()> {
+        let transport = self.get_transport();
+        for (identifier, data_type) in [
+            (NIJIKA_DATA_BLOCK_QUEUE, NijikaMessageDataType::DataBlockHash),
+            (NIJIKA_PBFT_MSG_QUEUE, NijikaMessageDataType::PBFTMsgHash),
+        ] {
+            for hash in self.get_hash_queue(Some(identifier))? {
+                let message = NijikaMessage::new_invite_message(transport.get_local_id(), data_type, *hash);
+                transport.send(peer, message)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message(&mut self, message: NijikaMessage) -> NijikaResult<()> {
+        if !message.is_intact() {
+            return Err(NijikaError::NetworkFail(format!("a message from {} does not match its hash", message.get_source())));
+        }
+        match message.get_type() {
+            NijikaMessageType::Invite => self.handle_invite(message),
+            NijikaMessageType::GetData => self.handle_get_data(message),
+            NijikaMessageType::Data => self.handle_data(message),
+            NijikaMessageType::GetBlocks => self.handle_get_blocks(message),
+            NijikaMessageType::Blocks => self.handle_blocks(message),
+        }
+    }
+
+    /// ask the announcing peer for the body if we don't have it yet
+    fn handle_invite(&mut self, message: NijikaMessage) -> NijikaResult<()> {
+        let hash: NijikaDigest<CB> = parse_hash(message.get_content())?;
+        if self.is_known(&hash) {
+            return Ok(());
+        }
+        match message.get_data_type() {
+            NijikaMessageDataType::DataBlockHash => self.request_data_block(hash, Some(message.get_source())),
+            NijikaMessageDataType::PBFTMsgHash => {
+                let transport = self.get_transport();
+                let request = NijikaMessage::new_message(transport.get_local_id(), NijikaMessageType::GetData, NijikaMessageDataType::PBFTMsg, hash.as_bytes().to_vec());
+                transport.send(message.get_source(), request)
+            },
+            other => Err(NijikaError::ParseError(format!("cannot invite a {:?}", other)))
+        }
+    }
+
+    /// serve the body from the pools, silently ignoring what we don't have
+    fn handle_get_data(&mut self, message: NijikaMessage) -> NijikaResult<()> {
+        let hash: NijikaDigest<CB> = parse_hash(message.get_content())?;
+        let content = match message.get_data_type() {
+            NijikaMessageDataType::DataBlock => match self.get_data_block(&hash) {
+                Some(block) => bincode::serialize(block).map_err(|e| NijikaError::ParseError(format!("{}", e)))?,
+                None => return Ok(())
+            },
+            NijikaMessageDataType::PBFTMsg => match self.get_pbft_message(&hash) {
+                Some(pbft_msg) => pbft_msg.encode()?,
+                None => return Ok(())
+            },
+            other => return Err(NijikaError::ParseError(format!("cannot serve a {:?}", other)))
+        };
+        let transport = self.get_transport();
+        let reply = NijikaMessage::new_message(transport.get_local_id(), NijikaMessageType::Data, message.get_data_type(), content);
+        transport.send(message.get_source(), reply)
+    }
+
+    /// store a received body and pass it on to every peer but the one it came from.
+    /// A data block is only taken if it was asked for and its transactions match its Merkle root
+    fn handle_data(&mut self, message: NijikaMessage) -> NijikaResult<()> {
+        let source = message.get_source();
+        match message.get_data_type() {
+            NijikaMessageDataType::DataBlock => {
+                let block: DB = bincode::deserialize(message.get_content())
+                    .map_err(|e| NijikaError::ParseError(format!("{}", e)))?;
+                let hash = block.hash()?;
+                if self.is_known(&hash) {
+                    return Ok(());
+                }
+                // the digest asked for is the recomputed hash of the body, or the body is not the one asked for
+                if !self.get_data_requests_mut().accept(&hash) {
+                    return Err(NijikaError::NetworkFail(format!("data block {} from {} answers no request of ours", hash, source)));
+                }
+                if block.compute_merkle_root().is_some_and(|root| root != *block.get_merkle_root()) {
+                    return Err(NijikaError::InvalidDataBlock(format!("transactions of {} from {} do not match its Merkle root", hash, source)));
+                }
+                self.store_data_block(block)?;
+                self.broadcast_hash_message(NijikaMessageDataType::DataBlockHash, hash, Some(source))
+            },
+            NijikaMessageDataType::PBFTMsg => {
+                let pbft_msg: NijikaPBFTMessage<CB, ID> = NijikaPBFTMessage::decode(message.get_content())?;
+                if self.is_known(&pbft_msg.hash()?) {
+                    return Ok(());
+                }
+                let round_num = pbft_msg.get_round_num();
+                // handle_pbft_message stores the message and re-announces it, except to the source
+                self.handle_pbft_message(source, &pbft_msg)?;
+                if round_num > self.get_round_num().saturating_add(NIJIKA_SYNC_LAG) {
+                    self.request_blocks(Some(source))?;
+                }
+                Ok(())
+            },
+            NijikaMessageDataType::Evidence => {
+                let evidence: NijikaEvidence<CB, ID> = NijikaEvidence::decode(message.get_content())?;
+                let is_new = match self.get_evidence_pool() {
+                    Some(pool) => !pool.contains(&evidence.hash()?),
+                    None => return Ok(())
+                };
+                if !is_new {
+                    return Ok(());
+                }
+                self.verify_evidence(&evidence)?;
+                if let Some(pool) = self.get_evidence_pool_mut() {
+                    pool.insert(evidence.clone())?;
+                }
+                self.broadcast_evidence(&evidence, Some(source))
+            },
+            other => Err(NijikaError::ParseError(format!("unexpected data of {:?}", other)))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hash_checks_length() {
+        let hash = HashValue::random();
+        assert_eq!(parse_hash::<HashValue>(hash.as_bytes()).unwrap(), hash);
+        assert!(parse_hash::<HashValue>(&hash.as_bytes()[1..]).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{hash::{hash, NijikaDigestT}, primitives::{HashValue, NijikaResult, NijikaError}};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum NijikaMessageType {
+    Invite,
+    GetData,
+    Data,
+    /// ask a peer for the committed control blocks after our tip
+    GetBlocks,
+    Blocks,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum NijikaMessageDataType {
+    DataBlock,
+    DataBlockHash,
+    PBFTMsg,
+    PBFTMsgHash,
+    NetworkData,
+    NetworkDataHash,
+    ControlBlocks,
+    /// pushed whole to the peers, see NijikaEvidence
+    Evidence,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GetDataContent {
+    hash: HashValue,
+    source_node: HashValue,
+}
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct NijikaMessageHeader {
+    message_type: NijikaMessageType,
+    timestamp: i64,
+    source_node: HashValue,
+    data_type: NijikaMessageDataType,
+    data_hash: HashValue,
+}
+
+impl NijikaMessageHeader {
+    fn new(message_type: NijikaMessageType, data_type: NijikaMessageDataType, source_node: HashValue, data_hash: HashValue) -> Self {
+        let timestamp = chrono::Utc::now().timestamp();
+        NijikaMessageHeader { message_type, timestamp, source_node, data_type, data_hash }
+    }
+}
+
+/// the envelope of everything sent between peers
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NijikaMessage {
+    header: NijikaMessageHeader,
+    content: Vec<u8>,
+}
+
+impl NijikaMessage {
+    pub fn new_message(source_node: HashValue ,message_type: NijikaMessageType, data_type: NijikaMessageDataType, content: Vec<u8>) -> Self {
+        let data_hash = hash::new(&content);
+        let header = NijikaMessageHeader::new(message_type, data_type, source_node, data_hash);
+        NijikaMessage {
+            header,
+            content
+        }
+    }
+    /// announce the hash of a data block or pbft message to a peer
+    pub fn new_invite_message<D: NijikaDigestT>(source_node: HashValue, data_type: NijikaMessageDataType, hash: D) -> Self {
+        Self::new_message(source_node, NijikaMessageType::Invite, data_type, hash.as_bytes().to_vec())
+    }
+    pub fn get_type(&self) -> NijikaMessageType {
+        self.header.message_type
+    }
+    pub fn get_data_type(&self) -> NijikaMessageDataType {
+        self.header.data_type
+    }
+    pub fn get_source(&self) -> HashValue {
+        self.header.source_node
+    }
+    pub fn get_timestamp(&self) -> i64 {
+        self.header.timestamp
+    }
+    pub fn get_content(&self) -> &[u8] {
+        &self.content
+    }
+    /// check the content against the hash in the header
+    pub fn is_intact(&self) -> bool {
+        hash::new(&self.content) == self.header.data_hash
+    }
+
+    pub fn as_bytes(&self) -> NijikaResult<Vec<u8>> {
+        match bincode::serialize(self) {
+            Ok(bytes) => Ok(bytes),
+            Err(e) => Err(NijikaError::ParseError(format!("{}", e)))
+        }
+    }
+    pub fn from_bytes(bytes: &[u8]) -> NijikaResult<Self> {
+        match bincode::deserialize::<NijikaMessage>(bytes) {
+            Ok(message) => Ok(message),
+            Err(e) => Err(NijikaError::ParseError(format!("{}", e)))
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,264 @@
+use std::{collections::HashMap, fmt::Debug, time::{Duration, Instant}};
+
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+use crate::{
+    consensus::NijikaPBFTMessageApi,
+    hash::NijikaDigestT,
+    primitives::{
+        HashValue,
+        NijikaDigest,
+        NijikaResult,
+        NijikaError,
+        NijikaControlBlockT,
+        NijikaDataBlockT,
+        NijikaIdT,
+        NijikaPBFTMessage,
+        NijikaPBFTMessageType,
+        NIJIKA_PBFT_MSG_QUEUE,
+    },
+    storage::NijikaRecordKind,
+};
+
+use super::{NijikaMessage, NijikaMessageType, NijikaMessageDataType};
+
+/// at most this many control blocks are sent in one Blocks message
+pub const NIJIKA_SYNC_BATCH: u64 = 64;
+/// a pbft message this many rounds ahead of ours means we have fallen behind
+pub const NIJIKA_SYNC_LAG: u64 = 1;
+/// an unanswered GetBlocks request is forgotten after this long, later answers to it are refused
+pub const NIJIKA_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// content of a GetBlocks message
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct NijikaGetBlocks<D> {
+    /// the tip of the requester's ledger, blocks are sent from its child on
+    pub tip: D,
+    pub limit: u64,
+}
+
+/// content of a Blocks message
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NijikaBlocks<CB: NijikaControlBlockT, ID: Clone + Copy + Debug + Serialize + NijikaIdT> {
+    /// the tip of the request this answers
+    pub tip: NijikaDigest<CB>,
+    /// consecutive committed control blocks, each one extending the previous
+    pub blocks: Vec<CB>,
+    /// for each block, the signed commit votes of the quorum that committed it
+    pub commits: Vec<Vec<NijikaPBFTMessage<CB, ID>>>,
+    /// the round the responder is in
+    pub round_num: u64,
+}
+
+/// the GetBlocks requests waiting for an answer, by the tip they were sent from.
+/// A request sent to every peer may be answered by any of them until it expires, one sent to a peer only once by that peer
+#[derive(Debug, Clone)]
+pub struct NijikaSyncRequests<D> {
+    outstanding: HashMap<D, (Option<HashValue>, Instant)>,
+}
+
+impl<D: NijikaDigestT> NijikaSyncRequests<D> {
+    pub fn new() -> Self {
+        NijikaSyncRequests { outstanding: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    pub fn insert(&mut self, tip: D, peer: Option<HashValue>) {
+        self.outstanding.insert(tip, (peer, Instant::now()));
+    }
+
+    /// whether an answer from the source to the request sent from the given tip is expected, and settle it if so
+    pub fn accept(&mut self, tip: &D, source: HashValue) -> bool {
+        let now = Instant::now();
+        self.outstanding.retain(|_, (_, sent)| now.duration_since(*sent) < NIJIKA_SYNC_TIMEOUT);
+        match self.outstanding.get(tip) {
+            Some((None, _)) => true,
+            Some((Some(peer), _)) if *peer == source => {
+                self.outstanding.remove(tip);
+                true
+            },
+            _ => false
+        }
+    }
+}
+
+impl<D: NijikaDigestT> Default for NijikaSyncRequests<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// check that the blocks form one chain extending a block of the ledger
+pub fn verify_chain<CB: NijikaControlBlockT>(known: impl Fn(&NijikaDigest<CB>) -> bool, blocks: &[CB]) -> NijikaResult<()> {
+    let mut expected_pre_hash: Option<NijikaDigest<CB>> = None;
+    for block in blocks {
+        let pre_hash = block.get_pre_hash();
+        match expected_pre_hash {
+            Some(hash) if hash != *pre_hash => {
+                return Err(NijikaError::InvalidControlBlock(format!("block of round {} does not extend {}", block.get_round(), hash)));
+            },
+            None if !known(pre_hash) => {
+                return Err(NijikaError::InvalidControlBlock(format!("first block extends an unknown block {}", pre_hash)));
+            },
+            _ => ()
+        }
+        expected_pre_hash = Some(block.header_hash()?);
+    }
+    Ok(())
+}
+
+/// catch-up sync of committed control blocks for nodes that started late or missed rounds.
+/// A lagging node asks a peer for the blocks after its tip (GetBlocks), commits the chain it gets back (Blocks),
+/// fetches the data blocks they reference with GetData and jumps to the peer's round
+pub trait NijikaSyncApi<'a,
+    CB: NijikaControlBlockT + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    DB: NijikaDataBlockT<Hasher = CB::Hasher> + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned + 'a
+>: NijikaPBFTMessageApi<'a, CB, DB, ID> {
+    /// the GetBlocks requests sent and not answered yet
+    fn get_sync_requests_mut(&mut self) -> &mut NijikaSyncRequests<NijikaDigest<CB>>;
+
+    /// ask the given peer, or every peer, for the blocks after our tip
+    fn request_blocks(&mut self, peer: Option<HashValue>) -> NijikaResult<()> {
+        let tip = self.get_ledger().get_tip();
+        self.get_sync_requests_mut().insert(tip, peer);
+        let request = NijikaGetBlocks { tip, limit: NIJIKA_SYNC_BATCH };
+        let content = bincode::serialize(&request).map_err(|e| NijikaError::ParseError(format!("{}", e)))?;
+        let transport = self.get_transport();
+        let message = NijikaMessage::new_message(transport.get_local_id(), NijikaMessageType::GetBlocks, NijikaMessageDataType::ControlBlocks, content);
+        match peer {
+            Some(peer) => transport.send(peer, message),
+            None => transport.broadcast(&message, None)
+        }
+    }
+
+    /// the pooled commit votes of each given block, from the view in which most of them were cast
+    fn collect_commits(&self, blocks: &[CB]) -> NijikaResult<Vec<Vec<NijikaPBFTMessage<CB, ID>>>> {
+        let wanted = blocks.iter().map(|block| block.header_hash()).collect::<NijikaResult<Vec<_>>>()?;
+        // (block, view) -> the commit votes for the block cast in that view
+        let mut by_view = HashMap::new();
+        for message_hash in self.get_hash_queue(Some(NIJIKA_PBFT_MSG_QUEUE))? {
+            let message = match self.get_pbft_message(message_hash) {
+                Some(message) if matches!(message.get_type(), NijikaPBFTMessageType::Commit) => message,
+                _ => continue
+            };
+            if wanted.contains(&message.get_control_block_hash()) {
+                by_view.entry((message.get_control_block_hash(), message.get_view())).or_insert_with(Vec::new).push(message.without_proof());
+            }
+        }
+        Ok(wanted.iter().map(|block_hash| {
+            by_view.iter()
+                .filter(|((hash, _), _)| hash == block_hash)
+                .map(|(_, commits)| commits)
+                .max_by_key(|commits| commits.len())
+                .cloned()
+                .unwrap_or_default()
+        }).collect())
+    }
+
+    /// send the blocks of our main chain after the requester's tip, each with the commit votes that prove it.
+    /// A tip we don't know or that is off our main chain gets the chain from the genesis block on
+    fn handle_get_blocks(&mut self, message: NijikaMessage) -> NijikaResult<()> {
+        let request: NijikaGetBlocks<NijikaDigest<CB>> = bincode::deserialize(message.get_content())
+            .map_err(|e| NijikaError::ParseError(format!("{}", e)))?;
+        let ledger = self.get_ledger();
+        let start = if ledger.is_ancestor(&request.tip, &ledger.get_tip()) {
+            ledger.get_height_of(&request.tip).unwrap_or(0).saturating_add(1)
+        } else {
+            1
+        };
+        let blocks = (start..start.saturating_add(request.limit.min(NIJIKA_SYNC_BATCH)))
+            .map_while(|height| ledger.get_block_at(height).cloned())
+            .collect::<Vec<CB>>();
+        let commits = self.collect_commits(&blocks)?;
+        let response = NijikaBlocks { tip: request.tip, blocks, commits, round_num: self.get_round_num() };
+        let content = bincode::serialize(&response).map_err(|e| NijikaError::ParseError(format!("{}", e)))?;
+        let transport = self.get_transport();
+        let reply = NijikaMessage::new_message(transport.get_local_id(), NijikaMessageType::Blocks, NijikaMessageDataType::ControlBlocks, content);
+        transport.send(message.get_source(), reply)
+    }
+
+    /// commit the received chain once each block is proven by a commit quorum drawn from the seed of its parent,
+    /// ask for the data blocks it references that we miss, then keep asking while the peer sends full batches,
+    /// or catch up with its round. Only answers to our own requests are taken, and the round the peer claims
+    /// is only followed up to the one after our verified tip
+    fn handle_blocks(&mut self, message: NijikaMessage) -> NijikaResult<()> {
+        let source = message.get_source();
+        let response: NijikaBlocks<CB, ID> = bincode::deserialize(message.get_content())
+            .map_err(|e| NijikaError::ParseError(format!("{}", e)))?;
+        if !self.get_sync_requests_mut().accept(&response.tip, source) {
+            return Err(NijikaError::NetworkFail(format!("blocks from {} answer no request of ours", source)));
+        }
+        if response.commits.len() != response.blocks.len() {
+            return Err(NijikaError::InvalidControlBlock(format!("{} blocks from {} with {} commit proofs", response.blocks.len(), source, response.commits.len())));
+        }
+        verify_chain(|hash| self.get_ledger().contains(hash), &response.blocks)?;
+        let mut missing = vec![];
+        for (block, commits) in response.blocks.iter().zip(response.commits.iter()) {
+            let block_hash = block.header_hash()?;
+            if self.get_ledger().contains(&block_hash) {
+                continue;
+            }
+            // verify_chain made sure the parent is known by now
+            let seed = self.get_ledger().get(block.get_pre_hash()).map(|parent| parent.get_seed()).unwrap_or_default();
+            let view = commits.first().map(|commit| commit.get_view()).unwrap_or_default();
+            self.verify_commits(block.get_round(), view, block_hash, seed, commits)?;
+            missing.extend(block.get_data_block_pointers().iter().filter(|hash| self.get_data_block(hash).is_none()).copied());
+            self.persist(NijikaRecordKind::ControlBlock, block)?;
+            self.commit_control_block(block.clone())?;
+        }
+        for hash in missing {
+            self.request_data_block(hash, Some(source))?;
+        }
+        if response.blocks.len() as u64 >= NIJIKA_SYNC_BATCH {
+            return self.request_blocks(Some(source));
+        }
+        // a round ends with the commit of its block, so the peer can only prove the round after our tip by now
+        let round_num = response.round_num.min(self.get_ledger().get_tip_block().get_round().saturating_add(1));
+        if round_num > self.get_round_num() {
+            println!("[Sync] catch up with round {}", round_num);
+            self.fast_forward(round_num)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestBlock;
+    use super::*;
+
+    #[test]
+    fn verify_chain_checks_pre_hash() {
+        let tip = HashValue::random();
+        let b1 = TestBlock::new(1, tip);
+        let b2 = TestBlock::child(&b1, 2, 0);
+        let known = |hash: &HashValue| *hash == tip;
+        assert!(verify_chain(known, std::slice::from_ref(&b2)).is_err());
+        assert!(verify_chain(known, &[b1.clone(), b2]).is_ok());
+        assert!(verify_chain(known, &[b1, TestBlock::new(2, tip)]).is_err());
+    }
+
+    #[test]
+    fn only_answers_to_outstanding_requests_are_taken() {
+        let (tip, peer, other) = (HashValue::random(), HashValue::random(), HashValue::random());
+        let mut requests = NijikaSyncRequests::new();
+        assert!(!requests.accept(&tip, peer));
+        requests.insert(tip, Some(peer));
+        assert!(!requests.accept(&tip, other));
+        assert!(requests.accept(&tip, peer));
+        assert!(!requests.accept(&tip, peer));
+        // a request to every peer stays open for all of them
+        requests.insert(tip, None);
+        assert!(requests.accept(&tip, peer) && requests.accept(&tip, other));
+        assert_eq!(requests.len(), 1);
+    }
+}
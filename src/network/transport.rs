@@ -0,0 +1,236 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    spawn,
+    sync::mpsc::{self, UnboundedSender},
+};
+
+use crate::primitives::{HashValue, NijikaResult, NijikaError};
+
+use super::NijikaMessage;
+
+/// frames larger than this are treated as a broken or hostile peer
+pub const NIJIKA_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// write the bytes as one frame: a 4-byte big-endian length followed by the payload
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8]) -> NijikaResult<()> {
+    if bytes.len() > NIJIKA_MAX_FRAME_SIZE {
+        return Err(NijikaError::NetworkFail(format!("frame of {} bytes is too large", bytes.len())));
+    }
+    let len = (bytes.len() as u32).to_be_bytes();
+    writer.write_all(&len).await.map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+    writer.write_all(bytes).await.map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+    writer.flush().await.map_err(|e| NijikaError::NetworkFail(format!("{}", e)))
+}
+
+/// read one frame written by write_frame
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> NijikaResult<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).await.map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > NIJIKA_MAX_FRAME_SIZE {
+        return Err(NijikaError::NetworkFail(format!("frame of {} bytes is too large", len)));
+    }
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await.map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+    Ok(buf)
+}
+
+/// how a node reaches its peers. Peers are identified by the same HashValue used in message headers
+pub trait NijikaTransportT {
+    /// the id this node puts into the header of the messages it sends
+    fn get_local_id(&self) -> HashValue;
+
+    fn get_peers(&self) -> Vec<HashValue>;
+
+    /// queue the message to be sent to the given peer
+    fn send(&self, peer: HashValue, message: NijikaMessage) -> NijikaResult<()>;
+
+    /// send the message to all peers, except the given one
+    fn broadcast(&self, message: &NijikaMessage, except: Option<HashValue>) -> NijikaResult<()> {
+        for peer in self.get_peers() {
+            if Some(peer) != except {
+                self.send(peer, message.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct NijikaPeer {
+    address: String,
+    /// frames waiting to be written by the connection task, None while disconnected
+    outbox: Option<UnboundedSender<Vec<u8>>>,
+}
+
+/// length-prefixed framed TCP transport.
+/// Every peer gets one outgoing connection, opened on the first send and reopened after it fails
+#[derive(Debug, Clone)]
+pub struct NijikaTcpTransport {
+    local_id: HashValue,
+    peers: Arc<Mutex<HashMap<HashValue, NijikaPeer>>>,
+}
+
+impl NijikaTcpTransport {
+    pub fn new(local_id: HashValue) -> Self {
+        NijikaTcpTransport { local_id, peers: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn add_peer(&self, id: HashValue, address: String) {
+        let mut peers = self.peers.lock().expect("poisoned peer table");
+        peers.insert(id, NijikaPeer { address, outbox: None });
+    }
+
+    pub fn remove_peer(&self, id: &HashValue) {
+        let mut peers = self.peers.lock().expect("poisoned peer table");
+        peers.remove(id);
+    }
+
+    pub fn is_connected(&self, id: &HashValue) -> bool {
+        let peers = self.peers.lock().expect("poisoned peer table");
+        matches!(peers.get(id), Some(NijikaPeer { outbox: Some(outbox), .. }) if !outbox.is_closed())
+    }
+
+    /// bind the address and forward every message received from any peer into the inbox.
+    /// Returns the bound address, so that port 0 can be used
+    pub async fn listen(&self, address: &str, inbox: UnboundedSender<NijikaMessage>) -> NijikaResult<SocketAddr> {
+        let listener = TcpListener::bind(address).await.map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+        let local_address = listener.local_addr().map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+        spawn(async move {
+            loop {
+                let (mut socket, _) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        println!("network error: {}", e);
+                        continue;
+                    }
+                };
+                let inbox = inbox.clone();
+                spawn(async move {
+                    while let Ok(frame) = read_frame(&mut socket).await {
+                        match NijikaMessage::from_bytes(&frame) {
+                            Ok(message) => {
+                                if inbox.send(message).is_err() {
+                                    return;
+                                }
+                            },
+                            Err(e) => {
+                                println!("network error: drop a malformed frame, {:?}", e);
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        Ok(local_address)
+    }
+
+    /// open the connection task of a peer and return its outbox
+    fn connect(&self, id: HashValue, address: String) -> NijikaResult<UnboundedSender<Vec<u8>>> {
+        let handle = Handle::try_current().map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+        let (outbox, mut frames) = mpsc::unbounded_channel::<Vec<u8>>();
+        let peers = self.peers.clone();
+        handle.spawn(async move {
+            let mut stream = match TcpStream::connect(&address).await {
+                Ok(stream) => Some(stream),
+                Err(e) => {
+                    println!("network error: cannot connect to {}, {}", address, e);
+                    None
+                }
+            };
+            if let Some(stream) = stream.as_mut() {
+                while let Some(frame) = frames.recv().await {
+                    if let Err(e) = write_frame(stream, &frame).await {
+                        println!("network error: lost {}, {:?}", address, e);
+                        break;
+                    }
+                }
+            }
+            // let the next send reconnect
+            if let Some(peer) = peers.lock().expect("poisoned peer table").get_mut(&id) {
+                peer.outbox = None;
+            }
+        });
+        Ok(outbox)
+    }
+}
+
+impl NijikaTransportT for NijikaTcpTransport {
+    fn get_local_id(&self) -> HashValue {
+        self.local_id
+    }
+
+    fn get_peers(&self) -> Vec<HashValue> {
+        let peers = self.peers.lock().expect("poisoned peer table");
+        peers.keys().copied().collect()
+    }
+
+    fn send(&self, peer: HashValue, message: NijikaMessage) -> NijikaResult<()> {
+        let frame = message.as_bytes()?;
+        let address = {
+            let peers = self.peers.lock().expect("poisoned peer table");
+            match peers.get(&peer) {
+                Some(NijikaPeer { outbox: Some(outbox), .. }) if !outbox.is_closed() => {
+                    return outbox.send(frame).map_err(|e| NijikaError::NetworkFail(format!("{}", e)));
+                },
+                Some(NijikaPeer { address, .. }) => address.clone(),
+                None => return Err(NijikaError::NetworkFail(format!("unknown peer {}", peer)))
+            }
+        };
+        let outbox = self.connect(peer, address)?;
+        outbox.send(frame).map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+        if let Some(entry) = self.peers.lock().expect("poisoned peer table").get_mut(&peer) {
+            entry.outbox = Some(outbox);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::network::{NijikaMessageType, NijikaMessageDataType};
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_round_trip() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let payload = vec![7u8; 1000];
+        let writer = spawn(async move {
+            write_frame(&mut a, &payload).await.unwrap();
+            write_frame(&mut a, &[]).await.unwrap();
+        });
+        assert_eq!(read_frame(&mut b).await.unwrap(), vec![7u8; 1000]);
+        assert!(read_frame(&mut b).await.unwrap().is_empty());
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_go_over_tcp() {
+        let receiver = NijikaTcpTransport::new(HashValue::random());
+        let (inbox, mut incoming) = mpsc::unbounded_channel();
+        let address = receiver.listen("127.0.0.1:0", inbox).await.unwrap();
+
+        let sender = NijikaTcpTransport::new(HashValue::random());
+        sender.add_peer(receiver.get_local_id(), address.to_string());
+        let hash = HashValue::random();
+        let message = NijikaMessage::new_invite_message(sender.get_local_id(), NijikaMessageDataType::PBFTMsgHash, hash);
+        sender.broadcast(&message, None).unwrap();
+        sender.broadcast(&message, Some(receiver.get_local_id())).unwrap();
+
+        let received = timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(received.get_type(), NijikaMessageType::Invite);
+        assert_eq!(received.get_source(), sender.get_local_id());
+        assert_eq!(received.get_content(), hash.as_bytes());
+        assert!(received.is_intact());
+    }
+}
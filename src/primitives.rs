@@ -7,11 +7,23 @@ pub use node::*;
 mod block;
 pub use block::*;
 
+mod transaction;
+pub use transaction::*;
+
 mod consensus;
 pub use consensus::*;
 
 mod message;
 pub use message::*;
 
+mod ledger;
+pub use ledger::*;
+
+mod stake;
+pub use stake::*;
+
 mod error;
 pub use error::*;
+
+mod encoding;
+pub use encoding::*;
@@ -1,9 +1,10 @@
-use core::fmt::{Debug, Formatter, Result as FmtResult};
+use core::fmt::Debug;
 use serde::{Serialize, Deserialize};
-use erased_serde;
 
 
-use super::{value::HashValue, NijikaResult};
+use crate::hash::NijikaHasher;
+
+use super::{value::HashValue, NijikaResult, NijikaSignedStakeChange, NijikaControlBlockHeader};
 
 #[derive(Debug, Serialize, Clone, Deserialize)]
 pub enum NijikaBlockType {
@@ -11,21 +12,52 @@ pub enum NijikaBlockType {
     DATA,
 }
 
+/// the digest of a block of type B under its own hasher
+pub type NijikaDigest<B> = <<B as NijikaBlockT>::Hasher as NijikaHasher>::Output;
+
 pub trait NijikaBlockT: erased_serde::Serialize {
+    /// the hash function of the block. Its digests key the ledger, the pools and the votes,
+    /// so a control block and the data blocks it references use the same one
+    type Hasher: NijikaHasher;
     fn get_type(&self) -> &NijikaBlockType;
     fn get_round(&self) -> u64;
-    fn hash(&self) -> NijikaResult<HashValue>;
+    fn hash(&self) -> NijikaResult<NijikaDigest<Self>>;
     fn as_bytes(&self) -> NijikaResult<Vec<u8>>;
+    /// the digest of the block's bincode form under any hasher, streamed instead of collected with as_bytes
+    fn hash_with<H: NijikaHasher>(&self) -> NijikaResult<H::Output> where Self: Sized {
+        H::digest_serialized(self as &dyn erased_serde::Serialize)
+    }
 }
-erased_serde::serialize_trait_object!(NijikaBlockT);
 
 pub trait NijikaControlBlockT: NijikaBlockT {
     fn get_seed(&self) -> u64;
-    fn get_pre_hash(&self) -> &HashValue;
-    // fn get_proposer(&self) -> &HashValue;
+    /// the proposer's VRF proof that the seed follows from the seed of the previous block, see vrf::derive_seed
+    fn get_seed_proof(&self) -> &[u8];
+    fn get_pre_hash(&self) -> &NijikaDigest<Self>;
+    /// hashes of the data blocks referenced by this block
+    fn get_data_block_pointers(&self) -> &[NijikaDigest<Self>];
+    /// the stake changes applied once the block is committed, each signed by its owner for the block's round. None by default
+    fn get_stake_changes(&self) -> &[NijikaSignedStakeChange] {
+        &[]
+    }
+    /// when the block was proposed, in the embedder's unit. Part of the header, 0 by default
+    fn get_timestamp(&self) -> u64 {
+        0
+    }
+    /// the canonical header encoding, what the proposer signs
+    fn header_bytes(&self) -> NijikaResult<Vec<u8>> where Self: Sized {
+        NijikaControlBlockHeader::of(self).encode()
+    }
+    /// the digest of the header encoding, which identifies the block in votes, the pools and the ledger.
+    /// Fields of the embedder's block outside the header are not covered by it
+    fn header_hash(&self) -> NijikaResult<NijikaDigest<Self>> where Self: Sized {
+        NijikaControlBlockHeader::of(self).hash_with::<Self::Hasher>()
+    }
+    /// the node that proposed the block, whose signature verify_control_block_signature checks.
+    /// Part of the header, so that a signed block cannot be passed off as another proposer's
+    fn get_proposer(&self) -> &HashValue;
     // fn get_weights_sum(&self) -> u64;
 }
-erased_serde::serialize_trait_object!(NijikaControlBlockT);
 
 /* impl Debug for dyn NijikaControlBlockT {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
@@ -35,5 +67,12 @@ erased_serde::serialize_trait_object!(NijikaControlBlockT);
 
 pub trait NijikaDataBlockT: NijikaBlockT {
     // fn get_packer(&self) -> &HashValue;
-}
-erased_serde::serialize_trait_object!(NijikaDataBlockT);
\ No newline at end of file
+    /// the Merkle root over the block's transactions, see merkle::root.
+    /// It is part of the block's hash, so a control block pointing to the block commits to it
+    fn get_merkle_root(&self) -> &HashValue;
+    /// the Merkle root recomputed from the block's transactions, which a received block must match.
+    /// None by default, for blocks whose hash covers the transactions themselves
+    fn compute_merkle_root(&self) -> Option<HashValue> {
+        None
+    }
+}
\ No newline at end of file
@@ -1,6 +1,11 @@
-use super::{NijikaNodeRole, NijikaControlBlockT, NijikaResult, NijikaError};
+use std::{collections::{HashMap, HashSet, hash_map::Entry}, time::{Duration, Instant}};
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+use super::{NijikaNodeRole, NijikaControlBlockT, NijikaDigest, NijikaResult, NijikaError, HashValue, NijikaVRFCredential, NijikaRejectReason, NijikaSortition};
+
+/// default quorum: a stage completes once more than 2/3 of the expected committee weight has voted
+pub const NIJIKA_DEFAULT_QUORUM: u64 = 67;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum NijikaPBFTStage {
     PrePrepare,
     Prepare,
@@ -10,18 +15,70 @@ pub enum NijikaPBFTStage {
     Packing,
     WaitReply
 }
+
+impl NijikaPBFTStage {
+    /// how long a node stays in the stage of view 0 before it asks for a view change
+    pub fn timeout(&self) -> Duration {
+        match self {
+            NijikaPBFTStage::PrePrepare | NijikaPBFTStage::Packing => Duration::from_secs(5),
+            NijikaPBFTStage::WaitPrePrepare => Duration::from_secs(10),
+            NijikaPBFTStage::Prepare | NijikaPBFTStage::Commit => Duration::from_secs(5),
+            NijikaPBFTStage::Reply | NijikaPBFTStage::WaitReply => Duration::from_secs(20),
+        }
+    }
+}
+/// a block that a quorum prepared in some view of the round, and the pooled messages proving it:
+/// the proposal carrying the block and the prepare votes for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NijikaPrepared<D> {
+    pub view: u64,
+    pub block_hash: D,
+    /// the VRF public key of the block's proposer, to verify its seed when the block is proposed again
+    pub proposer_key: Vec<u8>,
+    pub messages: Vec<D>,
+}
+
 #[derive(Debug)]
 pub struct NijikaRound<CB: NijikaControlBlockT> {
+    /// percentage of the expected committee weight that a stage needs to reach quorum
     thresh: u64,
     expected: u64,
     round_num: u64,
+    /// bumped on every view change, each view elects its own proposer and committee
+    view: u64,
     role: NijikaNodeRole,
     stage: NijikaPBFTStage,
-    prepare_vote: u64,
-    commit_vote: u64,
-    reply_vote: u64,
+    deadline: Instant,
+    /// voter -> (the control block hash it voted for, its sortition weight), one entry per voter
+    prepare_votes: HashMap<HashValue, (NijikaDigest<CB>, u64)>,
+    commit_votes: HashMap<HashValue, (NijikaDigest<CB>, u64)>,
+    reply_votes: HashMap<HashValue, (NijikaDigest<CB>, u64)>,
+    /// voter -> (the control block hash it voted against, its sortition weight)
+    prepare_nays: HashMap<HashValue, (NijikaDigest<CB>, u64)>,
+    commit_nays: HashMap<HashValue, (NijikaDigest<CB>, u64)>,
+    /// view to leave -> voter -> (its sortition weight, the hash of its ViewChange message)
+    view_change_votes: HashMap<u64, HashMap<HashValue, (u64, NijikaDigest<CB>)>>,
+    /// the highest prepared block known in the round, carried from view to view
+    prepared: Option<NijikaPrepared<NijikaDigest<CB>>>,
     end: bool,
-    control_block: Option<CB>
+    control_block: Option<CB>,
+    /// set once this node has voted against the control block of the current view
+    rejection: Option<NijikaRejectReason>,
+    aborted: bool,
+    /// data blocks the control block points to that are still being fetched, the vote on it waits for them
+    awaited: HashSet<NijikaDigest<CB>>,
+    /// VRF public key of the proposer of the current view, to verify the seed of its block
+    proposer_key: Vec<u8>,
+    /// the credential of the role driving the stages, sent along with this node's messages
+    credential: NijikaVRFCredential,
+    sortition: NijikaSortition,
+}
+
+impl<CB: NijikaControlBlockT> Default for NijikaRound<CB> {
+    /// an idle round 0 with no quorum set, before the first round starts
+    fn default() -> Self {
+        Self::new(0, 0, 0, NijikaNodeRole::NORMAL, NijikaPBFTStage::WaitPrePrepare)
+    }
 }
 
 impl<CB: NijikaControlBlockT> NijikaRound<CB> {
@@ -30,32 +87,43 @@ impl<CB: NijikaControlBlockT> NijikaRound<CB> {
             thresh,
             expected,
             round_num,
+            view: 0,
             role,
             stage,
-            prepare_vote: 0,
-            commit_vote: 0,
-            reply_vote: 0,
+            deadline: Instant::now() + stage.timeout(),
+            prepare_votes: HashMap::new(),
+            commit_votes: HashMap::new(),
+            reply_votes: HashMap::new(),
+            prepare_nays: HashMap::new(),
+            commit_nays: HashMap::new(),
+            view_change_votes: HashMap::new(),
+            prepared: None,
             end: false,
-            control_block: None
+            control_block: None,
+            rejection: None,
+            aborted: false,
+            awaited: HashSet::new(),
+            proposer_key: vec![],
+            credential: NijikaVRFCredential::default(),
+            sortition: NijikaSortition::new(),
         }
     }
-    pub fn default() -> Self {
-        Self {
-            thresh: 0,
-            expected: 0,
-            round_num: 0,
-            role: NijikaNodeRole::NORMAL,
-            stage: NijikaPBFTStage::WaitPrePrepare,
-            prepare_vote: 0,
-            commit_vote: 0,
-            reply_vote: 0,
-            end: false,
-            control_block: None
-        }
+    /// the same round in the given view, with the role won in that view and none of the old stage votes.
+    /// The view change votes and the prepared block are kept, the new view is built on them
+    pub fn new_view(&self, view: u64, role: NijikaNodeRole, stage: NijikaPBFTStage) -> Self {
+        let mut round = Self::new(self.thresh, self.expected, self.round_num, role, stage);
+        round.view = view;
+        round.view_change_votes = self.view_change_votes.clone();
+        round.prepared = self.prepared.clone();
+        round.deadline = Instant::now() + round.stage_timeout();
+        round
     }
     pub fn get_round_num(&self) -> u64 {
         self.round_num
     }
+    pub fn get_view(&self) -> u64 {
+        self.view
+    }
     pub fn get_role(&self) -> NijikaNodeRole {
         self.role
     }
@@ -64,11 +132,22 @@ impl<CB: NijikaControlBlockT> NijikaRound<CB> {
     }
     pub fn set_stage(&mut self, next: NijikaPBFTStage) {
         self.stage = next;
+        self.deadline = Instant::now() + self.stage_timeout();
+    }
+    /// the stage timeout, doubled on every view change so that a slow network can still make progress
+    pub fn stage_timeout(&self) -> Duration {
+        self.stage.timeout() * 2u32.saturating_pow(self.view.min(16) as u32)
+    }
+    pub fn get_deadline(&self) -> Instant {
+        self.deadline
+    }
+    pub fn is_timeout(&self, now: Instant) -> bool {
+        !self.end && now >= self.deadline
     }
     pub fn try_set_stage(&mut self, next:NijikaPBFTStage) -> NijikaResult<NijikaPBFTStage> {
         match next {
             NijikaPBFTStage::Commit => {
-                if self.prepare_vote >= self.thresh - 1 {
+                if self.has_quorum(self.get_round_vote_weight(NijikaPBFTStage::Prepare)?) {
                     if self.stage == NijikaPBFTStage::Prepare {
                         self.set_stage(NijikaPBFTStage::Commit);
                         Ok(NijikaPBFTStage::Commit)
@@ -80,7 +159,7 @@ impl<CB: NijikaControlBlockT> NijikaRound<CB> {
                 }
             }
             NijikaPBFTStage::Reply => {
-                if self.prepare_vote >= self.thresh {
+                if self.has_quorum(self.get_round_vote_weight(NijikaPBFTStage::Commit)?) {
                     if self.stage == NijikaPBFTStage::Commit {
                         self.set_stage(NijikaPBFTStage::Reply);
                         Ok(NijikaPBFTStage::Reply)
@@ -97,6 +176,32 @@ impl<CB: NijikaControlBlockT> NijikaRound<CB> {
     pub fn get_expected(&self) -> u64 {
         self.expected
     }
+    pub fn get_thresh(&self) -> u64 {
+        self.thresh
+    }
+    /// whether the given voting weight crosses the quorum fraction of the expected committee size
+    pub fn has_quorum(&self, weight: u64) -> bool {
+        weight > 0 && weight * 100 > self.expected * self.thresh
+    }
+    pub fn get_credential(&self) -> &NijikaVRFCredential {
+        &self.credential
+    }
+    pub fn get_sortition(&self) -> &NijikaSortition {
+        &self.sortition
+    }
+    /// keep every role won in the view; the credential sent along is the one of the round's role
+    pub fn set_sortition(&mut self, sortition: NijikaSortition) {
+        self.credential = sortition.get(self.role).cloned().unwrap_or_default();
+        self.sortition = sortition;
+    }
+    /// the credential to vote with: only validator sub-users are counted against the expected committee,
+    /// a proposer that won no validator seat has no vote
+    pub fn get_vote_credential(&self) -> Option<&NijikaVRFCredential> {
+        self.sortition.get(NijikaNodeRole::VALIDATOR)
+    }
+    pub fn has_role(&self, role: NijikaNodeRole) -> bool {
+        self.sortition.has(role)
+    }
     pub fn set_expected(&mut self, value: u64) -> NijikaResult<()> {
         self.expected = value;
         Ok(())
@@ -107,30 +212,347 @@ impl<CB: NijikaControlBlockT> NijikaRound<CB> {
             None => None
         }
     }
-    pub fn set_control_block(&mut self, block: CB) -> () {
+    pub fn set_control_block(&mut self, block: CB) {
         self.control_block = Some(block);
     }
+    pub fn set_proposer_key(&mut self, key: Vec<u8>) {
+        self.proposer_key = key;
+    }
+    pub fn get_proposer_key(&self) -> &[u8] {
+        &self.proposer_key
+    }
+    pub fn reject(&mut self, reason: NijikaRejectReason) {
+        self.rejection = Some(reason);
+    }
+    pub fn get_rejection(&self) -> Option<NijikaRejectReason> {
+        self.rejection
+    }
+    pub fn await_data_blocks(&mut self, hashes: Vec<NijikaDigest<CB>>) {
+        self.awaited.extend(hashes);
+    }
+    pub fn is_awaiting_data(&self) -> bool {
+        !self.awaited.is_empty()
+    }
+    /// whether the given data block was the last one the vote waited for
+    pub fn data_block_arrived(&mut self, hash: &NijikaDigest<CB>) -> bool {
+        self.awaited.remove(hash) && self.awaited.is_empty()
+    }
     pub fn end(&mut self) -> bool {
         self.end = true;
         true
     }
-    pub fn vote_inc(&mut self, stage: NijikaPBFTStage) -> NijikaResult<()> {
+    pub fn is_end(&self) -> bool {
+        self.end
+    }
+    fn votes_of(&self, stage: NijikaPBFTStage) -> NijikaResult<&HashMap<HashValue, (NijikaDigest<CB>, u64)>> {
         match stage {
-            NijikaPBFTStage::Prepare => {
-                self.prepare_vote += 1;
-                Ok(())
-            },
-            NijikaPBFTStage::Commit => {
-                self.commit_vote += 1;
-                Ok(())
-            },
-            NijikaPBFTStage::Reply => {
-                self.reply_vote += 1;
-                Ok(())
-            },
-            _ => {
-                Err(NijikaError::IncorrectStage(stage))
+            NijikaPBFTStage::Prepare => Ok(&self.prepare_votes),
+            NijikaPBFTStage::Commit => Ok(&self.commit_votes),
+            NijikaPBFTStage::Reply => Ok(&self.reply_votes),
+            _ => Err(NijikaError::IncorrectStage(stage)),
+        }
+    }
+    fn votes_of_mut(&mut self, stage: NijikaPBFTStage) -> NijikaResult<&mut HashMap<HashValue, (NijikaDigest<CB>, u64)>> {
+        match stage {
+            NijikaPBFTStage::Prepare => Ok(&mut self.prepare_votes),
+            NijikaPBFTStage::Commit => Ok(&mut self.commit_votes),
+            NijikaPBFTStage::Reply => Ok(&mut self.reply_votes),
+            _ => Err(NijikaError::IncorrectStage(stage)),
+        }
+    }
+    fn nays_of(&self, stage: NijikaPBFTStage) -> NijikaResult<&HashMap<HashValue, (NijikaDigest<CB>, u64)>> {
+        match stage {
+            NijikaPBFTStage::Prepare => Ok(&self.prepare_nays),
+            NijikaPBFTStage::Commit => Ok(&self.commit_nays),
+            _ => Err(NijikaError::IncorrectStage(stage)),
+        }
+    }
+    fn nays_of_mut(&mut self, stage: NijikaPBFTStage) -> NijikaResult<&mut HashMap<HashValue, (NijikaDigest<CB>, u64)>> {
+        match stage {
+            NijikaPBFTStage::Prepare => Ok(&mut self.prepare_nays),
+            NijikaPBFTStage::Commit => Ok(&mut self.commit_nays),
+            _ => Err(NijikaError::IncorrectStage(stage)),
+        }
+    }
+    /// record the vote of the given voter, weighted by its sortition sub-user count.
+    /// Returns false if the voter has already voted for the same block with at least this weight,
+    /// and an Equivocation error if it has voted for a different one, or against a block in the same stage.
+    /// A heavier vote for the same block replaces the lighter one, e.g. the prepare of a proposer that also won a validator seat
+    pub fn add_vote(&mut self, stage: NijikaPBFTStage, voter: HashValue, control_block_hash: NijikaDigest<CB>, weight: u64) -> NijikaResult<bool> {
+        if let Some((rejected, _)) = self.nays_of(stage).ok().and_then(|nays| nays.get(&voter)) {
+            return Err(NijikaError::Equivocation(format!(
+                "voter {} voted both against {} and for {} in stage {:?}", voter, rejected, control_block_hash, stage
+            )));
+        }
+        let votes = self.votes_of_mut(stage)?;
+        match votes.get(&voter) {
+            Some((voted, counted)) if *voted == control_block_hash => {
+                if weight <= *counted {
+                    return Ok(false);
+                }
+                votes.insert(voter, (control_block_hash, weight));
+                Ok(true)
             },
+            Some((voted, _)) => Err(NijikaError::Equivocation(format!(
+                "voter {} voted for both {} and {} in stage {:?}", voter, voted, control_block_hash, stage
+            ))),
+            None => {
+                votes.insert(voter, (control_block_hash, weight));
+                Ok(true)
+            }
+        }
+    }
+    /// record a vote against the given control block, the counterpart of add_vote
+    pub fn add_nay_vote(&mut self, stage: NijikaPBFTStage, voter: HashValue, control_block_hash: NijikaDigest<CB>, weight: u64) -> NijikaResult<bool> {
+        if let Some((voted, _)) = self.votes_of(stage)?.get(&voter) {
+            return Err(NijikaError::Equivocation(format!(
+                "voter {} voted both for {} and against {} in stage {:?}", voter, voted, control_block_hash, stage
+            )));
+        }
+        let nays = self.nays_of_mut(stage)?;
+        match nays.get(&voter) {
+            Some((rejected, _)) if *rejected == control_block_hash => Ok(false),
+            Some((rejected, _)) => Err(NijikaError::Equivocation(format!(
+                "voter {} voted against both {} and {} in stage {:?}", voter, rejected, control_block_hash, stage
+            ))),
+            None => {
+                nays.insert(voter, (control_block_hash, weight));
+                Ok(true)
+            }
+        }
+    }
+    /// sum the weight of the votes against the round's own control block in a stage
+    pub fn get_round_nay_weight(&self, stage: NijikaPBFTStage) -> NijikaResult<u64> {
+        let block_hash = match &self.control_block {
+            Some(block) => block.header_hash()?,
+            None => return Ok(0)
+        };
+        let nays = self.nays_of(stage)?;
+        Ok(nays.values().filter(|(rejected, _)| *rejected == block_hash).map(|(_, weight)| weight).sum())
+    }
+    /// whether the round's control block is rejected in a stage: it has no quorum, while a quorum's weight voted on it either way
+    /// and the votes against it are more than that share of the observed weight may hold back.
+    /// The weight that turned up is what counts, the committee drawn may be larger than expected
+    pub fn is_rejected(&self, stage: NijikaPBFTStage) -> NijikaResult<bool> {
+        let nays = self.get_round_nay_weight(stage)?;
+        let yeas = self.get_round_vote_weight(stage)?;
+        let observed = nays + yeas;
+        Ok(nays > 0 && !self.has_quorum(yeas) && self.has_quorum(observed) && nays * 100 >= observed * (100 - self.thresh.min(100)))
+    }
+    /// set once the round's control block is found rejected, so that the view is left only once
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+    /// record the wish of the given voter to leave a view, with the message it asked in. Returns false for a duplicated vote
+    pub fn add_view_change_vote(&mut self, view: u64, voter: HashValue, weight: u64, message_hash: NijikaDigest<CB>) -> bool {
+        let votes = self.view_change_votes.entry(view).or_default();
+        match votes.entry(voter) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert((weight, message_hash));
+                true
+            }
+        }
+    }
+    /// sum the weight of the votes to leave the given view
+    pub fn get_view_change_weight(&self, view: u64) -> u64 {
+        match self.view_change_votes.get(&view) {
+            Some(votes) => votes.values().map(|(weight, _)| weight).sum(),
+            None => 0
+        }
+    }
+    /// the hashes of the ViewChange messages asking to leave the given view
+    pub fn get_view_change_messages(&self, view: u64) -> Vec<NijikaDigest<CB>> {
+        match self.view_change_votes.get(&view) {
+            Some(votes) => votes.values().map(|(_, message_hash)| *message_hash).collect(),
+            None => vec![]
         }
     }
-}
\ No newline at end of file
+    pub fn get_prepared(&self) -> Option<&NijikaPrepared<NijikaDigest<CB>>> {
+        self.prepared.as_ref()
+    }
+    /// keep the prepared block if it is from a later view than the one known. Returns whether it was kept
+    pub fn set_prepared(&mut self, prepared: NijikaPrepared<NijikaDigest<CB>>) -> bool {
+        if self.prepared.as_ref().is_some_and(|known| known.view >= prepared.view) {
+            return false;
+        }
+        self.prepared = Some(prepared);
+        true
+    }
+    /// the block and weight the voter is counted with in a stage
+    pub fn get_vote(&self, stage: NijikaPBFTStage, voter: &HashValue) -> Option<&(NijikaDigest<CB>, u64)> {
+        self.votes_of(stage).ok().and_then(|votes| votes.get(voter))
+    }
+    /// sum the weight of the votes for the given control block in a stage
+    pub fn get_vote_weight(&self, stage: NijikaPBFTStage, control_block_hash: &NijikaDigest<CB>) -> NijikaResult<u64> {
+        let votes = self.votes_of(stage)?;
+        Ok(votes.values().filter(|(voted, _)| voted == control_block_hash).map(|(_, weight)| weight).sum())
+    }
+    /// sum the weight of the votes for the round's own control block in a stage
+    pub fn get_round_vote_weight(&self, stage: NijikaPBFTStage) -> NijikaResult<u64> {
+        match &self.control_block {
+            Some(block) => self.get_vote_weight(stage, &block.header_hash()?),
+            None => Ok(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::NijikaSha256;
+    use crate::primitives::{ByteArray, NijikaPBFTMessage, NijikaPBFTMessageType, NijikaVote};
+    use crate::testing::{TestBlock, TestBlockOf};
+    use super::*;
+
+    fn test_block() -> TestBlock {
+        TestBlock::new(1, HashValue::default())
+    }
+
+    #[test]
+    fn replayed_vote_is_counted_once() {
+        let mut round = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 3, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Prepare);
+        let block = test_block();
+        let block_hash = block.header_hash().unwrap();
+        round.set_control_block(block);
+        let voter = HashValue::random();
+        assert!(round.add_vote(NijikaPBFTStage::Prepare, voter, block_hash, 1).unwrap());
+        assert!(!round.add_vote(NijikaPBFTStage::Prepare, voter, block_hash, 1).unwrap());
+        assert_eq!(round.get_round_vote_weight(NijikaPBFTStage::Prepare).unwrap(), 1);
+        assert!(round.try_set_stage(NijikaPBFTStage::Commit).is_err());
+    }
+
+    #[test]
+    fn heavier_vote_replaces_the_lighter_one() {
+        let mut round = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 3, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Prepare);
+        let block = test_block();
+        let block_hash = block.header_hash().unwrap();
+        round.set_control_block(block);
+        let voter = HashValue::random();
+        assert!(round.add_vote(NijikaPBFTStage::Prepare, voter, block_hash, 1).unwrap());
+        assert!(round.add_vote(NijikaPBFTStage::Prepare, voter, block_hash, 3).unwrap());
+        assert!(!round.add_vote(NijikaPBFTStage::Prepare, voter, block_hash, 2).unwrap());
+        assert_eq!(round.get_round_vote_weight(NijikaPBFTStage::Prepare).unwrap(), 3);
+        assert!(round.try_set_stage(NijikaPBFTStage::Commit).is_ok());
+    }
+
+    #[test]
+    fn conflicting_vote_is_equivocation() {
+        let mut round: NijikaRound<TestBlock> = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 3, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Prepare);
+        let voter = HashValue::random();
+        round.add_vote(NijikaPBFTStage::Commit, voter, HashValue::random(), 1).unwrap();
+        match round.add_vote(NijikaPBFTStage::Commit, voter, HashValue::random(), 1) {
+            Err(NijikaError::Equivocation(_)) => (),
+            other => panic!("expected equivocation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn quorum_is_reached_by_weight() {
+        let mut round = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 10, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Prepare);
+        let block = test_block();
+        let block_hash = block.header_hash().unwrap();
+        round.set_control_block(block);
+        round.add_vote(NijikaPBFTStage::Prepare, HashValue::random(), block_hash, 4).unwrap();
+        round.add_vote(NijikaPBFTStage::Prepare, HashValue::random(), HashValue::random(), 5).unwrap();
+        assert!(round.try_set_stage(NijikaPBFTStage::Commit).is_err());
+        round.add_vote(NijikaPBFTStage::Prepare, HashValue::random(), block_hash, 3).unwrap();
+        assert_eq!(round.try_set_stage(NijikaPBFTStage::Commit).unwrap(), NijikaPBFTStage::Commit);
+    }
+
+    #[test]
+    fn votes_can_be_keyed_by_sha256() {
+        let mut round = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 3, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Prepare);
+        let block = TestBlockOf::<NijikaSha256>::new(1, ByteArray::default());
+        let message: NijikaPBFTMessage<TestBlockOf<NijikaSha256>, u64> = NijikaPBFTMessage::new_vote_message(7u64, 1, 0, NijikaPBFTMessageType::Prepare, block.header_hash().unwrap(), NijikaVote::new_true(7u64), NijikaVRFCredential::default());
+        assert_eq!(message.hash().unwrap().as_bytes().len(), 32);
+        round.set_control_block(block);
+        round.add_vote(NijikaPBFTStage::Prepare, HashValue::random(), message.get_control_block_hash(), 3).unwrap();
+        assert_eq!(round.try_set_stage(NijikaPBFTStage::Commit).unwrap(), NijikaPBFTStage::Commit);
+    }
+
+    #[test]
+    fn nays_block_the_quorum() {
+        let mut round = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 3, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Prepare);
+        let block = test_block();
+        let block_hash = block.header_hash().unwrap();
+        round.set_control_block(block);
+        let voter = HashValue::random();
+        assert!(round.add_nay_vote(NijikaPBFTStage::Prepare, voter, block_hash, 0).unwrap());
+        assert!(!round.add_nay_vote(NijikaPBFTStage::Prepare, voter, block_hash, 0).unwrap());
+        assert!(!round.is_rejected(NijikaPBFTStage::Prepare).unwrap());
+        assert!(round.add_vote(NijikaPBFTStage::Prepare, voter, block_hash, 1).is_err());
+        // a single nay is no rejection until a quorum's weight has voted
+        round.add_nay_vote(NijikaPBFTStage::Prepare, HashValue::random(), block_hash, 1).unwrap();
+        assert_eq!(round.get_round_nay_weight(NijikaPBFTStage::Prepare).unwrap(), 1);
+        assert!(!round.is_rejected(NijikaPBFTStage::Prepare).unwrap());
+        // a third of the 3 sub-users that voted cannot leave a 67% quorum
+        round.add_vote(NijikaPBFTStage::Prepare, HashValue::random(), block_hash, 2).unwrap();
+        assert!(round.is_rejected(NijikaPBFTStage::Prepare).unwrap());
+        assert!(!round.is_rejected(NijikaPBFTStage::Commit).unwrap());
+    }
+
+    #[test]
+    fn nays_are_weighed_against_the_observed_weight() {
+        let mut round = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 3, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Prepare);
+        let block = test_block();
+        let block_hash = block.header_hash().unwrap();
+        round.set_control_block(block);
+        // a third of the expected committee, but the committee drawn is larger than expected
+        round.add_nay_vote(NijikaPBFTStage::Prepare, HashValue::random(), block_hash, 1).unwrap();
+        round.add_vote(NijikaPBFTStage::Prepare, HashValue::random(), block_hash, 1).unwrap();
+        round.add_vote(NijikaPBFTStage::Prepare, HashValue::random(), block_hash, 1).unwrap();
+        round.add_vote(NijikaPBFTStage::Prepare, HashValue::random(), block_hash, 1).unwrap();
+        assert!(!round.is_rejected(NijikaPBFTStage::Prepare).unwrap());
+        assert_eq!(round.try_set_stage(NijikaPBFTStage::Commit).unwrap(), NijikaPBFTStage::Commit);
+    }
+
+    #[test]
+    fn view_change_needs_quorum() {
+        let mut round: NijikaRound<TestBlock> = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 3, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::WaitPrePrepare);
+        let voter = HashValue::random();
+        let (first, second) = (HashValue::random(), HashValue::random());
+        assert!(round.add_view_change_vote(0, voter, 2, first));
+        assert!(!round.add_view_change_vote(0, voter, 2, second));
+        assert!(!round.has_quorum(round.get_view_change_weight(0)));
+        round.add_view_change_vote(0, HashValue::random(), 1, second);
+        assert!(round.has_quorum(round.get_view_change_weight(0)));
+        let next = round.new_view(1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::WaitPrePrepare);
+        assert_eq!(next.get_view(), 1);
+        assert_eq!(next.get_round_num(), 1);
+        // the proposer of the new view proves it with the ViewChange messages of the old one
+        assert_eq!(next.get_view_change_weight(0), 3);
+        assert_eq!(next.get_view_change_messages(0).len(), 2);
+        assert!(next.stage_timeout() > round.stage_timeout());
+    }
+
+    #[test]
+    fn only_a_later_prepared_block_replaces_the_known_one() {
+        let mut round: NijikaRound<TestBlock> = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 3, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::WaitPrePrepare);
+        let prepared = |view| NijikaPrepared { view, block_hash: HashValue::random(), proposer_key: vec![], messages: vec![] };
+        let first = prepared(1);
+        assert!(round.set_prepared(first.clone()));
+        assert!(!round.set_prepared(prepared(0)));
+        assert!(!round.set_prepared(prepared(1)));
+        let next = round.new_view(2, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::WaitPrePrepare);
+        assert_eq!(next.get_prepared(), Some(&first));
+        assert!(round.set_prepared(prepared(2)));
+    }
+
+    #[test]
+    fn round_keeps_every_role_won() {
+        let mut sortition = NijikaSortition::new();
+        assert_eq!(sortition.primary_role(), NijikaNodeRole::NORMAL);
+        sortition.insert(NijikaVRFCredential { role: NijikaNodeRole::PACKER, sub_users: 2, ..Default::default() });
+        sortition.insert(NijikaVRFCredential { role: NijikaNodeRole::VALIDATOR, sub_users: 3, ..Default::default() });
+        assert_eq!(sortition.primary_role(), NijikaNodeRole::VALIDATOR);
+        let mut round: NijikaRound<TestBlock> = NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 3, 1, sortition.primary_role(), NijikaPBFTStage::WaitPrePrepare);
+        round.set_sortition(sortition);
+        assert!(round.has_role(NijikaNodeRole::PACKER));
+        assert!(!round.has_role(NijikaNodeRole::PROPOSER));
+        assert_eq!(round.get_credential().sub_users, 3);
+        assert_eq!(round.get_sortition().roles(), vec![NijikaNodeRole::PACKER, NijikaNodeRole::VALIDATOR]);
+    }
+}
@@ -0,0 +1,395 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::hash::{NijikaHasher, NijikaDigestT};
+
+use super::{ByteArray, HashValue, NijikaError, NijikaResult, NijikaNodeRole, NijikaStakeChange, NijikaSignedStakeChange, NijikaControlBlockT};
+
+/// the only format version this build writes and reads. Bump it whenever the layout below changes
+pub const NIJIKA_ENCODING_VERSION: u8 = 1;
+
+/// what a frame holds, written right after the version byte so that one kind can never be read as another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NijikaEncodingKind {
+    Vote = 1,
+    ControlBlockHeader = 2,
+    /// the fields of a pbft message that its signature covers
+    SignedPBFTMessage = 3,
+    PBFTMessage = 4,
+    /// a stake change with the round its owner signs it for
+    StakeChange = 5,
+    /// two conflicting pbft messages of one sender
+    Evidence = 6,
+}
+
+/// writes the canonical form: integers are big-endian and fixed width, bools are one byte,
+/// byte strings and lists are preceded by a u32 count, options by a 0 or 1 byte and enums by an explicit tag.
+/// Node IDs are written by their NijikaIdT impl, control block bodies are opaque byte strings of their bincode form
+#[derive(Debug)]
+pub struct NijikaEncoder {
+    bytes: Vec<u8>,
+}
+
+impl NijikaEncoder {
+    pub fn new(kind: NijikaEncodingKind) -> Self {
+        NijikaEncoder { bytes: vec![NIJIKA_ENCODING_VERSION, kind as u8] }
+    }
+    pub fn put_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+    pub fn put_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+    pub fn put_bool(&mut self, value: bool) {
+        self.put_u8(value as u8);
+    }
+    pub fn put_len(&mut self, len: usize) -> NijikaResult<()> {
+        let len = u32::try_from(len).map_err(|_| NijikaError::ParseError(format!("{} items do not fit a u32 count", len)))?;
+        self.bytes.extend_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+    pub fn put_bytes(&mut self, value: &[u8]) -> NijikaResult<()> {
+        self.put_len(value.len())?;
+        self.bytes.extend_from_slice(value);
+        Ok(())
+    }
+    /// a digest is written as it is, its width is known from its type
+    pub fn put_hash<D: NijikaDigestT>(&mut self, value: &D) {
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+    pub fn put_id<I: NijikaIdT>(&mut self, value: &I) {
+        value.encode_id(self);
+    }
+    pub fn put_stake_change(&mut self, change: &NijikaStakeChange) {
+        match *change {
+            NijikaStakeChange::Deposit { account, amount } => {
+                self.put_u8(0);
+                self.put_hash(&account);
+                self.put_u64(amount);
+            },
+            NijikaStakeChange::Withdraw { account, amount } => {
+                self.put_u8(1);
+                self.put_hash(&account);
+                self.put_u64(amount);
+            },
+            NijikaStakeChange::Transfer { from, to, amount } => {
+                self.put_u8(2);
+                self.put_hash(&from);
+                self.put_hash(&to);
+                self.put_u64(amount);
+            },
+        }
+    }
+    pub fn put_opaque<T: Serialize>(&mut self, value: &T) -> NijikaResult<()> {
+        let bytes = bincode::serialize(value).map_err(|e| NijikaError::ParseError(format!("{}", e)))?;
+        self.put_bytes(&bytes)
+    }
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// reads what NijikaEncoder wrote, refusing other versions, other kinds and bytes left over
+#[derive(Debug)]
+pub struct NijikaDecoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> NijikaDecoder<'a> {
+    pub fn new(bytes: &'a [u8], kind: NijikaEncodingKind) -> NijikaResult<Self> {
+        let mut decoder = NijikaDecoder { bytes };
+        let version = decoder.get_u8()?;
+        if version != NIJIKA_ENCODING_VERSION {
+            return Err(NijikaError::UnsupportedVersion(version));
+        }
+        let found = decoder.get_u8()?;
+        if found != kind as u8 {
+            return Err(NijikaError::ParseError(format!("expected a {:?}, found kind {}", kind, found)));
+        }
+        Ok(decoder)
+    }
+    fn take(&mut self, len: usize) -> NijikaResult<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(NijikaError::ParseError(format!("{} more bytes expected, {} left", len, self.bytes.len())));
+        }
+        let (head, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(head)
+    }
+    pub fn get_u8(&mut self) -> NijikaResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn get_u64(&mut self) -> NijikaResult<u64> {
+        let bytes = self.take(8)?.try_into().expect("8 bytes");
+        Ok(u64::from_be_bytes(bytes))
+    }
+    pub fn get_bool(&mut self) -> NijikaResult<bool> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(NijikaError::ParseError(format!("{} is not a bool", other)))
+        }
+    }
+    pub fn get_len(&mut self) -> NijikaResult<usize> {
+        let bytes = self.take(4)?.try_into().expect("4 bytes");
+        Ok(u32::from_be_bytes(bytes) as usize)
+    }
+    pub fn get_bytes(&mut self) -> NijikaResult<Vec<u8>> {
+        let len = self.get_len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+    pub fn get_hash<D: NijikaDigestT>(&mut self) -> NijikaResult<D> {
+        D::from_bytes(self.take(D::LEN)?)
+    }
+    pub fn get_id<I: NijikaIdT>(&mut self) -> NijikaResult<I> {
+        I::decode_id(self)
+    }
+    pub fn get_stake_change(&mut self) -> NijikaResult<NijikaStakeChange> {
+        match self.get_u8()? {
+            0 => Ok(NijikaStakeChange::Deposit { account: self.get_hash()?, amount: self.get_u64()? }),
+            1 => Ok(NijikaStakeChange::Withdraw { account: self.get_hash()?, amount: self.get_u64()? }),
+            2 => Ok(NijikaStakeChange::Transfer { from: self.get_hash()?, to: self.get_hash()?, amount: self.get_u64()? }),
+            other => Err(NijikaError::ParseError(format!("unknown stake change tag {}", other)))
+        }
+    }
+    pub fn get_opaque<T: DeserializeOwned>(&mut self) -> NijikaResult<T> {
+        bincode::deserialize(&self.get_bytes()?).map_err(|e| NijikaError::ParseError(format!("{}", e)))
+    }
+    /// the end of the frame, nothing may follow the value
+    pub fn finish(self) -> NijikaResult<()> {
+        match self.bytes.len() {
+            0 => Ok(()),
+            left => Err(NijikaError::ParseError(format!("{} trailing bytes", left)))
+        }
+    }
+}
+
+/// a node ID in its canonical form, so that the signed bytes of a vote do not depend on bincode
+pub trait NijikaIdT: Sized {
+    fn encode_id(&self, encoder: &mut NijikaEncoder);
+    fn decode_id(decoder: &mut NijikaDecoder) -> NijikaResult<Self>;
+}
+
+impl NijikaIdT for u64 {
+    fn encode_id(&self, encoder: &mut NijikaEncoder) {
+        encoder.put_u64(*self);
+    }
+    fn decode_id(decoder: &mut NijikaDecoder) -> NijikaResult<Self> {
+        decoder.get_u64()
+    }
+}
+
+impl<const L: usize> NijikaIdT for ByteArray<L> {
+    fn encode_id(&self, encoder: &mut NijikaEncoder) {
+        encoder.put_hash(self);
+    }
+    fn decode_id(decoder: &mut NijikaDecoder) -> NijikaResult<Self> {
+        decoder.get_hash()
+    }
+}
+
+impl NijikaNodeRole {
+    pub fn tag(&self) -> u8 {
+        match self {
+            NijikaNodeRole::NORMAL => 0,
+            NijikaNodeRole::PACKER => 1,
+            NijikaNodeRole::PROPOSER => 2,
+            NijikaNodeRole::VALIDATOR => 3,
+        }
+    }
+    pub fn from_tag(tag: u8) -> NijikaResult<Self> {
+        match tag {
+            0 => Ok(NijikaNodeRole::NORMAL),
+            1 => Ok(NijikaNodeRole::PACKER),
+            2 => Ok(NijikaNodeRole::PROPOSER),
+            3 => Ok(NijikaNodeRole::VALIDATOR),
+            other => Err(NijikaError::ParseError(format!("unknown role tag {}", other)))
+        }
+    }
+}
+
+/// the fields of a control block that consensus relies on, in a layout that does not depend on the embedder's struct
+/// D is the digest of the block's hasher, stake accounts are HashValues whatever it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NijikaControlBlockHeader<D: NijikaDigestT = HashValue> {
+    pub round: u64,
+    pub proposer: HashValue,
+    pub timestamp: u64,
+    pub seed: u64,
+    pub seed_proof: Vec<u8>,
+    pub pre_hash: D,
+    pub data_block_pointers: Vec<D>,
+    pub stake_changes: Vec<NijikaSignedStakeChange>,
+}
+
+impl<D: NijikaDigestT> NijikaControlBlockHeader<D> {
+    pub fn of<CB: NijikaControlBlockT + ?Sized>(block: &CB) -> Self where CB::Hasher: NijikaHasher<Output = D> {
+        NijikaControlBlockHeader {
+            round: block.get_round(),
+            proposer: *block.get_proposer(),
+            timestamp: block.get_timestamp(),
+            seed: block.get_seed(),
+            seed_proof: block.get_seed_proof().to_vec(),
+            pre_hash: *block.get_pre_hash(),
+            data_block_pointers: block.get_data_block_pointers().to_vec(),
+            stake_changes: block.get_stake_changes().to_vec(),
+        }
+    }
+
+    pub fn encode(&self) -> NijikaResult<Vec<u8>> {
+        let mut encoder = NijikaEncoder::new(NijikaEncodingKind::ControlBlockHeader);
+        encoder.put_u64(self.round);
+        encoder.put_hash(&self.proposer);
+        encoder.put_u64(self.timestamp);
+        encoder.put_u64(self.seed);
+        encoder.put_bytes(&self.seed_proof)?;
+        encoder.put_hash(&self.pre_hash);
+        encoder.put_len(self.data_block_pointers.len())?;
+        self.data_block_pointers.iter().for_each(|pointer| encoder.put_hash(pointer));
+        encoder.put_len(self.stake_changes.len())?;
+        for signed in self.stake_changes.iter() {
+            encoder.put_stake_change(&signed.change);
+            encoder.put_bytes(&signed.signature)?;
+        }
+        Ok(encoder.finish())
+    }
+
+    pub fn decode(bytes: &[u8]) -> NijikaResult<Self> {
+        let mut decoder = NijikaDecoder::new(bytes, NijikaEncodingKind::ControlBlockHeader)?;
+        let round = decoder.get_u64()?;
+        let proposer = decoder.get_hash()?;
+        let timestamp = decoder.get_u64()?;
+        let seed = decoder.get_u64()?;
+        let seed_proof = decoder.get_bytes()?;
+        let pre_hash = decoder.get_hash()?;
+        let data_block_pointers = (0..decoder.get_len()?).map(|_| decoder.get_hash()).collect::<NijikaResult<_>>()?;
+        let stake_changes = (0..decoder.get_len()?)
+            .map(|_| Ok(NijikaSignedStakeChange { change: decoder.get_stake_change()?, signature: decoder.get_bytes()? }))
+            .collect::<NijikaResult<_>>()?;
+        decoder.finish()?;
+        Ok(NijikaControlBlockHeader { round, proposer, timestamp, seed, seed_proof, pre_hash, data_block_pointers, stake_changes })
+    }
+
+    pub fn hash_with<H: NijikaHasher>(&self) -> NijikaResult<H::Output> {
+        Ok(H::digest(&self.encode()?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{hash::NijikaSha512, testing::TestBlock, primitives::{NijikaPBFTMessage, NijikaPBFTMessageType, NijikaVote, NijikaRejectReason, NijikaVRFCredential}};
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn filled(byte: u8) -> HashValue {
+        HashValue::new([byte; 64])
+    }
+
+    fn commit_message() -> NijikaPBFTMessage<TestBlock, u64> {
+        let credential = NijikaVRFCredential { role: NijikaNodeRole::VALIDATOR, sub_users: 2, proof: vec![1], hash: vec![2], public_key: vec![3] };
+        let mut message = NijikaPBFTMessage::new_vote_message(7u64, 1, 0, NijikaPBFTMessageType::Commit, filled(0x11), NijikaVote::new_true(7u64), credential);
+        message.set_signature(vec![9, 9]);
+        message
+    }
+
+    #[test]
+    fn vote_golden_vector() {
+        let vote = NijikaVote::new_false(7u64, NijikaRejectReason::WrongSeed);
+        let bytes = vote.encode().unwrap();
+        assert_eq!(hex(&bytes), "01010000000000000007000102");
+        assert_eq!(NijikaVote::<u64>::decode(&bytes).unwrap(), vote);
+    }
+
+    #[test]
+    fn header_golden_vector() {
+        let header = NijikaControlBlockHeader {
+            round: 3,
+            proposer: filled(0x55),
+            timestamp: 4,
+            seed: 5,
+            seed_proof: vec![0xaa, 0xbb],
+            pre_hash: filled(0x11),
+            data_block_pointers: vec![filled(0x22)],
+            stake_changes: vec![NijikaSignedStakeChange {
+                change: NijikaStakeChange::Transfer { from: filled(0x33), to: filled(0x44), amount: 9 },
+                signature: vec![0xcc],
+            }],
+        };
+        let bytes = header.encode().unwrap();
+        let expected = [
+            "0102", "0000000000000003", &"55".repeat(64), "0000000000000004", "0000000000000005", "00000002aabb", &"11".repeat(64),
+            "00000001", &"22".repeat(64),
+            "00000001", "02", &"33".repeat(64), &"44".repeat(64), "0000000000000009", "00000001cc",
+        ].concat();
+        assert_eq!(hex(&bytes), expected);
+        assert_eq!(NijikaControlBlockHeader::decode(&bytes).unwrap(), header);
+    }
+
+    #[test]
+    fn control_blocks_are_hashed_through_their_header() {
+        let block = TestBlock::child(&TestBlock::genesis(), 1, 4);
+        let header = NijikaControlBlockHeader::decode(&block.header_bytes().unwrap()).unwrap();
+        assert_eq!(header, NijikaControlBlockHeader::of(&block));
+        assert_eq!(header.timestamp, 4);
+        assert_eq!(block.header_hash().unwrap(), header.hash_with::<NijikaSha512>().unwrap());
+        assert_ne!(block.header_hash().unwrap(), TestBlock::child(&TestBlock::genesis(), 1, 5).header_hash().unwrap());
+        let mut other_proposer = header.clone();
+        other_proposer.proposer = filled(0x66);
+        assert_ne!(block.header_hash().unwrap(), other_proposer.hash_with::<NijikaSha512>().unwrap());
+    }
+
+    #[test]
+    fn digest_ids_are_written_raw() {
+        let vote = NijikaVote::new_true(filled(0x55));
+        let bytes = vote.encode().unwrap();
+        assert_eq!(hex(&bytes), ["0101", &"55".repeat(64), "01", "00"].concat());
+        assert_eq!(NijikaVote::<HashValue>::decode(&bytes).unwrap(), vote);
+    }
+
+    #[test]
+    fn pbft_message_golden_vector() {
+        let message = commit_message();
+        let fields = [
+            "0000000000000007", "0000000000000001", "0000000000000000", "02", &"11".repeat(64),
+            "01", "0000000000000007", "01", "00",
+            "03", "0000000000000002", "0000000101", "0000000102", "0000000103",
+            "00000000",
+        ].concat();
+        assert_eq!(hex(&message.signing_bytes().unwrap()), ["0103", &fields].concat());
+        let bytes = message.encode().unwrap();
+        assert_eq!(hex(&bytes), ["0104", &fields, "000000020909", "00", "00000000"].concat());
+        let decoded = NijikaPBFTMessage::<TestBlock, u64>::decode(&bytes).unwrap();
+        assert_eq!(decoded.encode().unwrap(), bytes);
+        assert_eq!(decoded.hash().unwrap(), message.hash().unwrap());
+    }
+
+    #[test]
+    fn the_hash_leaves_the_control_block_out() {
+        let credential = NijikaVRFCredential::default();
+        let with_block = |round| NijikaPBFTMessage::new_control_block_message(7u64, 1, 0, NijikaPBFTMessageType::PrePrepare, filled(0), TestBlock::new(round, HashValue::default()), credential.clone());
+        let (a, b) = (with_block(1), with_block(2));
+        assert_ne!(a.encode().unwrap(), b.encode().unwrap());
+        assert_eq!(a.hash().unwrap(), b.hash().unwrap());
+        let decoded = NijikaPBFTMessage::<TestBlock, u64>::decode(&b.encode().unwrap()).unwrap();
+        assert_eq!(decoded.get_control_block().as_ref().map(|block| block.round), Some(2));
+    }
+
+    #[test]
+    fn bad_frames_are_refused() {
+        let bytes = commit_message().encode().unwrap();
+        let mut future = bytes.clone();
+        future[0] = NIJIKA_ENCODING_VERSION + 1;
+        assert!(matches!(NijikaPBFTMessage::<TestBlock, u64>::decode(&future), Err(NijikaError::UnsupportedVersion(2))));
+        assert!(NijikaVote::<u64>::decode(&bytes).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(NijikaPBFTMessage::<TestBlock, u64>::decode(&trailing).is_err());
+        assert!(NijikaPBFTMessage::<TestBlock, u64>::decode(&bytes[..bytes.len() - 1]).is_err());
+        let mut bad_tag = bytes.clone();
+        bad_tag[2 + 8 + 16] = 9;
+        assert!(NijikaPBFTMessage::<TestBlock, u64>::decode(&bad_tag).is_err());
+    }
+}
@@ -5,13 +5,22 @@ pub type NijikaResult<T> = Result<T, NijikaError>;
 pub enum NijikaError {
     InitializeFailed,
     NetworkFail(String),
+    StorageError(String),
+    StakeError(String),
     HashCollision(HashValue),
     InsufficientDataBlock,
     TooLessVote,
+    Equivocation(String),
     InvalidControlBlock(String),
+    InvalidDataBlock(String),
+    InvalidTransaction(String),
     InvalidPBFTMessage(String),
+    InvalidSignature(String),
     VRFError(String),
+    InvalidCredential(String),
     ParseError(String),
+    /// a canonical encoding of a format version this build does not know
+    UnsupportedVersion(u8),
     IncorrectStage(NijikaPBFTStage),
     MismatchedRole(NijikaNodeRole, NijikaNodeRole),
     MismatchedStage(NijikaPBFTStage, NijikaPBFTStage),
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use super::{NijikaControlBlockT, NijikaDigest, NijikaResult, NijikaError};
+
+/// blocks whose parent is unknown are kept until it arrives, up to this many
+pub const NIJIKA_MAX_ORPHANS: usize = 1024;
+
+#[derive(Debug)]
+struct NijikaLedgerEntry<CB> {
+    block: CB,
+    height: u64,
+}
+
+/// committed control blocks, stored by hash as a tree rooted at the genesis block.
+/// Every block must extend a known block with a greater round; blocks that arrive before their parent are kept as orphans.
+/// The tip is the end of the highest chain, the first one seen wins a tie
+#[derive(Debug)]
+pub struct NijikaLedger<CB: NijikaControlBlockT> {
+    blocks: HashMap<NijikaDigest<CB>, NijikaLedgerEntry<CB>>,
+    /// orphans by the hash of their missing parent
+    orphans: HashMap<NijikaDigest<CB>, Vec<CB>>,
+    orphan_count: usize,
+    genesis: NijikaDigest<CB>,
+    tip: NijikaDigest<CB>,
+}
+
+impl<CB: NijikaControlBlockT> NijikaLedger<CB> {
+    /// the pre_hash of the genesis block is not checked
+    pub fn new(genesis: CB) -> NijikaResult<Self> {
+        let hash = genesis.header_hash()?;
+        let mut blocks = HashMap::new();
+        blocks.insert(hash, NijikaLedgerEntry { block: genesis, height: 0 });
+        Ok(NijikaLedger { blocks, orphans: HashMap::new(), orphan_count: 0, genesis: hash, tip: hash })
+    }
+
+    /// add a block to the ledger and return the hashes of the blocks connected by it,
+    /// i.e. the block itself and the orphans waiting for it. Returns nothing for a known or orphaned block
+    pub fn commit(&mut self, block: CB) -> NijikaResult<Vec<NijikaDigest<CB>>> {
+        let hash = block.header_hash()?;
+        if self.contains(&hash) {
+            return Ok(vec![]);
+        }
+        let pre_hash = *block.get_pre_hash();
+        if !self.contains(&pre_hash) {
+            if self.orphan_count >= NIJIKA_MAX_ORPHANS {
+                return Err(NijikaError::InvalidControlBlock(format!("too many orphans, drop {}", hash)));
+            }
+            let siblings = self.orphans.entry(pre_hash).or_default();
+            if !siblings.iter().any(|b| b.header_hash().ok() == Some(hash)) {
+                siblings.push(block);
+                self.orphan_count += 1;
+            }
+            return Ok(vec![]);
+        }
+        self.connect(hash, block)?;
+        let mut connected = vec![hash];
+        let mut i = 0;
+        while i < connected.len() {
+            for orphan in self.orphans.remove(&connected[i]).unwrap_or_default() {
+                self.orphan_count -= 1;
+                let orphan_hash = orphan.header_hash()?;
+                // a bad orphan must not block its siblings
+                match self.connect(orphan_hash, orphan) {
+                    Ok(()) => connected.push(orphan_hash),
+                    Err(e) => println!("ledger error: {:?}", e),
+                }
+            }
+            i += 1;
+        }
+        Ok(connected)
+    }
+
+    /// link a block to its known parent and move the tip if it makes a higher chain
+    fn connect(&mut self, hash: NijikaDigest<CB>, block: CB) -> NijikaResult<()> {
+        let parent = &self.blocks[block.get_pre_hash()];
+        if block.get_round() <= parent.block.get_round() {
+            return Err(NijikaError::InvalidControlBlock(format!(
+                "block {} of round {} cannot extend a block of round {}", hash, block.get_round(), parent.block.get_round()
+            )));
+        }
+        let height = parent.height + 1;
+        self.blocks.insert(hash, NijikaLedgerEntry { block, height });
+        if height > self.get_height() {
+            self.tip = hash;
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, hash: &NijikaDigest<CB>) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &NijikaDigest<CB>) -> Option<&CB> {
+        self.blocks.get(hash).map(|entry| &entry.block)
+    }
+
+    pub fn get_genesis(&self) -> NijikaDigest<CB> {
+        self.genesis
+    }
+
+    pub fn get_tip(&self) -> NijikaDigest<CB> {
+        self.tip
+    }
+
+    pub fn get_tip_block(&self) -> &CB {
+        &self.blocks[&self.tip].block
+    }
+
+    /// the height of the tip, the genesis block is at 0
+    pub fn get_height(&self) -> u64 {
+        self.blocks[&self.tip].height
+    }
+
+    pub fn get_height_of(&self, hash: &NijikaDigest<CB>) -> Option<u64> {
+        self.blocks.get(hash).map(|entry| entry.height)
+    }
+
+    pub fn get_orphan_count(&self) -> usize {
+        self.orphan_count
+    }
+
+    /// the ancestor of the given block at the given height, the block itself included
+    pub fn get_ancestor(&self, hash: &NijikaDigest<CB>, height: u64) -> Option<NijikaDigest<CB>> {
+        let mut current = *hash;
+        let mut entry = self.blocks.get(&current)?;
+        if height > entry.height {
+            return None;
+        }
+        while entry.height > height {
+            current = *entry.block.get_pre_hash();
+            entry = self.blocks.get(&current)?;
+        }
+        Some(current)
+    }
+
+    pub fn is_ancestor(&self, ancestor: &NijikaDigest<CB>, descendant: &NijikaDigest<CB>) -> bool {
+        match self.get_height_of(ancestor) {
+            Some(height) => self.get_ancestor(descendant, height).as_ref() == Some(ancestor),
+            None => false
+        }
+    }
+
+    /// the block at the given height of the chain ending at the tip
+    pub fn get_block_at(&self, height: u64) -> Option<&CB> {
+        self.get_ancestor(&self.tip, height).and_then(|hash| self.get(&hash))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::NijikaSha256;
+    use crate::testing::{TestBlock, TestBlockOf};
+    use super::*;
+
+    #[test]
+    fn commit_checks_linkage_and_round() {
+        let genesis = TestBlock::genesis();
+        let mut ledger = NijikaLedger::new(genesis.clone()).unwrap();
+        let b1 = TestBlock::child(&genesis, 1, 0);
+        assert_eq!(ledger.commit(b1.clone()).unwrap(), vec![b1.header_hash().unwrap()]);
+        assert!(ledger.commit(b1.clone()).unwrap().is_empty());
+        assert!(ledger.commit(TestBlock::child(&b1, 1, 0)).is_err());
+        assert_eq!(ledger.get_height(), 1);
+        assert_eq!(ledger.get_tip(), b1.header_hash().unwrap());
+    }
+
+    #[test]
+    fn orphans_connect_when_parent_arrives() {
+        let genesis = TestBlock::genesis();
+        let mut ledger = NijikaLedger::new(genesis.clone()).unwrap();
+        let b1 = TestBlock::child(&genesis, 1, 0);
+        let b2 = TestBlock::child(&b1, 2, 0);
+        let b3 = TestBlock::child(&b2, 3, 0);
+        assert!(ledger.commit(b3.clone()).unwrap().is_empty());
+        assert!(ledger.commit(b2.clone()).unwrap().is_empty());
+        assert_eq!(ledger.get_orphan_count(), 2);
+        assert_eq!(ledger.commit(b1.clone()).unwrap().len(), 3);
+        assert_eq!(ledger.get_orphan_count(), 0);
+        assert_eq!(ledger.get_tip(), b3.header_hash().unwrap());
+        assert_eq!(ledger.get_block_at(2).unwrap().header_hash().unwrap(), b2.header_hash().unwrap());
+    }
+
+    #[test]
+    fn fork_choice_follows_the_highest_chain() {
+        let genesis = TestBlock::genesis();
+        let mut ledger = NijikaLedger::new(genesis.clone()).unwrap();
+        let a1 = TestBlock::child(&genesis, 1, 0);
+        let b1 = TestBlock::child(&genesis, 1, 1);
+        let b2 = TestBlock::child(&b1, 2, 1);
+        ledger.commit(a1.clone()).unwrap();
+        ledger.commit(b1.clone()).unwrap();
+        // the first block seen wins a tie
+        assert_eq!(ledger.get_tip(), a1.header_hash().unwrap());
+        ledger.commit(b2.clone()).unwrap();
+        assert_eq!(ledger.get_tip(), b2.header_hash().unwrap());
+        assert!(ledger.is_ancestor(&b1.header_hash().unwrap(), &b2.header_hash().unwrap()));
+        assert!(!ledger.is_ancestor(&a1.header_hash().unwrap(), &b2.header_hash().unwrap()));
+        assert_eq!(ledger.get_ancestor(&b2.header_hash().unwrap(), 0), Some(ledger.get_genesis()));
+    }
+
+    #[test]
+    fn blocks_can_be_keyed_by_sha256() {
+        let genesis = TestBlockOf::<NijikaSha256>::genesis();
+        let mut ledger = NijikaLedger::new(genesis.clone()).unwrap();
+        let b1 = TestBlockOf::child(&genesis, 1, 0);
+        assert_eq!(ledger.commit(b1.clone()).unwrap(), vec![b1.header_hash().unwrap()]);
+        assert_eq!(ledger.get_tip().as_bytes().len(), 32);
+        assert_eq!(ledger.get_block_at(1).map(|block| block.round), Some(1));
+    }
+}
@@ -1,9 +1,9 @@
 use std::fmt::Debug;
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 
-use crate::hash::hash;
+use crate::hash::NijikaHasher;
 
-use super::{HashValue, NijikaControlBlockT, NijikaError, NijikaResult, NijikaNodeT, NijikaPBFTStage};
+use super::{NijikaDigest, NijikaNodeRole, NijikaControlBlockT, NijikaError, NijikaResult, NijikaEncoder, NijikaDecoder, NijikaEncodingKind, NijikaIdT};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 pub enum NijikaPBFTMessageType {
@@ -11,62 +11,374 @@ pub enum NijikaPBFTMessageType {
     Prepare,
     Commit,
     Reply,
+    /// the sender gives up on the proposer of its current view
+    ViewChange,
+    /// the pre-prepare of a proposer elected after a view change
+    NewView,
 }
 
-#[derive(Debug, Serialize, Clone)]
-pub struct NijikaPBFTMessage<CB: NijikaControlBlockT, ID: Clone + Copy + Debug + Serialize > {
+impl NijikaPBFTMessageType {
+    /// the roles a sender must have won in the round to send this type of message
+    pub fn sender_roles(&self) -> &'static [NijikaNodeRole] {
+        match self {
+            NijikaPBFTMessageType::PrePrepare
+            | NijikaPBFTMessageType::Reply
+            | NijikaPBFTMessageType::NewView => &[NijikaNodeRole::PROPOSER],
+            NijikaPBFTMessageType::Prepare
+            | NijikaPBFTMessageType::Commit
+            | NijikaPBFTMessageType::ViewChange => &[NijikaNodeRole::VALIDATOR],
+        }
+    }
+
+    pub fn tag(&self) -> u8 {
+        match self {
+            NijikaPBFTMessageType::PrePrepare => 0,
+            NijikaPBFTMessageType::Prepare => 1,
+            NijikaPBFTMessageType::Commit => 2,
+            NijikaPBFTMessageType::Reply => 3,
+            NijikaPBFTMessageType::ViewChange => 4,
+            NijikaPBFTMessageType::NewView => 5,
+        }
+    }
+    pub fn from_tag(tag: u8) -> NijikaResult<Self> {
+        match tag {
+            0 => Ok(NijikaPBFTMessageType::PrePrepare),
+            1 => Ok(NijikaPBFTMessageType::Prepare),
+            2 => Ok(NijikaPBFTMessageType::Commit),
+            3 => Ok(NijikaPBFTMessageType::Reply),
+            4 => Ok(NijikaPBFTMessageType::ViewChange),
+            5 => Ok(NijikaPBFTMessageType::NewView),
+            other => Err(NijikaError::ParseError(format!("unknown pbft message tag {}", other)))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NijikaPBFTMessage<CB: NijikaControlBlockT, ID: Clone + Copy + Debug + Serialize + NijikaIdT > {
     source_node: ID,
     round_num: u64,
+    /// the view of the round the sender was in
+    view: u64,
     message_type: NijikaPBFTMessageType,
-    control_block_hash: HashValue,
+    control_block_hash: NijikaDigest<CB>,
     vote: Option<NijikaVote<ID>>,
     control_block: Option<CB>,
+    credential: NijikaVRFCredential,
+    signature: Vec<u8>,
+    /// the hashes of the messages backing this one, signed: a ViewChange's prepared certificate,
+    /// or the ViewChange messages that let a NewView's view begin
+    proof_hashes: Vec<NijikaDigest<CB>>,
+    /// the messages themselves, left out when the message is only carried as proof of another
+    proof: Vec<NijikaPBFTMessage<CB, ID>>,
+}
+
+/// how deep messages may be carried as proof of one another: a NewView carries ViewChanges, which carry certificates
+pub const NIJIKA_MAX_PROOF_DEPTH: usize = 2;
+
+/// the sender's sortition result for the round: the role it claims, how many sub-users it won,
+/// and the VRF proof, hash and public key to check it against
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct NijikaVRFCredential {
+    pub role: NijikaNodeRole,
+    pub sub_users: u64,
+    pub proof: Vec<u8>,
+    pub hash: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+impl NijikaVRFCredential {
+    pub fn encode_into(&self, encoder: &mut NijikaEncoder) -> NijikaResult<()> {
+        encoder.put_u8(self.role.tag());
+        encoder.put_u64(self.sub_users);
+        encoder.put_bytes(&self.proof)?;
+        encoder.put_bytes(&self.hash)?;
+        encoder.put_bytes(&self.public_key)
+    }
+    pub fn decode_from(decoder: &mut NijikaDecoder) -> NijikaResult<Self> {
+        Ok(NijikaVRFCredential {
+            role: NijikaNodeRole::from_tag(decoder.get_u8()?)?,
+            sub_users: decoder.get_u64()?,
+            proof: decoder.get_bytes()?,
+            hash: decoder.get_bytes()?,
+            public_key: decoder.get_bytes()?,
+        })
+    }
+}
+
+/// every role a node won in the sortition of a round view, with the credential proving each
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct NijikaSortition {
+    credentials: Vec<NijikaVRFCredential>,
+}
+
+impl NijikaSortition {
+    pub fn new() -> Self {
+        Self { credentials: vec![] }
+    }
+    /// keep the credential of a won role, replacing any earlier one of the same role
+    pub fn insert(&mut self, credential: NijikaVRFCredential) {
+        self.credentials.retain(|c| c.role != credential.role);
+        self.credentials.push(credential);
+    }
+    pub fn get(&self, role: NijikaNodeRole) -> Option<&NijikaVRFCredential> {
+        self.credentials.iter().find(|c| c.role == role)
+    }
+    pub fn has(&self, role: NijikaNodeRole) -> bool {
+        self.get(role).is_some()
+    }
+    pub fn roles(&self) -> Vec<NijikaNodeRole> {
+        self.credentials.iter().map(|c| c.role).collect()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.credentials.is_empty()
+    }
+    /// the role that drives the stages of the round: proposing first, then voting, then packing
+    pub fn primary_role(&self) -> NijikaNodeRole {
+        [NijikaNodeRole::PROPOSER, NijikaNodeRole::VALIDATOR, NijikaNodeRole::PACKER].into_iter()
+            .find(|role| self.has(*role))
+            .unwrap_or(NijikaNodeRole::NORMAL)
+    }
+}
+
+/// why a validator voted against a control block
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum NijikaRejectReason {
+    /// the block does not extend the tip of the ledger
+    WrongPreHash,
+    WrongRound,
+    /// the seed is not the one the previous block determines
+    WrongSeed,
+    InvalidSignature,
+    /// a data block pointer is not in the data block pool. Validators fetch such blocks instead of voting against the control block
+    MissingDataBlock,
+    /// a stake change is not signed by its owner or cannot apply on the stake of the tip
+    InvalidStakeChange,
+}
+
+impl NijikaRejectReason {
+    pub fn tag(&self) -> u8 {
+        match self {
+            NijikaRejectReason::WrongPreHash => 0,
+            NijikaRejectReason::WrongRound => 1,
+            NijikaRejectReason::WrongSeed => 2,
+            NijikaRejectReason::InvalidSignature => 3,
+            NijikaRejectReason::MissingDataBlock => 4,
+            NijikaRejectReason::InvalidStakeChange => 5,
+        }
+    }
+    pub fn from_tag(tag: u8) -> NijikaResult<Self> {
+        match tag {
+            0 => Ok(NijikaRejectReason::WrongPreHash),
+            1 => Ok(NijikaRejectReason::WrongRound),
+            2 => Ok(NijikaRejectReason::WrongSeed),
+            3 => Ok(NijikaRejectReason::InvalidSignature),
+            4 => Ok(NijikaRejectReason::MissingDataBlock),
+            5 => Ok(NijikaRejectReason::InvalidStakeChange),
+            other => Err(NijikaError::ParseError(format!("unknown reject reason tag {}", other)))
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
-pub struct NijikaVote<ID: Clone + Copy + Debug + Serialize> {
+pub struct NijikaVote<ID: Clone + Copy + Debug + Serialize + NijikaIdT> {
     id: ID,
     result: bool,
+    reason: Option<NijikaRejectReason>,
 }
 
-impl<ID: Clone + Copy + Debug + Serialize> NijikaVote<ID> {
+impl<ID: Clone + Copy + Debug + Serialize + NijikaIdT> NijikaVote<ID> {
     pub fn new_true(id: ID) -> Self {
-        NijikaVote { id, result: true }
+        NijikaVote { id, result: true, reason: None }
+    }
+    pub fn new_false(id: ID, reason: NijikaRejectReason) -> Self {
+        NijikaVote { id, result: false, reason: Some(reason) }
+    }
+    /// the reason of a false vote
+    pub fn get_reason(&self) -> Option<NijikaRejectReason> {
+        self.reason
+    }
+    pub fn get_id(&self) -> ID {
+        self.id
     }
     pub fn get_result(&self) -> bool {
         self.result
     }
+
+    pub fn encode_into(&self, encoder: &mut NijikaEncoder) -> NijikaResult<()> {
+        encoder.put_id(&self.id);
+        encoder.put_bool(self.result);
+        match self.reason {
+            Some(reason) => {
+                encoder.put_u8(1);
+                encoder.put_u8(reason.tag());
+            },
+            None => encoder.put_u8(0)
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> NijikaResult<Vec<u8>> {
+        let mut encoder = NijikaEncoder::new(NijikaEncodingKind::Vote);
+        self.encode_into(&mut encoder)?;
+        Ok(encoder.finish())
+    }
 }
 
-impl<CB: NijikaControlBlockT + Serialize + Debug, ID: Clone + Copy + Debug + Serialize > NijikaPBFTMessage<CB, ID> {
-    pub fn new_control_block_message(source_node: ID, round_num: u64, message_type: NijikaPBFTMessageType, control_block_hash: HashValue, control_block: CB) -> Self {
+impl<ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned> NijikaVote<ID> {
+    pub fn decode_from(decoder: &mut NijikaDecoder) -> NijikaResult<Self> {
+        let id = decoder.get_id()?;
+        let result = decoder.get_bool()?;
+        let reason = match decoder.get_bool()? {
+            true => Some(NijikaRejectReason::from_tag(decoder.get_u8()?)?),
+            false => None
+        };
+        Ok(NijikaVote { id, result, reason })
+    }
+
+    pub fn decode(bytes: &[u8]) -> NijikaResult<Self> {
+        let mut decoder = NijikaDecoder::new(bytes, NijikaEncodingKind::Vote)?;
+        let vote = Self::decode_from(&mut decoder)?;
+        decoder.finish()?;
+        Ok(vote)
+    }
+}
+
+impl<CB: NijikaControlBlockT + Serialize + Debug, ID: Clone + Copy + Debug + Serialize + NijikaIdT > NijikaPBFTMessage<CB, ID> {
+    pub fn new_control_block_message(source_node: ID, round_num: u64, view: u64, message_type: NijikaPBFTMessageType, control_block_hash: NijikaDigest<CB>, control_block: CB, credential: NijikaVRFCredential) -> Self {
         NijikaPBFTMessage {
             source_node,
             round_num,
+            view,
             message_type,
             control_block_hash,
             control_block: Some(control_block),
             vote: None,
+            credential,
+            signature: vec![],
+            proof_hashes: vec![],
+            proof: vec![],
         }
     }
 
-    pub fn new_vote_message(source_node: ID, round_num: u64, message_type: NijikaPBFTMessageType, control_block_hash: HashValue, vote: NijikaVote<ID>) -> Self {
+    pub fn new_vote_message(source_node: ID, round_num: u64, view: u64, message_type: NijikaPBFTMessageType, control_block_hash: NijikaDigest<CB>, vote: NijikaVote<ID>, credential: NijikaVRFCredential) -> Self {
         NijikaPBFTMessage {
             source_node,
             round_num,
+            view,
             message_type,
             control_block_hash,
             control_block: None,
             vote: Some(vote),
+            credential,
+            signature: vec![],
+            proof_hashes: vec![],
+            proof: vec![],
+        }
+    }
+
+    /// ask to leave the given view of a round
+    pub fn new_view_change_message(source_node: ID, round_num: u64, view: u64, credential: NijikaVRFCredential) -> Self {
+        NijikaPBFTMessage {
+            source_node,
+            round_num,
+            view,
+            message_type: NijikaPBFTMessageType::ViewChange,
+            control_block_hash: Default::default(),
+            control_block: None,
+            vote: None,
+            credential,
+            signature: vec![],
+            proof_hashes: vec![],
+            proof: vec![],
         }
     }
 
-    pub fn hash (&self) -> NijikaResult<HashValue> {
-        if let Ok(content) = bincode::serialize(self) {
-            Ok(hash::new(&content))
-        } else {
-            Err(NijikaError::ParseError(format!("parse error: msg {:#?}", self)))
+    /// attach the messages backing this one, before it is signed
+    pub fn set_proof(&mut self, proof: Vec<Self>) -> NijikaResult<()> {
+        self.proof_hashes = proof.iter().map(|message| message.hash()).collect::<NijikaResult<_>>()?;
+        self.proof = proof;
+        Ok(())
+    }
+    pub fn get_proof(&self) -> &[Self] {
+        &self.proof
+    }
+    pub fn get_proof_hashes(&self) -> &[NijikaDigest<CB>] {
+        &self.proof_hashes
+    }
+    /// the same signed message without its proof, to be carried as proof of another one
+    pub fn without_proof(&self) -> Self where CB: Clone {
+        NijikaPBFTMessage { proof: vec![], ..self.clone() }
+    }
+
+    /// the digest under the hasher of the control block type
+    pub fn hash (&self) -> NijikaResult<NijikaDigest<CB>> {
+        self.hash_with::<CB::Hasher>()
+    }
+
+    /// the digest of the canonical form without the control block and the proof, which are committed through
+    /// control_block_hash and proof_hashes, so that the hash does not depend on how the embedder lays its block out
+    pub fn hash_with<H: NijikaHasher>(&self) -> NijikaResult<H::Output> {
+        Ok(H::digest(&self.encoder_without_block()?.finish()))
+    }
+
+    fn encode_signed_fields(&self, encoder: &mut NijikaEncoder) -> NijikaResult<()> {
+        encoder.put_id(&self.source_node);
+        encoder.put_u64(self.round_num);
+        encoder.put_u64(self.view);
+        encoder.put_u8(self.message_type.tag());
+        encoder.put_hash(&self.control_block_hash);
+        match &self.vote {
+            Some(vote) => {
+                encoder.put_u8(1);
+                vote.encode_into(encoder)?;
+            },
+            None => encoder.put_u8(0)
         }
+        self.credential.encode_into(encoder)?;
+        encoder.put_len(self.proof_hashes.len())?;
+        self.proof_hashes.iter().for_each(|hash| encoder.put_hash(hash));
+        Ok(())
+    }
+
+    fn encoder_without_block(&self) -> NijikaResult<NijikaEncoder> {
+        let mut encoder = NijikaEncoder::new(NijikaEncodingKind::PBFTMessage);
+        self.encode_signed_fields(&mut encoder)?;
+        encoder.put_bytes(&self.signature)?;
+        Ok(encoder)
+    }
+
+    /// the bytes covered by the signature. The control block itself is committed through control_block_hash
+    pub fn signing_bytes(&self) -> NijikaResult<Vec<u8>> {
+        let mut encoder = NijikaEncoder::new(NijikaEncodingKind::SignedPBFTMessage);
+        self.encode_signed_fields(&mut encoder)?;
+        Ok(encoder.finish())
+    }
+
+    /// the canonical form sent over the network and kept in storage
+    pub fn encode(&self) -> NijikaResult<Vec<u8>> {
+        let mut encoder = self.encoder_without_block()?;
+        match &self.control_block {
+            Some(control_block) => {
+                encoder.put_u8(1);
+                encoder.put_opaque(control_block)?;
+            },
+            None => encoder.put_u8(0)
+        }
+        encoder.put_len(self.proof.len())?;
+        for message in self.proof.iter() {
+            encoder.put_bytes(&message.encode()?)?;
+        }
+        Ok(encoder.finish())
+    }
+
+    pub fn get_signature(&self) -> &[u8] {
+        &self.signature
+    }
+    pub fn set_signature(&mut self, signature: Vec<u8>) {
+        self.signature = signature;
+    }
+    pub fn is_signed(&self) -> bool {
+        !self.signature.is_empty()
     }
 
     pub fn get_source(&self) -> ID {
@@ -75,26 +387,74 @@ impl<CB: NijikaControlBlockT + Serialize + Debug, ID: Clone + Copy + Debug + Ser
     pub fn get_round_num(&self) -> u64 {
         self.round_num
     }
+    pub fn get_view(&self) -> u64 {
+        self.view
+    }
     pub fn get_type(&self) -> NijikaPBFTMessageType {
         self.message_type
     }
     pub fn get_vote(&self) -> Option<NijikaVote<ID>> {
         self.vote
     }
+    pub fn get_credential(&self) -> &NijikaVRFCredential {
+        &self.credential
+    }
+    /// the voting weight claimed by the sender, i.e. its sortition sub-user count
+    pub fn get_weight(&self) -> u64 {
+        self.credential.sub_users
+    }
 
     pub fn get_control_block(&self) -> &Option<CB> {
         &self.control_block
     }
-    pub fn get_control_block_hash(&self) -> HashValue {
+    pub fn get_control_block_hash(&self) -> NijikaDigest<CB> {
         self.control_block_hash
     }
 }
 
+impl<CB: NijikaControlBlockT + Serialize + DeserializeOwned + Debug, ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned> NijikaPBFTMessage<CB, ID> {
+    /// read what encode wrote, refusing other format versions
+    pub fn decode(bytes: &[u8]) -> NijikaResult<Self> {
+        Self::decode_at(bytes, 0)
+    }
+
+    /// depth is how deep the message is carried as proof, deeper messages may not carry any
+    fn decode_at(bytes: &[u8], depth: usize) -> NijikaResult<Self> {
+        let mut decoder = NijikaDecoder::new(bytes, NijikaEncodingKind::PBFTMessage)?;
+        let source_node = decoder.get_id()?;
+        let round_num = decoder.get_u64()?;
+        let view = decoder.get_u64()?;
+        let message_type = NijikaPBFTMessageType::from_tag(decoder.get_u8()?)?;
+        let control_block_hash = decoder.get_hash()?;
+        let vote = match decoder.get_bool()? {
+            true => Some(NijikaVote::decode_from(&mut decoder)?),
+            false => None
+        };
+        let credential = NijikaVRFCredential::decode_from(&mut decoder)?;
+        let proof_hashes = (0..decoder.get_len()?).map(|_| decoder.get_hash()).collect::<NijikaResult<_>>()?;
+        let signature = decoder.get_bytes()?;
+        let control_block = match decoder.get_bool()? {
+            true => Some(decoder.get_opaque()?),
+            false => None
+        };
+        let proof_len = decoder.get_len()?;
+        if proof_len > 0 && depth >= NIJIKA_MAX_PROOF_DEPTH {
+            return Err(NijikaError::ParseError(format!("a proof nested deeper than {} messages", NIJIKA_MAX_PROOF_DEPTH)));
+        }
+        let proof = (0..proof_len).map(|_| Self::decode_at(&decoder.get_bytes()?, depth + 1)).collect::<NijikaResult<_>>()?;
+        decoder.finish()?;
+        Ok(NijikaPBFTMessage { source_node, round_num, view, message_type, control_block_hash, vote, control_block, credential, signature, proof_hashes, proof })
+    }
+}
+
 
+#[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::primitives::HashValue;
 
+    #[test]
     fn test_nijika_vote_with_option(/* hash: HashValue */) {
         let a = Some(NijikaVote::new_true(HashValue::random()));
         let b = bincode::serialize(&a).expect("fail 1");
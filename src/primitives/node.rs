@@ -1,18 +1,26 @@
 use std::{collections::HashMap, fmt::Debug};
 
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 
-use super::{HashValue, NijikaRound, NijikaControlBlockT, NijikaResult, NijikaPBFTMessage, NijikaPBFTStage, NijikaError, NijikaDataBlockT, NijikaPBFTMessageType};
+use crate::{hash::{hash, NijikaDigestT}, network::{NijikaTransportT, NijikaMessage, NijikaMessageType, NijikaMessageDataType, NijikaDataRequests}, storage::NijikaStorageT, evidence::NijikaEvidencePool};
 
-#[derive(Debug, Serialize, PartialEq, Eq, Clone, Copy, Hash)]
+use super::{HashValue, NijikaDigest, NijikaLedger, NijikaStakeRegistry, NijikaStakeChange, NijikaRound, NijikaControlBlockT, NijikaResult, NijikaPBFTMessage, NijikaDataBlockT, NijikaIdT};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Hash, Default)]
 pub enum NijikaNodeRole {
+    #[default]
     NORMAL,
     PACKER,
     PROPOSER,
     VALIDATOR,
 }
 
-pub trait NijikaNodeT<'a, CB: NijikaControlBlockT, DB: NijikaDataBlockT, ID: Clone + Copy + Debug + Serialize> {
+/// identifier of the data block hash queue in get_hash_queue
+pub const NIJIKA_DATA_BLOCK_QUEUE: &str = "data_block";
+/// identifier of the pbft message hash queue in get_hash_queue
+pub const NIJIKA_PBFT_MSG_QUEUE: &str = "pbft_msg";
+
+pub trait NijikaNodeT<'a, CB: NijikaControlBlockT, DB: NijikaDataBlockT<Hasher = CB::Hasher>, ID: Clone + Copy + Debug + Serialize + NijikaIdT> {
     // basic info
     fn get_name(&self) -> &str;
 
@@ -23,22 +31,64 @@ pub trait NijikaNodeT<'a, CB: NijikaControlBlockT, DB: NijikaDataBlockT, ID: Clo
     fn get_role(&self) -> NijikaNodeRole;
 
     fn get_weight(&self) -> u64;
+    /// the weight of the given node in the current round, used to re-run its sortition
+    fn get_weight_of(&self, id: ID) -> u64;
     fn get_total_weight(&self) -> u64;
-    fn get_vrf_params(&self) -> (u64, u64);
+    /// the weight of the given node in the sortition of the given round, read from the stake registry if there is one
+    fn get_weight_of_at(&self, id: ID, round_num: u64) -> u64 {
+        match (self.get_stake_registry(), hash::serialized(&id)) {
+            (Some(registry), Ok(account)) => registry.get_weight_at(round_num, &account),
+            _ => self.get_weight_of(id)
+        }
+    }
+    /// the total weight the sortition of the given round draws from, read from the stake registry if there is one
+    fn get_total_weight_at(&self, round_num: u64) -> u64 {
+        match self.get_stake_registry() {
+            Some(registry) => registry.get_total_at(round_num),
+            None => self.get_total_weight()
+        }
+    }
+    /// the expected sub-user count of the role in a round view, and the total weight it is drawn from
+    fn get_vrf_params(&self, role: NijikaNodeRole) -> (u64, u64);
 
     fn get_peer_info_mut(&mut self) -> &mut HashMap<HashValue, (String, String)>;
 
-    fn get_hash_queue(&self, identifier: Option<&str>) -> NijikaResult<&Vec<HashValue>>;
-    fn get_hash_queue_mut(&mut self, identifier: Option<&str>) -> NijikaResult<&mut Vec<HashValue>>;
+    /// the transport used to reach the peers, e.g. a NijikaTcpTransport
+    fn get_transport(&self) -> &dyn NijikaTransportT;
+
+    /// where blocks and pbft messages are kept across restarts, e.g. a NijikaFileStorage.
+    /// Nothing is persisted by default
+    fn get_storage_mut(&mut self) -> Option<&mut dyn NijikaStorageT> {
+        None
+    }
+
+    /// where conflicting pbft messages are detected and the evidence against their senders is kept.
+    /// Equivocation goes unnoticed by default
+    fn get_evidence_pool(&self) -> Option<&NijikaEvidencePool<CB, ID>> {
+        None
+    }
+    fn get_evidence_pool_mut(&mut self) -> Option<&mut NijikaEvidencePool<CB, ID>> {
+        None
+    }
+
+    fn get_hash_queue(&self, identifier: Option<&str>) -> NijikaResult<&Vec<NijikaDigest<CB>>>;
+    fn get_hash_queue_mut(&mut self, identifier: Option<&str>) -> NijikaResult<&mut Vec<NijikaDigest<CB>>>;
 
     fn get_vrf_seed(&self) -> u64;
     fn set_vrf_seed(&mut self, seed: u64) -> ();
 
     fn get_secret_key(&self) -> &[u8];
     fn get_public_key(&self) -> &[u8];
+    /// the VRF public key registered for the given node, if it is known
+    fn get_public_key_of(&self, id: ID) -> Option<&[u8]>;
     fn set_keys(&mut self, private_key: Vec<u8>, public_key: Vec<u8>) -> ();
     fn update_proof(&mut self, proof: Vec<u8>, hash: Vec<u8>) -> NijikaResult<()>;
 
+    /// sign the given content with node's signing key
+    fn sign(&self, content: &[u8]) -> NijikaResult<Vec<u8>>;
+    /// check that the signature over the given content was made by the signer node
+    fn verify_signature(&self, signer: ID, content: &[u8], signature: &[u8]) -> NijikaResult<bool>;
+
     // pbft round info
 
     fn set_round(&mut self, round: NijikaRound<CB>) -> NijikaResult<()>;
@@ -65,33 +115,143 @@ pub trait NijikaNodeT<'a, CB: NijikaControlBlockT, DB: NijikaDataBlockT, ID: Clo
 
 
     // handle block, block queue and block pool
-    /// Create a new control block with pre_hash.
-    /// Make sure that its seed equals node's VRFSeed.
-    /// Then, fill its data_block_pointers and empty the node's data_block_hash_queue
-    /// Finally, sign the block with node's key
-    fn new_control_block(&self) -> CB;
+    /// Create a new control block extending the ledger tip, carrying the given seed and its proof.
+    fn new_control_block(&self, seed: u64, seed_proof: Vec<u8>) -> CB;
+    /// fill the block's data_block_pointers and empty the node's data_block_hash_queue.
+    /// Finally, sign the block's header_bytes with node's key
     fn load_control_block(&mut self, block: &mut CB) -> NijikaResult<()>;
-    fn commit_control_block(&mut self, block: CB) -> NijikaResult<()>;
+    /// check the proposer's signature on the block's header_bytes, e.g. with verify_signature
+    fn verify_control_block_signature(&self, block: &CB) -> NijikaResult<bool>;
+
+    /// the stake behind the node weights, e.g. for get_weight to read NijikaStakeRegistry::get_weight_at.
+    /// Stake changes of committed blocks are not tracked by default
+    fn get_stake_registry(&self) -> Option<&NijikaStakeRegistry<NijikaDigest<CB>>> {
+        None
+    }
+    fn get_stake_registry_mut(&mut self) -> Option<&mut NijikaStakeRegistry<NijikaDigest<CB>>> {
+        None
+    }
+    /// the node owning the given stake account, whose signature a stake change drawing from it must carry.
+    /// No owner is known by default, so that no stake change is accepted
+    fn get_account_owner(&self, _account: &HashValue) -> Option<ID> {
+        None
+    }
+    /// the node whose signature a deposit must carry, as a deposit creates stake no account owner can vouch for.
+    /// There is none by default, so that no deposit is accepted
+    fn get_minting_authority(&self) -> Option<ID> {
+        None
+    }
+
+    /// the committed control blocks, new control blocks should extend its tip
+    fn get_ledger(&self) -> &NijikaLedger<CB>;
+    fn get_ledger_mut(&mut self) -> &mut NijikaLedger<CB>;
+
+    /// add the block to the ledger, an orphan is kept there until its parent is committed.
+    /// The transactions of the data blocks referenced by every block connected to the ledger are evicted from the mempool,
+    /// and the stake registry follows the chain of the tip
+    fn commit_control_block(&mut self, block: CB) -> NijikaResult<()> {
+        let connected = self.get_ledger_mut().commit(block)?;
+        self.follow_tip_stake()?;
+        let ledger = self.get_ledger();
+        let data_blocks = connected.iter()
+            .filter_map(|hash| ledger.get(hash))
+            .flat_map(|block| block.get_data_block_pointers().iter().copied())
+            .collect::<Vec<NijikaDigest<CB>>>();
+        self.evict_committed_transactions(&data_blocks)
+    }
+
+    /// bring the stake registry to the chain of the ledger tip: revert the applied blocks a fork switch left behind,
+    /// then apply the blocks of the tip chain after the last one still applied
+    fn follow_tip_stake(&mut self) -> NijikaResult<()> {
+        let tip = self.get_ledger().get_tip();
+        loop {
+            let last = match self.get_stake_registry() {
+                Some(registry) => registry.get_last_applied(),
+                None => return Ok(())
+            };
+            match last {
+                Some((_, block)) if !self.get_ledger().is_ancestor(&block, &tip) => (),
+                _ => break
+            }
+            if let Some(registry) = self.get_stake_registry_mut() {
+                registry.revert()?;
+            }
+        }
+        let (last_round, last_block) = match self.get_stake_registry() {
+            Some(registry) => (registry.get_last_round(), registry.get_last_applied().map(|(_, block)| block)),
+            None => return Ok(())
+        };
+        let ledger = self.get_ledger();
+        let mut blocks = vec![];
+        let mut current = tip;
+        while let Some(block) = ledger.get(&current) {
+            if Some(current) == last_block || block.get_round() <= last_round {
+                break;
+            }
+            let changes = block.get_stake_changes().iter().map(|signed| signed.change).collect::<Vec<NijikaStakeChange>>();
+            blocks.push((block.get_round(), current, changes));
+            current = *block.get_pre_hash();
+        }
+        if let Some(registry) = self.get_stake_registry_mut() {
+            for (round, block, changes) in blocks.into_iter().rev() {
+                registry.apply(round, block, &changes)?;
+            }
+        }
+        Ok(())
+    }
 
     /// Create a new data block
     fn new_data_block(&self) -> DB;
+    /// fill the data block with transactions selected from the node's mempool, e.g. NijikaMempool::select,
+    /// and hold them back with NijikaMempool::mark_packed
+    fn load_data_block(&mut self, block: &mut DB) -> NijikaResult<()>;
+    /// make the transactions held back by data blocks not committed in the ended round or view selectable again,
+    /// e.g. NijikaMempool::release_all
+    fn release_packed_transactions(&mut self) -> NijikaResult<()>;
+    /// drop the transactions carried by the given committed data blocks, whoever packed them, e.g. NijikaMempool::evict_committed.
+    /// A data block not received yet can be kept for later with NijikaMempool::await_committed
+    fn evict_committed_transactions(&mut self, data_blocks: &[NijikaDigest<CB>]) -> NijikaResult<()>;
 
     /// append the given hash to the node's data block hash queue
-    fn append_data_block_hash_queue(&mut self, hash: HashValue) -> NijikaResult<()>;
+    fn append_data_block_hash_queue(&mut self, hash: NijikaDigest<CB>) -> NijikaResult<()>;
 
     /// use the given hash as Key, the block as Value. Then insert it into the node's data block pool
-    fn insert_data_block_pool(&mut self, hash: HashValue, block: DB) -> NijikaResult<()>;
+    fn insert_data_block_pool(&mut self, hash: NijikaDigest<CB>, block: DB) -> NijikaResult<()>;
+
+    /// look up the data block pool
+    fn get_data_block(&self, hash: &NijikaDigest<CB>) -> Option<&DB>;
+
+    /// the data blocks asked for and not received yet, the only ones taken from Data messages
+    fn get_data_requests_mut(&mut self) -> &mut NijikaDataRequests<NijikaDigest<CB>>;
 
 
 
     // handle pbft message
     /// append node's pbft_message_queue with the given hash value
-    fn append_pbft_message_queue(&mut self, hash: HashValue) -> NijikaResult<()>;
+    fn append_pbft_message_queue(&mut self, hash: NijikaDigest<CB>) -> NijikaResult<()>;
 
     /// use the given hash as Key, the message as Value. Then insert it into the pbft_message_pool
-    fn insert_pbft_message_pool(&mut self, hash: HashValue, message: NijikaPBFTMessage<CB, ID>) -> NijikaResult<()>;
-
-    /// create a inv message and then broadcast the given hash to all peers, except the source node
-    fn broadcast_hash_message(&self, hash: HashValue, source: Option<HashValue>) -> NijikaResult<()>;
+    fn insert_pbft_message_pool(&mut self, hash: NijikaDigest<CB>, message: NijikaPBFTMessage<CB, ID>) -> NijikaResult<()>;
+
+    /// look up the pbft message pool
+    fn get_pbft_message(&self, hash: &NijikaDigest<CB>) -> Option<&NijikaPBFTMessage<CB, ID>>;
+
+    /// create a inv message of the given hash type, e.g. PBFTMsgHash, and then broadcast it to all peers, except the source node
+    fn broadcast_hash_message(&self, data_type: NijikaMessageDataType, hash: NijikaDigest<CB>, source: Option<HashValue>) -> NijikaResult<()> {
+        let transport = self.get_transport();
+        let message = NijikaMessage::new_invite_message(transport.get_local_id(), data_type, hash);
+        transport.broadcast(&message, source)
+    }
+
+    /// ask the given peer, or every peer, for the body of a data block, remembering its digest to check the answer against
+    fn request_data_block(&mut self, hash: NijikaDigest<CB>, peer: Option<HashValue>) -> NijikaResult<()> {
+        self.get_data_requests_mut().insert(hash);
+        let transport = self.get_transport();
+        let request = NijikaMessage::new_message(transport.get_local_id(), NijikaMessageType::GetData, NijikaMessageDataType::DataBlock, hash.as_bytes().to_vec());
+        match peer {
+            Some(peer) => transport.send(peer, request),
+            None => transport.broadcast(&request, None)
+        }
+    }
 
 }
\ No newline at end of file
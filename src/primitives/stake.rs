@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Serialize, Deserialize};
+
+use crate::hash::NijikaDigestT;
+
+use super::{HashValue, NijikaResult, NijikaError, NijikaEncoder, NijikaEncodingKind};
+
+/// the sortition of a round uses the weights of this many rounds before it,
+/// so that stake moved by the last blocks cannot steer the sortition they take part in
+pub const NIJIKA_STAKE_LOOKBACK: u64 = 2;
+/// how many rounds of balance history are kept past the lookback, to verify credentials of late messages
+pub const NIJIKA_STAKE_HISTORY: u64 = 64;
+
+/// a change of stake carried by a control block, accounts are keyed with hash::serialized of the node ID
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum NijikaStakeChange {
+    Deposit { account: HashValue, amount: u64 },
+    Withdraw { account: HashValue, amount: u64 },
+    Transfer { from: HashValue, to: HashValue, amount: u64 },
+}
+
+impl NijikaStakeChange {
+    /// the account whose owner must sign the change, the one it draws from.
+    /// A deposit draws from none, it is signed by the minting authority instead
+    pub fn get_owner(&self) -> Option<HashValue> {
+        match *self {
+            NijikaStakeChange::Deposit { .. } => None,
+            NijikaStakeChange::Withdraw { account, .. } => Some(account),
+            NijikaStakeChange::Transfer { from, .. } => Some(from),
+        }
+    }
+}
+
+/// a stake change with the signature of its owner over the change and the round of the block carrying it,
+/// so that it cannot be replayed in another block
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct NijikaSignedStakeChange {
+    pub change: NijikaStakeChange,
+    pub signature: Vec<u8>,
+}
+
+impl NijikaSignedStakeChange {
+    /// what the owner signs for the change to be carried by the block of the given round
+    pub fn signing_bytes(change: &NijikaStakeChange, round: u64) -> NijikaResult<Vec<u8>> {
+        let mut encoder = NijikaEncoder::new(NijikaEncodingKind::StakeChange);
+        encoder.put_u64(round);
+        encoder.put_stake_change(change);
+        Ok(encoder.finish())
+    }
+}
+
+/// the stake of every account, with the history needed to look the weights up as they were at an earlier round.
+/// Changes are applied block by block from committed control blocks, and the blocks of a chain left by a fork switch
+/// are reverted in reverse order. D is the digest of the control blocks
+#[derive(Debug, Clone)]
+pub struct NijikaStakeRegistry<D: NijikaDigestT = HashValue> {
+    lookback: u64,
+    /// account -> round -> its balance from the end of that round on
+    balances: HashMap<HashValue, BTreeMap<u64, u64>>,
+    /// round -> the total stake from the end of that round on
+    totals: BTreeMap<u64, u64>,
+    /// round -> the block applied in it and the accounts it changed, what reverting it removes.
+    /// Rounds older than the history are final and forgotten
+    applied: BTreeMap<u64, (D, Vec<HashValue>)>,
+    last_round: u64,
+}
+
+fn value_at(history: &BTreeMap<u64, u64>, round: u64) -> u64 {
+    history.range(..=round).next_back().map(|(_, value)| *value).unwrap_or(0)
+}
+
+/// drop the entries no lookup at or after the given round can reach, the last one before it stays
+fn prune_before(history: &mut BTreeMap<u64, u64>, round: u64) {
+    let keep = history.range(..=round).next_back().map(|(r, _)| *r);
+    if let Some(keep) = keep {
+        history.retain(|r, _| *r >= keep);
+    }
+}
+
+impl<D: NijikaDigestT> NijikaStakeRegistry<D> {
+    /// the stake at the genesis block, i.e. round 0
+    pub fn new(lookback: u64, genesis: impl IntoIterator<Item = (HashValue, u64)>) -> NijikaResult<Self> {
+        let mut registry = NijikaStakeRegistry { lookback, balances: HashMap::new(), totals: BTreeMap::new(), applied: BTreeMap::new(), last_round: 0 };
+        let mut total: u64 = 0;
+        for (account, amount) in genesis {
+            total = total.checked_add(amount)
+                .ok_or(NijikaError::StakeError(String::from("total stake overflows")))?;
+            let balance = registry.get_balance(&account) + amount;
+            registry.balances.entry(account).or_default().insert(0, balance);
+        }
+        registry.totals.insert(0, total);
+        Ok(registry)
+    }
+
+    pub fn get_lookback(&self) -> u64 {
+        self.lookback
+    }
+
+    /// the last round whose changes were applied
+    pub fn get_last_round(&self) -> u64 {
+        self.last_round
+    }
+
+    /// the current balance of the account
+    pub fn get_balance(&self, account: &HashValue) -> u64 {
+        self.balances.get(account).map(|history| value_at(history, u64::MAX)).unwrap_or(0)
+    }
+
+    /// the current total stake
+    pub fn get_total(&self) -> u64 {
+        value_at(&self.totals, u64::MAX)
+    }
+
+    /// the round whose balances the sortition of the given round uses
+    pub fn snapshot_round(&self, round: u64) -> u64 {
+        round.saturating_sub(self.lookback)
+    }
+
+    /// the weight of the account in the sortition of the given round
+    pub fn get_weight_at(&self, round: u64, account: &HashValue) -> u64 {
+        let snapshot = self.snapshot_round(round);
+        self.balances.get(account).map(|history| value_at(history, snapshot)).unwrap_or(0)
+    }
+
+    /// the total weight the sortition of the given round draws from
+    pub fn get_total_at(&self, round: u64) -> u64 {
+        value_at(&self.totals, self.snapshot_round(round))
+    }
+
+    /// every account with a weight in the sortition of the given round
+    pub fn snapshot(&self, round: u64) -> HashMap<HashValue, u64> {
+        self.balances.keys()
+            .map(|account| (*account, self.get_weight_at(round, account)))
+            .filter(|(_, weight)| *weight > 0)
+            .collect()
+    }
+
+    /// the last applied block that can still be reverted, with its round
+    pub fn get_last_applied(&self) -> Option<(u64, D)> {
+        self.applied.iter().next_back().map(|(round, (block, _))| (*round, *block))
+    }
+
+    /// the balances the changes of the given round would leave, and the total stake after them
+    fn updates(&self, round: u64, changes: &[NijikaStakeChange]) -> NijikaResult<(HashMap<HashValue, u64>, u64)> {
+        if round <= self.last_round {
+            return Err(NijikaError::StakeError(format!("round {} is not after the last applied round {}", round, self.last_round)));
+        }
+        let mut updated: HashMap<HashValue, u64> = HashMap::new();
+        let mut total = self.get_total();
+        for change in changes {
+            let (credit, debit, amount) = match *change {
+                NijikaStakeChange::Deposit { account, amount } => (Some(account), None, amount),
+                NijikaStakeChange::Withdraw { account, amount } => (None, Some(account), amount),
+                NijikaStakeChange::Transfer { from, to, amount } => (Some(to), Some(from), amount),
+            };
+            if let Some(account) = debit {
+                let balance = updated.get(&account).copied().unwrap_or_else(|| self.get_balance(&account));
+                let balance = balance.checked_sub(amount)
+                    .ok_or(NijikaError::StakeError(format!("{} holds less than {}", account, amount)))?;
+                updated.insert(account, balance);
+            }
+            if let Some(account) = credit {
+                let balance = updated.get(&account).copied().unwrap_or_else(|| self.get_balance(&account));
+                let balance = balance.checked_add(amount)
+                    .ok_or(NijikaError::StakeError(format!("balance of {} overflows", account)))?;
+                updated.insert(account, balance);
+            }
+            total = match (credit, debit) {
+                (Some(_), None) => total.checked_add(amount),
+                (None, Some(_)) => total.checked_sub(amount),
+                _ => Some(total),
+            }.ok_or(NijikaError::StakeError(String::from("total stake overflows")))?;
+        }
+        Ok((updated, total))
+    }
+
+    /// whether the changes could be applied in the given round, without applying them
+    pub fn check(&self, round: u64, changes: &[NijikaStakeChange]) -> NijikaResult<()> {
+        self.updates(round, changes).map(|_| ())
+    }
+
+    /// apply the stake changes of the given block, committed in the given round, all of them or none.
+    /// Rounds must be applied in increasing order
+    pub fn apply(&mut self, round: u64, block: D, changes: &[NijikaStakeChange]) -> NijikaResult<()> {
+        let (updated, total) = self.updates(round, changes)?;
+        let horizon = self.snapshot_round(round).saturating_sub(NIJIKA_STAKE_HISTORY);
+        let mut accounts = vec![];
+        for (account, balance) in updated {
+            let history = self.balances.entry(account).or_default();
+            history.insert(round, balance);
+            prune_before(history, horizon);
+            accounts.push(account);
+        }
+        self.totals.insert(round, total);
+        prune_before(&mut self.totals, horizon);
+        self.applied.insert(round, (block, accounts));
+        self.applied.retain(|r, _| *r >= horizon);
+        self.last_round = round;
+        Ok(())
+    }
+
+    /// undo the last applied block, e.g. when a fork switch leaves it behind
+    pub fn revert(&mut self) -> NijikaResult<()> {
+        let (round, (_, accounts)) = self.applied.pop_last()
+            .ok_or(NijikaError::StakeError(format!("the stake changes before round {} are final", self.last_round + 1)))?;
+        for account in accounts {
+            if let Some(history) = self.balances.get_mut(&account) {
+                history.remove(&round);
+            }
+        }
+        self.totals.remove(&round);
+        self.last_round = self.applied.keys().next_back().copied()
+            .unwrap_or_else(|| self.totals.keys().next_back().copied().unwrap_or(0));
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sortition_sees_the_stake_of_the_lookback_round() {
+        let (a, b) = (HashValue::random(), HashValue::random());
+        let mut registry: NijikaStakeRegistry = NijikaStakeRegistry::new(2, [(a, 100), (b, 50)]).unwrap();
+        registry.apply(1, HashValue::random(), &[NijikaStakeChange::Transfer { from: a, to: b, amount: 30 }]).unwrap();
+        registry.apply(3, HashValue::random(), &[NijikaStakeChange::Deposit { account: a, amount: 10 }]).unwrap();
+        assert_eq!(registry.get_balance(&a), 80);
+        assert_eq!(registry.get_total(), 160);
+        assert_eq!(registry.get_weight_at(2, &a), 100);
+        assert_eq!(registry.get_weight_at(3, &b), 80);
+        assert_eq!(registry.get_total_at(4), 150);
+        assert_eq!(registry.get_total_at(5), 160);
+        assert_eq!(registry.snapshot(3).len(), 2);
+    }
+
+    #[test]
+    fn changes_of_a_round_apply_all_or_none() {
+        let a = HashValue::random();
+        let mut registry: NijikaStakeRegistry = NijikaStakeRegistry::new(NIJIKA_STAKE_LOOKBACK, [(a, 10)]).unwrap();
+        let changes = [
+            NijikaStakeChange::Withdraw { account: a, amount: 5 },
+            NijikaStakeChange::Withdraw { account: a, amount: 6 },
+        ];
+        assert!(registry.check(1, &changes).is_err());
+        assert!(registry.apply(1, HashValue::random(), &changes).is_err());
+        assert_eq!(registry.get_balance(&a), 10);
+        registry.check(1, &changes[..1]).unwrap();
+        registry.apply(1, HashValue::random(), &changes[..1]).unwrap();
+        assert!(registry.check(1, &changes[..1]).is_err());
+        assert_eq!(registry.get_total(), 5);
+    }
+
+    #[test]
+    fn reverted_blocks_leave_no_trace() {
+        let (a, b) = (HashValue::random(), HashValue::random());
+        let mut registry: NijikaStakeRegistry = NijikaStakeRegistry::new(NIJIKA_STAKE_LOOKBACK, [(a, 10)]).unwrap();
+        let (b1, b2) = (HashValue::random(), HashValue::random());
+        registry.apply(1, b1, &[NijikaStakeChange::Transfer { from: a, to: b, amount: 4 }]).unwrap();
+        registry.apply(2, b2, &[NijikaStakeChange::Withdraw { account: b, amount: 1 }]).unwrap();
+        assert_eq!(registry.get_last_applied(), Some((2, b2)));
+        registry.revert().unwrap();
+        assert_eq!((registry.get_last_round(), registry.get_balance(&b), registry.get_total()), (1, 4, 10));
+        registry.revert().unwrap();
+        assert_eq!((registry.get_last_round(), registry.get_balance(&a), registry.get_balance(&b)), (0, 10, 0));
+        assert!(registry.revert().is_err());
+        // the fork applies its own block of round 1
+        registry.apply(1, b2, &[NijikaStakeChange::Deposit { account: b, amount: 2 }]).unwrap();
+        assert_eq!(registry.get_total(), 12);
+    }
+
+    #[test]
+    fn owners_sign_the_round_of_their_change() {
+        let a = HashValue::random();
+        let change = NijikaStakeChange::Transfer { from: a, to: HashValue::random(), amount: 1 };
+        assert_eq!(change.get_owner(), Some(a));
+        assert_eq!(NijikaStakeChange::Deposit { account: a, amount: 1 }.get_owner(), None);
+        assert_ne!(NijikaSignedStakeChange::signing_bytes(&change, 1).unwrap(), NijikaSignedStakeChange::signing_bytes(&change, 2).unwrap());
+    }
+}
@@ -0,0 +1,25 @@
+use crate::hash::hash;
+
+use super::{HashValue, Transaction};
+
+/// what the mempool needs to know about a transaction
+pub trait NijikaTransactionT: Clone {
+    /// unique id, e.g. the hash of the transaction
+    fn get_id(&self) -> HashValue;
+    /// encoded size in bytes, counted against the data block size limit
+    fn get_size(&self) -> u64;
+    fn get_fee(&self) -> u64;
+}
+
+/// the opaque fixed-size transaction pays no fee
+impl NijikaTransactionT for Transaction {
+    fn get_id(&self) -> HashValue {
+        hash::new(self.as_bytes())
+    }
+    fn get_size(&self) -> u64 {
+        self.as_bytes().len() as u64
+    }
+    fn get_fee(&self) -> u64 {
+        0
+    }
+}
@@ -1,9 +1,11 @@
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::{fmt::{Display, Formatter, Result as FmtResult}, str::FromStr};
 use serde::{
-    ser::{Serialize, Serializer, SerializeTuple},
-    Deserialize, de::Visitor
+    ser::{Serialize, Serializer},
+    Deserialize, de::{self, Visitor}
 };
 
+use super::{NijikaError, NijikaResult};
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct ByteArray<const L: usize>([u8; L]);
 
@@ -25,16 +27,25 @@ impl<'de, const L: usize> Deserialize<'de> for ByteArray<L> {
         impl<'de, const L: usize> Visitor<'de> for ArrayVisitor<L> {
             type Value = ByteArray<L>;
             fn expecting(&self, formatter: &mut Formatter) -> FmtResult {
-                formatter.write_str("a vec<u8>")
+                write!(formatter, "{} bytes", L)
             }
+            /// exactly L elements, a longer sequence is refused before it is read through
             fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
                 where
                     A: serde::de::SeqAccess<'de>, {
-                let mut res: Vec<u8> = Vec::new();
-                while let Ok(Some(elem)) = seq.next_element() {
-                    res.push(elem);
+                let mut res = [0u8; L];
+                for (i, byte) in res.iter_mut().enumerate() {
+                    *byte = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self))?;
+                }
+                if seq.next_element::<u8>()?.is_some() {
+                    return Err(de::Error::invalid_length(L + 1, &self));
                 }
-                Ok(ByteArray::from(res))
+                Ok(ByteArray(res))
+            }
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+                where
+                    E: de::Error, {
+                ByteArray::try_from(v).map_err(|_| E::invalid_length(v.len(), &self))
             }
         }
         deserializer.deserialize_seq(ArrayVisitor)
@@ -43,28 +54,64 @@ impl<'de, const L: usize> Deserialize<'de> for ByteArray<L> {
 
 
 impl<const L: usize> Display for ByteArray<L> {
+    /// 0x and two hex digits per byte, so that every value has the same width
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         f.write_str("0x")?;
-        self.0.iter().try_for_each(|v| write!(f, "{:x}", v))
+        self.0.iter().try_for_each(|v| write!(f, "{:02x}", v))
     }
 }
 
-impl<const L: usize> ByteArray<L> {
-    pub fn new(data: [u8;L]) -> Self {
-        Self(data)
+impl<const L: usize> FromStr for ByteArray<L> {
+    type Err = NijikaError;
+    /// the 2 * L hex digits Display writes, the 0x is optional
+    fn from_str(s: &str) -> NijikaResult<Self> {
+        let digits = s.strip_prefix("0x").unwrap_or(s).as_bytes();
+        if digits.len() != 2 * L {
+            return Err(NijikaError::ParseError(format!("expected {} hex digits, got {}", 2 * L, digits.len())));
+        }
+        let digit = |c: u8| (c as char).to_digit(16)
+            .map(|d| d as u8)
+            .ok_or_else(|| NijikaError::ParseError(format!("{:?} is not a hex digit", c as char)));
+        let mut res = [0u8; L];
+        for (byte, pair) in res.iter_mut().zip(digits.chunks(2)) {
+            *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+        }
+        Ok(ByteArray(res))
+    }
+}
+
+impl<const L: usize> TryFrom<&[u8]> for ByteArray<L> {
+    type Error = NijikaError;
+    fn try_from(data: &[u8]) -> NijikaResult<Self> {
+        let res: [u8; L] = data.try_into()
+            .map_err(|_| NijikaError::ParseError(format!("expected {} bytes, got {}", L, data.len())))?;
+        Ok(ByteArray(res))
     }
+}
 
-    pub fn from(data: Vec<u8>) -> Self {
-        let res: [u8; L] = match data.try_into() {
-            Ok(a) => a,
-            Err(e) => panic!("unable to convert a vec to arr, with its length: {}", e.len())
-        };
-        ByteArray::new(res)
+impl<const L: usize> TryFrom<Vec<u8>> for ByteArray<L> {
+    type Error = NijikaError;
+    fn try_from(data: Vec<u8>) -> NijikaResult<Self> {
+        Self::try_from(data.as_slice())
     }
+}
 
-    pub fn default() -> Self {
+impl<const L: usize> From<[u8; L]> for ByteArray<L> {
+    fn from(data: [u8; L]) -> Self {
+        Self(data)
+    }
+}
+
+impl<const L: usize> Default for ByteArray<L> {
+    fn default() -> Self {
         Self([0; L])
     }
+}
+
+impl<const L: usize> ByteArray<L> {
+    pub fn new(data: [u8;L]) -> Self {
+        Self(data)
+    }
 
     pub fn random() -> Self {
         let mut a = Self::default();
@@ -84,6 +131,7 @@ pub type Signature = ByteArray<256>;
 pub type Transaction = ByteArray<512>;
 
 
+#[cfg(test)]
 mod tests {
     use super::*;
     #[test]
@@ -94,5 +142,36 @@ mod tests {
         println!("serialize: {:#?}, len: {}", b, b.len());
         let c: HashValue = bincode::deserialize(&b).expect("deserialize fail");
         println!("deserialize: {}", c);
+        assert_eq!(a, c);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn wrong_lengths_are_errors() {
+        assert!(HashValue::try_from(vec![0u8; 63]).is_err());
+        assert!(Signature::try_from(&[0u8; 257][..]).is_err());
+        assert!(Transaction::try_from(vec![7u8; 512]).is_ok());
+        let short = bincode::serialize(&vec![1u8; 63]).unwrap();
+        assert!(bincode::deserialize::<HashValue>(&short).is_err());
+        let long = bincode::serialize(&vec![1u8; 65]).unwrap();
+        assert!(bincode::deserialize::<HashValue>(&long).is_err());
+        let json = serde_json::to_string(&vec![1u8; 65]).unwrap();
+        assert!(serde_json::from_str::<HashValue>(&json).is_err());
+    }
+
+    #[test]
+    fn hex_has_a_fixed_width() {
+        let mut bytes = [0u8; 64];
+        bytes[0] = 0x0a;
+        bytes[63] = 0xf0;
+        let a = HashValue::new(bytes);
+        let text = a.to_string();
+        assert_eq!(text.len(), 2 + 128);
+        assert!(text.starts_with("0x0a00") && text.ends_with("00f0"));
+        assert_eq!(text.parse::<HashValue>().unwrap(), a);
+        assert_eq!(HashValue::from_str(&text[2..]).unwrap(), a);
+        assert!(HashValue::from_str(&text[..129]).is_err());
+        assert!(HashValue::from_str(&text.replace("0a", "0g")).is_err());
+        let t = Transaction::random();
+        assert_eq!(Transaction::from_str(&t.to_string()).unwrap(), t);
+    }
+}
@@ -0,0 +1,224 @@
+use std::{fmt::Debug, marker::PhantomData, net::SocketAddr, time::Duration};
+
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{
+    select,
+    spawn,
+    sync::mpsc::{self, UnboundedSender, UnboundedReceiver},
+    time::interval,
+};
+
+use crate::{
+    network::{NijikaGossipApi, NijikaMessage, NijikaTcpTransport},
+    primitives::{NijikaControlBlockT, NijikaDataBlockT, NijikaIdT, NijikaResult, NijikaError},
+};
+
+/// how often the runtime checks the stage deadlines of the current round
+pub const NIJIKA_TICK: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub enum NijikaEvent {
+    IncomingMessage(NijikaMessage),
+    /// time to check the deadline of the current stage
+    Tick,
+    /// the given round has ended, the runtime may start the next one
+    RoundEnd(u64),
+    Shutdown,
+}
+
+/// owns a node and drives it: every incoming message, timer tick and round end goes through one channel,
+/// and a new round is only started once the node's NijikaRound reports the current one has ended
+pub struct NijikaRuntime<'a, CB, DB, ID, N>
+where
+    CB: NijikaControlBlockT + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    DB: NijikaDataBlockT<Hasher = CB::Hasher> + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned + 'a,
+    N: NijikaGossipApi<'a, CB, DB, ID>,
+{
+    node: N,
+    thresh: u64,
+    /// a round that could not be started, tried again on every tick
+    pending_round: Option<u64>,
+    sender: UnboundedSender<NijikaEvent>,
+    receiver: UnboundedReceiver<NijikaEvent>,
+    _marker: PhantomData<&'a (CB, DB, ID)>,
+}
+
+impl<'a, CB, DB, ID, N> NijikaRuntime<'a, CB, DB, ID, N>
+where
+    CB: NijikaControlBlockT + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    DB: NijikaDataBlockT<Hasher = CB::Hasher> + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned + 'a,
+    N: NijikaGossipApi<'a, CB, DB, ID>,
+{
+    /// thresh is passed to enter_round for every round
+    pub fn new(node: N, thresh: u64) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        NijikaRuntime { node, thresh, pending_round: None, sender, receiver, _marker: PhantomData }
+    }
+
+    pub fn get_node(&self) -> &N {
+        &self.node
+    }
+
+    pub fn get_node_mut(&mut self) -> &mut N {
+        &mut self.node
+    }
+
+    pub fn into_node(self) -> N {
+        self.node
+    }
+
+    /// the channel to feed events into the runtime, e.g. messages from a transport
+    pub fn get_sender(&self) -> UnboundedSender<NijikaEvent> {
+        self.sender.clone()
+    }
+
+    /// listen on the address and forward every received message into the runtime
+    pub async fn listen(&self, transport: &NijikaTcpTransport, address: &str) -> NijikaResult<SocketAddr> {
+        let (inbox, mut incoming) = mpsc::unbounded_channel();
+        let local_address = transport.listen(address, inbox).await?;
+        let sender = self.get_sender();
+        spawn(async move {
+            while let Some(message) = incoming.recv().await {
+                if sender.send(NijikaEvent::IncomingMessage(message)).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(local_address)
+    }
+
+    /// a round that fails to start, e.g. on a storage error, does not stop the runtime but is tried again on the next tick
+    fn start_round(&mut self, round_num: u64) {
+        match self.node.enter_round(round_num, self.thresh) {
+            Ok(()) => self.pending_round = None,
+            Err(e) => {
+                println!("round {} start error: {:?}", round_num, e);
+                self.pending_round = Some(round_num);
+            }
+        }
+    }
+
+    /// handle one event; returns false once the runtime should stop
+    fn dispatch(&mut self, event: NijikaEvent, last_round: Option<u64>) -> NijikaResult<bool> {
+        match event {
+            NijikaEvent::IncomingMessage(message) => {
+                if let Err(e) = self.node.handle_message(message) {
+                    println!("message error: {:?}", e);
+                }
+            },
+            NijikaEvent::Tick => {
+                if let Some(round_num) = self.pending_round {
+                    self.start_round(round_num);
+                } else if let Err(e) = self.node.check_timeout() {
+                    println!("timeout error: {:?}", e);
+                }
+            },
+            NijikaEvent::RoundEnd(round_num) => {
+                // stale or duplicated round ends are ignored
+                if self.pending_round.is_some() || round_num != self.node.get_round_num() || !self.node.get_round().is_end() {
+                    return Ok(true);
+                }
+                // there is no round after u64::MAX
+                let next = match round_num.checked_add(1) {
+                    Some(next) if last_round.is_none_or(|last| round_num < last) => next,
+                    _ => return Ok(false)
+                };
+                self.start_round(next);
+            },
+            NijikaEvent::Shutdown => return Ok(false),
+        }
+        if self.pending_round.is_none() && self.node.get_round().is_end() {
+            self.sender.send(NijikaEvent::RoundEnd(self.node.get_round_num()))
+                .map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+        }
+        Ok(true)
+    }
+
+    /// run rounds from first_round on, until last_round has ended or a Shutdown event arrives
+    pub async fn run(&mut self, first_round: u64, last_round: Option<u64>) -> NijikaResult<()> {
+        self.start_round(first_round);
+        // a node that starts late catches up with the peers' ledger and round
+        if let Err(e) = self.node.request_blocks(None) {
+            println!("sync error: {:?}", e);
+        }
+        if self.pending_round.is_none() && self.node.get_round().is_end() {
+            self.sender.send(NijikaEvent::RoundEnd(first_round))
+                .map_err(|e| NijikaError::NetworkFail(format!("{}", e)))?;
+        }
+        let mut ticker = interval(NIJIKA_TICK);
+        loop {
+            let event = select! {
+                Some(event) = self.receiver.recv() => event,
+                _ = ticker.tick() => NijikaEvent::Tick,
+            };
+            if !self.dispatch(event, last_round)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::{
+        consensus::NijikaPBFTMessageApi,
+        primitives::{HashValue, NijikaNodeT, NijikaNodeRole, NIJIKA_DATA_BLOCK_QUEUE},
+        simulation::{NijikaSimConfig, NijikaSimNetwork, NijikaSimNode, NijikaSimControlBlock, NijikaSimDataBlock},
+    };
+    use super::*;
+
+    fn runtime(config: NijikaSimConfig) -> NijikaRuntime<'static, NijikaSimControlBlock, NijikaSimDataBlock, HashValue, NijikaSimNode> {
+        let network = NijikaSimNetwork::new(config.seed, config.min_latency, config.max_latency);
+        let node = NijikaSimNode::new_nodes(&config, &network).unwrap().remove(0);
+        NijikaRuntime::new(node, config.thresh)
+    }
+
+    #[tokio::test]
+    async fn a_lone_node_runs_its_rounds() {
+        // a node holding all the stake and expected to fill every seat is the whole committee
+        let mut runtime = runtime(NijikaSimConfig { nodes: 1, proposers: 100, validators: 100, ..Default::default() });
+        timeout(Duration::from_secs(30), runtime.run(1, Some(3))).await.unwrap().unwrap();
+        assert_eq!(runtime.get_node().get_round_num(), 3);
+        assert_eq!(runtime.get_node().get_ledger().get_height(), 3);
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_the_runtime() {
+        let mut runtime = runtime(NijikaSimConfig::default());
+        runtime.get_sender().send(NijikaEvent::Shutdown).unwrap();
+        timeout(Duration::from_secs(5), runtime.run(1, None)).await.unwrap().unwrap();
+        assert_eq!(runtime.get_node().get_round_num(), 1);
+    }
+
+    #[test]
+    fn stale_round_ends_are_ignored() {
+        let mut runtime = runtime(NijikaSimConfig::default());
+        runtime.start_round(1);
+        assert!(runtime.dispatch(NijikaEvent::RoundEnd(7), None).unwrap());
+        assert_eq!(runtime.get_node().get_round_num(), 1);
+    }
+
+    #[test]
+    fn a_round_start_tried_again_starts_the_round() {
+        let mut runtime = runtime(NijikaSimConfig { nodes: 1, ..Default::default() });
+        // a first try that got as far as packing, then failed
+        let thresh = runtime.thresh;
+        runtime.get_node_mut().enter_round(1, thresh).unwrap();
+        let packed = runtime.get_node().get_hash_queue(Some(NIJIKA_DATA_BLOCK_QUEUE)).unwrap().clone();
+        assert_eq!(packed.len(), 1);
+        runtime.pending_round = Some(1);
+        assert!(runtime.dispatch(NijikaEvent::Tick, None).unwrap());
+        assert_eq!(runtime.pending_round, None);
+        assert_eq!(runtime.get_node().get_round_num(), 1);
+        assert!(runtime.get_node().get_round().has_role(NijikaNodeRole::PACKER));
+        // the lone node proposed again, with the block stored by the first try
+        assert_eq!(runtime.get_node_mut().get_round_control_block().get_data_block_pointers(), packed.as_slice());
+    }
+}
@@ -0,0 +1,8 @@
+mod network;
+pub use network::*;
+mod block;
+pub use block::*;
+mod node;
+pub use node::*;
+mod simulator;
+pub use simulator::*;
@@ -0,0 +1,160 @@
+use serde::{Serialize, Deserialize};
+
+use crate::{hash::NijikaSha512, merkle, primitives::{
+    HashValue,
+    Transaction,
+    NijikaBlockType,
+    NijikaBlockT,
+    NijikaControlBlockT,
+    NijikaDataBlockT,
+    NijikaResult,
+    NijikaError,
+    NijikaSignedStakeChange
+}};
+
+/// the control block of the simulated nodes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NijikaSimControlBlock {
+    block_type: NijikaBlockType,
+    round_num: u64,
+    pre_hash: HashValue,
+    seed: u64,
+    seed_proof: Vec<u8>,
+    proposer_id: HashValue,
+    /// virtual milliseconds
+    timestamp: u64,
+    data_block_pointers: Vec<HashValue>,
+    stake_changes: Vec<NijikaSignedStakeChange>,
+    /// the proposer's signature on the header_bytes
+    signature: Vec<u8>,
+}
+
+impl NijikaBlockT for NijikaSimControlBlock {
+    type Hasher = NijikaSha512;
+    fn get_type(&self) -> &NijikaBlockType {
+        &self.block_type
+    }
+    fn get_round(&self) -> u64 {
+        self.round_num
+    }
+    fn as_bytes(&self) -> NijikaResult<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| NijikaError::ParseError(format!("{}", e)))
+    }
+    fn hash(&self) -> NijikaResult<HashValue> {
+        self.hash_with::<NijikaSha512>()
+    }
+}
+
+impl NijikaControlBlockT for NijikaSimControlBlock {
+    fn get_seed(&self) -> u64 {
+        self.seed
+    }
+    fn get_seed_proof(&self) -> &[u8] {
+        &self.seed_proof
+    }
+    fn get_pre_hash(&self) -> &HashValue {
+        &self.pre_hash
+    }
+    fn get_data_block_pointers(&self) -> &[HashValue] {
+        &self.data_block_pointers
+    }
+    fn get_stake_changes(&self) -> &[NijikaSignedStakeChange] {
+        &self.stake_changes
+    }
+    fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+    fn get_proposer(&self) -> &HashValue {
+        &self.proposer_id
+    }
+}
+
+impl NijikaSimControlBlock {
+    pub fn new(proposer_id: HashValue, round_num: u64, pre_hash: HashValue, seed: u64, seed_proof: Vec<u8>) -> Self {
+        NijikaSimControlBlock {
+            block_type: NijikaBlockType::CONTROL,
+            round_num,
+            pre_hash,
+            seed,
+            seed_proof,
+            proposer_id,
+            timestamp: 0,
+            data_block_pointers: vec![],
+            stake_changes: vec![],
+            signature: vec![],
+        }
+    }
+    /// the block every simulated node starts from
+    pub fn genesis(seed: u64) -> Self {
+        Self::new(HashValue::default(), 0, HashValue::default(), seed, vec![])
+    }
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+    pub fn push(&mut self, data_block: HashValue) {
+        self.data_block_pointers.push(data_block);
+    }
+    pub fn push_stake_change(&mut self, change: NijikaSignedStakeChange) {
+        self.stake_changes.push(change);
+    }
+    pub fn get_signature(&self) -> &[u8] {
+        &self.signature
+    }
+    pub fn set_signature(&mut self, signature: Vec<u8>) {
+        self.signature = signature;
+    }
+}
+
+/// the data block of the simulated nodes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NijikaSimDataBlock {
+    block_type: NijikaBlockType,
+    round_num: u64,
+    packer_id: HashValue,
+    merkle_root: HashValue,
+    transactions: Vec<Transaction>,
+}
+
+impl NijikaBlockT for NijikaSimDataBlock {
+    type Hasher = NijikaSha512;
+    fn get_type(&self) -> &NijikaBlockType {
+        &self.block_type
+    }
+    fn get_round(&self) -> u64 {
+        self.round_num
+    }
+    fn as_bytes(&self) -> NijikaResult<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| NijikaError::ParseError(format!("{}", e)))
+    }
+    fn hash(&self) -> NijikaResult<HashValue> {
+        self.hash_with::<NijikaSha512>()
+    }
+}
+
+impl NijikaDataBlockT for NijikaSimDataBlock {
+    fn get_merkle_root(&self) -> &HashValue {
+        &self.merkle_root
+    }
+    fn compute_merkle_root(&self) -> Option<HashValue> {
+        Some(merkle::root(&self.transactions))
+    }
+}
+
+impl NijikaSimDataBlock {
+    pub fn new(packer_id: HashValue, round_num: u64) -> Self {
+        NijikaSimDataBlock {
+            block_type: NijikaBlockType::DATA,
+            round_num,
+            packer_id,
+            merkle_root: merkle::root(&[]),
+            transactions: vec![],
+        }
+    }
+    pub fn set_transactions(&mut self, transactions: Vec<Transaction>) {
+        self.merkle_root = merkle::root(&transactions);
+        self.transactions = transactions;
+    }
+    pub fn get_transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+}
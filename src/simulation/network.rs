@@ -0,0 +1,267 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{Arc, Mutex}};
+
+use rand::Rng;
+use rand_chacha::{ChaCha8Rng, rand_core::SeedableRng};
+
+use crate::{network::{NijikaMessage, NijikaTransportT}, primitives::{HashValue, NijikaResult, NijikaError}};
+
+/// a message in flight, delivered to its destination once the virtual clock reaches `at`
+#[derive(Debug, Clone)]
+pub struct NijikaSimEnvelope {
+    pub at: u64,
+    pub from: HashValue,
+    pub to: HashValue,
+    pub message: NijikaMessage,
+}
+
+/// how the network misbehaves. Each message is dropped, duplicated or held back on its own, by chance
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NijikaSimFaults {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    /// a message held back gets up to max_delay more virtual milliseconds, which also reorders it
+    pub delay_rate: f64,
+    pub max_delay: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NijikaSimNetworkStats {
+    pub sent: u64,
+    /// lost by chance or cut by a partition
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delayed: u64,
+}
+
+#[derive(Debug)]
+struct NijikaSimState {
+    rng: ChaCha8Rng,
+    /// virtual milliseconds
+    now: u64,
+    next_seq: u64,
+    min_latency: u64,
+    max_latency: u64,
+    nodes: Vec<HashValue>,
+    /// (delivery time, send order) -> message
+    in_flight: BTreeMap<(u64, u64), NijikaSimEnvelope>,
+    faults: NijikaSimFaults,
+    /// node -> its side of the partition, None while the network is whole
+    partition: Option<HashMap<HashValue, usize>>,
+    stats: NijikaSimNetworkStats,
+}
+
+impl NijikaSimState {
+    /// draw only for the faults that are switched on, so that a healthy run does not depend on them
+    fn chance(&mut self, rate: f64) -> bool {
+        rate > 0.0 && self.rng.gen_bool(rate.min(1.0))
+    }
+
+    fn is_cut(&self, from: &HashValue, to: &HashValue) -> bool {
+        match &self.partition {
+            Some(sides) => sides.get(from) != sides.get(to),
+            None => false
+        }
+    }
+
+    fn schedule(&mut self, from: HashValue, to: HashValue, message: NijikaMessage) {
+        let (min, max) = (self.min_latency, self.max_latency);
+        let mut at = self.now + self.rng.gen_range(min..=max);
+        if self.chance(self.faults.delay_rate) {
+            at += self.rng.gen_range(0..=self.faults.max_delay);
+            self.stats.delayed += 1;
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.in_flight.insert((at, seq), NijikaSimEnvelope { at, from, to, message });
+    }
+}
+
+/// an in-memory network with a virtual clock. Every message gets a latency drawn from a seeded generator,
+/// and messages are delivered by time, then in the order they were sent, so that a run only depends on the seed
+#[derive(Debug, Clone)]
+pub struct NijikaSimNetwork {
+    state: Arc<Mutex<NijikaSimState>>,
+}
+
+impl NijikaSimNetwork {
+    /// latencies are drawn uniformly from min_latency..=max_latency virtual milliseconds
+    pub fn new(seed: u64, min_latency: u64, max_latency: u64) -> Self {
+        let state = NijikaSimState {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            now: 0,
+            next_seq: 0,
+            min_latency,
+            max_latency: max_latency.max(min_latency),
+            nodes: vec![],
+            in_flight: BTreeMap::new(),
+            faults: NijikaSimFaults::default(),
+            partition: None,
+            stats: NijikaSimNetworkStats::default(),
+        };
+        NijikaSimNetwork { state: Arc::new(Mutex::new(state)) }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, NijikaSimState> {
+        self.state.lock().expect("poisoned simulated network")
+    }
+
+    /// join the network; every node is a peer of all the others
+    pub fn add_node(&self, id: HashValue) -> NijikaSimTransport {
+        let mut state = self.lock();
+        if !state.nodes.contains(&id) {
+            state.nodes.push(id);
+        }
+        NijikaSimTransport { local_id: id, network: self.clone() }
+    }
+
+    pub fn get_nodes(&self) -> Vec<HashValue> {
+        self.lock().nodes.clone()
+    }
+
+    pub fn now(&self) -> u64 {
+        self.lock().now
+    }
+
+    /// how many messages are still on their way
+    pub fn in_flight(&self) -> usize {
+        self.lock().in_flight.len()
+    }
+
+    /// the faults apply to the messages sent from now on
+    pub fn set_faults(&self, faults: NijikaSimFaults) {
+        self.lock().faults = faults;
+    }
+
+    /// cut the network into the given sides; the nodes left out form one more side together
+    pub fn partition(&self, sides: &[Vec<HashValue>]) {
+        let map = sides.iter().enumerate()
+            .flat_map(|(side, nodes)| nodes.iter().map(move |id| (*id, side)))
+            .collect();
+        self.lock().partition = Some(map);
+    }
+
+    /// join the sides again, the messages cut meanwhile stay lost
+    pub fn heal(&self) {
+        self.lock().partition = None;
+    }
+
+    pub fn get_stats(&self) -> NijikaSimNetworkStats {
+        self.lock().stats.clone()
+    }
+
+    fn enqueue(&self, from: HashValue, to: HashValue, message: NijikaMessage) -> NijikaResult<()> {
+        let mut state = self.lock();
+        if !state.nodes.contains(&to) {
+            return Err(NijikaError::NetworkFail(format!("unknown peer {}", to)));
+        }
+        state.stats.sent += 1;
+        let drop_rate = state.faults.drop_rate;
+        if state.is_cut(&from, &to) || state.chance(drop_rate) {
+            state.stats.dropped += 1;
+            return Ok(());
+        }
+        let duplicate_rate = state.faults.duplicate_rate;
+        if state.chance(duplicate_rate) {
+            state.stats.duplicated += 1;
+            state.schedule(from, to, message.clone());
+        }
+        state.schedule(from, to, message);
+        Ok(())
+    }
+
+    /// when the next message in flight is due, None once nothing is in flight
+    pub fn next_at(&self) -> Option<u64> {
+        self.lock().in_flight.first_key_value().map(|((at, _), _)| *at)
+    }
+
+    /// move the clock forward without delivering anything, e.g. to a stage deadline
+    pub fn advance_to(&self, at: u64) {
+        let mut state = self.lock();
+        state.now = state.now.max(at);
+    }
+
+    /// move the clock to the next delivery and hand the message out, None once nothing is in flight
+    pub fn pop_next(&self) -> Option<NijikaSimEnvelope> {
+        let mut state = self.lock();
+        let (_, envelope) = state.in_flight.pop_first()?;
+        state.now = state.now.max(envelope.at);
+        Some(envelope)
+    }
+}
+
+/// the transport of one node of a NijikaSimNetwork
+#[derive(Debug, Clone)]
+pub struct NijikaSimTransport {
+    local_id: HashValue,
+    network: NijikaSimNetwork,
+}
+
+impl NijikaSimTransport {
+    pub fn get_network(&self) -> &NijikaSimNetwork {
+        &self.network
+    }
+}
+
+impl NijikaTransportT for NijikaSimTransport {
+    fn get_local_id(&self) -> HashValue {
+        self.local_id
+    }
+
+    fn get_peers(&self) -> Vec<HashValue> {
+        self.network.get_nodes().into_iter().filter(|id| *id != self.local_id).collect()
+    }
+
+    fn send(&self, peer: HashValue, message: NijikaMessage) -> NijikaResult<()> {
+        self.network.enqueue(self.local_id, peer, message)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{hash::hash, network::NijikaMessageDataType};
+    use super::*;
+
+    fn deliveries(seed: u64) -> Vec<(u64, HashValue, HashValue)> {
+        let network = NijikaSimNetwork::new(seed, 5, 50);
+        let transports: Vec<NijikaSimTransport> = (0u64..3).map(|i| network.add_node(hash::new(&i.to_be_bytes()))).collect();
+        for transport in transports.iter() {
+            let message = NijikaMessage::new_invite_message(transport.get_local_id(), NijikaMessageDataType::DataBlockHash, HashValue::default());
+            transport.broadcast(&message, None).unwrap();
+        }
+        let mut order = vec![];
+        while let Some(envelope) = network.pop_next() {
+            assert_eq!(envelope.at, network.now());
+            order.push((envelope.at, envelope.from, envelope.to));
+        }
+        order
+    }
+
+    #[test]
+    fn faults_and_partitions() {
+        let network = NijikaSimNetwork::new(1, 5, 50);
+        let (a, b, c) = (network.add_node(hash::new(b"a")), network.add_node(hash::new(b"b")), network.add_node(hash::new(b"c")));
+        let message = NijikaMessage::new_invite_message(a.get_local_id(), NijikaMessageDataType::DataBlockHash, HashValue::default());
+        network.partition(&[vec![a.get_local_id(), b.get_local_id()]]);
+        a.broadcast(&message, None).unwrap();
+        assert_eq!(network.pop_next().map(|e| e.to), Some(b.get_local_id()));
+        assert!(network.pop_next().is_none());
+        network.heal();
+        network.set_faults(NijikaSimFaults { duplicate_rate: 1.0, ..Default::default() });
+        c.send(a.get_local_id(), message.clone()).unwrap();
+        assert_eq!(network.in_flight(), 2);
+        network.set_faults(NijikaSimFaults { drop_rate: 1.0, ..Default::default() });
+        c.broadcast(&message, None).unwrap();
+        assert_eq!(network.in_flight(), 2);
+        assert_eq!(network.get_stats(), NijikaSimNetworkStats { sent: 5, dropped: 3, duplicated: 1, delayed: 0 });
+    }
+
+    #[test]
+    fn delivery_order_only_depends_on_the_seed() {
+        let order = deliveries(7);
+        assert_eq!(order.len(), 6);
+        assert!(order.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert!(order.iter().all(|(_, from, to)| from != to));
+        assert_eq!(order, deliveries(7));
+    }
+}
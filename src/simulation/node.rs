@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+
+use openssl::{pkey::{PKey, Id}, sign::{Signer, Verifier}};
+use rand::Rng;
+use rand_chacha::{ChaCha8Rng, rand_core::SeedableRng};
+
+use crate::{
+    consensus::{NijikaPBFTStageApi, NijikaPBFTMessageApi},
+    evidence::NijikaEvidencePool,
+    hash::hash,
+    mempool::{NijikaMempool, NijikaMempoolConfig},
+    network::{NijikaTransportT, NijikaSyncApi, NijikaSyncRequests, NijikaDataRequests, NijikaGossipApi, NijikaMessage, NijikaMessageType, NijikaMessageDataType},
+    storage::{NijikaMemoryStorage, NijikaStorageT, NijikaStorageApi},
+    primitives::{
+        HashValue,
+        Transaction,
+        NijikaNodeT,
+        NijikaNodeRole,
+        NijikaLedger,
+        NijikaRound,
+        NijikaPBFTMessage,
+        NijikaPBFTMessageType,
+        NijikaVote,
+        NijikaStakeRegistry,
+        NijikaTransactionT,
+        NijikaBlockT,
+        NijikaControlBlockT,
+        NijikaResult,
+        NijikaError,
+        NIJIKA_DATA_BLOCK_QUEUE,
+        NIJIKA_PBFT_MSG_QUEUE,
+        NIJIKA_STAKE_LOOKBACK
+    },
+    vrf::NijikaVRFClientS,
+};
+
+use super::{NijikaSimControlBlock, NijikaSimDataBlock, NijikaSimTransport, NijikaSimNetwork, NijikaSimConfig};
+
+/// data block pointers a simulated control block carries at most
+const NIJIKA_SIM_MAX_POINTERS: usize = 300;
+/// bytes of transactions a simulated data block carries at most
+const NIJIKA_SIM_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// how a simulated node treats its peers. Only what it sends changes, it follows the protocol otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NijikaSimBehaviour {
+    #[default]
+    Honest,
+    /// announces nothing, neither its own messages nor the ones it relays
+    Silent,
+    /// when it proposes, half of its peers get the block and the other half a twin of it
+    EquivocatingProposer,
+    /// along with each of its votes, sends one in the name of another node and one claiming more sub-users than it won
+    ForgedVotes,
+    /// when it replies, half of its peers get the committed block and the other half a twin of it, with the same commit votes
+    ConflictingReplies,
+}
+
+/// a node kept entirely in memory, with keys derived from the simulation seed.
+/// Every node knows the keys and the genesis stake of all the others from the start
+#[derive(Debug)]
+pub struct NijikaSimNode {
+    name: String,
+    id: HashValue,
+    config: NijikaSimConfig,
+    ledger: NijikaLedger<NijikaSimControlBlock>,
+    round: NijikaRound<NijikaSimControlBlock>,
+    peer_nodes: HashMap<HashValue, (String, String)>,
+    data_block_hash_queue: Vec<HashValue>,
+    data_block_pool: HashMap<HashValue, NijikaSimDataBlock>,
+    pbft_msg_hash_queue: Vec<HashValue>,
+    pbft_message_pool: HashMap<HashValue, NijikaPBFTMessage<NijikaSimControlBlock, HashValue>>,
+    vrf_seed: u64,
+    vrf_secret_key: Vec<u8>,
+    vrf_public_key: Vec<u8>,
+    signing_key: Vec<u8>,
+    /// node -> (VRF public key, signing public key), this node included
+    directory: HashMap<HashValue, (Vec<u8>, Vec<u8>)>,
+    stake: NijikaStakeRegistry,
+    mempool: NijikaMempool<Transaction>,
+    evidence: NijikaEvidencePool<NijikaSimControlBlock, HashValue>,
+    sync_requests: NijikaSyncRequests<HashValue>,
+    data_requests: NijikaDataRequests<HashValue>,
+    /// what the node persists, nothing unless enabled with set_storage
+    storage: Option<NijikaMemoryStorage>,
+    transport: NijikaSimTransport,
+    behaviour: NijikaSimBehaviour,
+}
+
+/// the id of the simulated node at the given index
+pub fn sim_node_id(index: usize) -> HashValue {
+    hash::new(format!("nijika-sim-node-{}", index).as_bytes())
+}
+
+impl NijikaSimNode {
+    /// build config.nodes nodes on the network, all of them starting from the same genesis block and stake
+    pub fn new_nodes(config: &NijikaSimConfig, network: &NijikaSimNetwork) -> NijikaResult<Vec<NijikaSimNode>> {
+        let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+        let mut keys = vec![];
+        for index in 0..config.nodes {
+            let mut vrf_client = NijikaVRFClientS::new_raw();
+            let (vrf_secret_key, vrf_public_key) = vrf_client.gen_keys(rng.gen())
+                .map_err(|e| NijikaError::VRFError(format!("{:?}", e)))?;
+            let raw: [u8; 32] = rng.gen();
+            let signing_key = PKey::private_key_from_raw_bytes(&raw, Id::ED25519)
+                .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+            let signing_public_key = signing_key.raw_public_key()
+                .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+            keys.push((sim_node_id(index), vrf_secret_key, vrf_public_key, raw.to_vec(), signing_public_key));
+        }
+        let directory: HashMap<HashValue, (Vec<u8>, Vec<u8>)> = keys.iter()
+            .map(|(id, _, vrf_public_key, _, signing_public_key)| (*id, (vrf_public_key.clone(), signing_public_key.clone())))
+            .collect();
+        let mut genesis_stake = vec![];
+        for (id, ..) in keys.iter() {
+            genesis_stake.push((hash::serialized(id)?, config.weight));
+        }
+        let mut nodes = vec![];
+        for (index, (id, vrf_secret_key, vrf_public_key, signing_key, _)) in keys.into_iter().enumerate() {
+            nodes.push(NijikaSimNode {
+                name: format!("nijika-sim-node-{}", index),
+                id,
+                config: config.clone(),
+                ledger: NijikaLedger::new(NijikaSimControlBlock::genesis(config.seed))?,
+                round: NijikaRound::default(),
+                peer_nodes: HashMap::new(),
+                data_block_hash_queue: vec![],
+                data_block_pool: HashMap::new(),
+                pbft_msg_hash_queue: vec![],
+                pbft_message_pool: HashMap::new(),
+                vrf_seed: config.seed,
+                vrf_secret_key,
+                vrf_public_key,
+                signing_key,
+                directory: directory.clone(),
+                stake: NijikaStakeRegistry::new(NIJIKA_STAKE_LOOKBACK, genesis_stake.clone())?,
+                mempool: NijikaMempool::new(NijikaMempoolConfig::default()),
+                evidence: NijikaEvidencePool::new(),
+                sync_requests: NijikaSyncRequests::new(),
+                data_requests: NijikaDataRequests::new(),
+                storage: None,
+                transport: network.add_node(id),
+                behaviour: NijikaSimBehaviour::Honest,
+            });
+        }
+        Ok(nodes)
+    }
+
+    pub fn get_mempool(&self) -> &NijikaMempool<Transaction> {
+        &self.mempool
+    }
+
+    pub fn get_mempool_mut(&mut self) -> &mut NijikaMempool<Transaction> {
+        &mut self.mempool
+    }
+
+    pub fn get_storage(&self) -> Option<&NijikaMemoryStorage> {
+        self.storage.as_ref()
+    }
+
+    /// persist the blocks and pbft messages from now on into the given storage, e.g. one to restore from
+    pub fn set_storage(&mut self, storage: NijikaMemoryStorage) {
+        self.storage = Some(storage);
+    }
+
+    pub fn get_behaviour(&self) -> NijikaSimBehaviour {
+        self.behaviour
+    }
+
+    pub fn set_behaviour(&mut self, behaviour: NijikaSimBehaviour) {
+        self.behaviour = behaviour;
+    }
+
+    /// what an honest node does with a stored hash: invite the peers to fetch it
+    fn announce(&self, data_type: NijikaMessageDataType, hash: HashValue, source: Option<HashValue>) -> NijikaResult<()> {
+        let message = NijikaMessage::new_invite_message(self.id, data_type, hash);
+        self.transport.broadcast(&message, source)
+    }
+
+    /// push a message that is not in our pool, so that nobody could fetch it after an Invite
+    fn push_pbft_message(&self, peer: HashValue, message: &NijikaPBFTMessage<NijikaSimControlBlock, HashValue>) -> NijikaResult<()> {
+        self.transport.send(peer, NijikaMessage::new_message(self.id, NijikaMessageType::Data, NijikaMessageDataType::PBFTMsg, message.encode()?))
+    }
+
+    fn sign_message(&self, message: &mut NijikaPBFTMessage<NijikaSimControlBlock, HashValue>) -> NijikaResult<()> {
+        let signature = self.sign(&message.signing_bytes()?)?;
+        message.set_signature(signature);
+        Ok(())
+    }
+
+    /// send the message to every other peer and one for a valid twin block, signed all the same and with the same proof, to the rest
+    fn equivocate(&self, message: &NijikaPBFTMessage<NijikaSimControlBlock, HashValue>) -> NijikaResult<()> {
+        let mut twin = match message.get_control_block() {
+            Some(block) => block.clone(),
+            None => return Ok(())
+        };
+        twin.set_timestamp(twin.get_timestamp() + 1);
+        twin.set_signature(self.sign(&twin.header_bytes()?)?);
+        let mut forged = NijikaPBFTMessage::new_control_block_message(
+            self.id, message.get_round_num(), message.get_view(), message.get_type(), twin.header_hash()?, twin, message.get_credential().clone()
+        );
+        forged.set_proof(message.get_proof().to_vec())?;
+        self.sign_message(&mut forged)?;
+        for (i, peer) in self.transport.get_peers().into_iter().enumerate() {
+            self.push_pbft_message(peer, if i % 2 == 0 { message } else { &forged })?;
+        }
+        Ok(())
+    }
+
+    /// send our vote as if another node had cast it, and our vote again with more weight than we won
+    fn forge_votes(&self, message: &NijikaPBFTMessage<NijikaSimControlBlock, HashValue>) -> NijikaResult<()> {
+        let peers = self.transport.get_peers();
+        let (round_num, view, message_type, hash) = (message.get_round_num(), message.get_view(), message.get_type(), message.get_control_block_hash());
+        let mut forged = vec![];
+        if let Some(victim) = peers.first() {
+            let mut impersonated = NijikaPBFTMessage::new_vote_message(
+                *victim, round_num, view, message_type, hash, NijikaVote::new_true(*victim), message.get_credential().clone()
+            );
+            self.sign_message(&mut impersonated)?;
+            forged.push(impersonated);
+        }
+        if let Some(vote) = message.get_vote() {
+            let mut credential = message.get_credential().clone();
+            credential.sub_users = credential.sub_users * 10 + 1;
+            let mut inflated = NijikaPBFTMessage::new_vote_message(self.id, round_num, view, message_type, hash, vote, credential);
+            self.sign_message(&mut inflated)?;
+            forged.push(inflated);
+        }
+        for peer in peers {
+            for message in forged.iter() {
+                self.push_pbft_message(peer, message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> NijikaPBFTStageApi<'a, NijikaSimControlBlock, NijikaSimDataBlock, HashValue> for NijikaSimNode {}
+impl<'a> NijikaPBFTMessageApi<'a, NijikaSimControlBlock, NijikaSimDataBlock, HashValue> for NijikaSimNode {}
+impl<'a> NijikaSyncApi<'a, NijikaSimControlBlock, NijikaSimDataBlock, HashValue> for NijikaSimNode {
+    fn get_sync_requests_mut(&mut self) -> &mut NijikaSyncRequests<HashValue> {
+        &mut self.sync_requests
+    }
+}
+impl<'a> NijikaGossipApi<'a, NijikaSimControlBlock, NijikaSimDataBlock, HashValue> for NijikaSimNode {}
+impl<'a> NijikaStorageApi<'a, NijikaSimControlBlock, NijikaSimDataBlock, HashValue> for NijikaSimNode {}
+
+impl<'a> NijikaNodeT<'a, NijikaSimControlBlock, NijikaSimDataBlock, HashValue> for NijikaSimNode {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_ip(&self) -> &str {
+        "in-memory"
+    }
+
+    fn get_id(&self) -> HashValue {
+        self.id
+    }
+
+    fn get_role(&self) -> NijikaNodeRole {
+        self.round.get_role()
+    }
+
+    fn get_weight(&self) -> u64 {
+        self.get_weight_of(self.id)
+    }
+
+    fn get_weight_of(&self, id: HashValue) -> u64 {
+        match hash::serialized(&id) {
+            Ok(account) => self.stake.get_weight_at(self.get_round_num(), &account),
+            Err(_) => 0
+        }
+    }
+
+    fn get_total_weight(&self) -> u64 {
+        self.stake.get_total_at(self.get_round_num())
+    }
+
+    fn get_vrf_params(&self, role: NijikaNodeRole) -> (u64, u64) {
+        let expected = match role {
+            NijikaNodeRole::PROPOSER => self.config.proposers,
+            NijikaNodeRole::VALIDATOR => self.config.validators,
+            NijikaNodeRole::PACKER => self.config.packers,
+            NijikaNodeRole::NORMAL => 0,
+        };
+        (expected, self.get_total_weight())
+    }
+
+    fn get_peer_info_mut(&mut self) -> &mut HashMap<HashValue, (String, String)> {
+        &mut self.peer_nodes
+    }
+
+    fn get_transport(&self) -> &dyn NijikaTransportT {
+        &self.transport
+    }
+
+    fn get_storage_mut(&mut self) -> Option<&mut dyn NijikaStorageT> {
+        self.storage.as_mut().map(|storage| storage as &mut dyn NijikaStorageT)
+    }
+
+    /// where the scripted misbehaviours of a NijikaSimBehaviour come in
+    fn broadcast_hash_message(&self, data_type: NijikaMessageDataType, hash: HashValue, source: Option<HashValue>) -> NijikaResult<()> {
+        // only what we send first, not our own messages coming back from a peer
+        let own = self.pbft_message_pool.get(&hash).filter(|message| source.is_none() && message.get_source() == self.id);
+        match (self.behaviour, own) {
+            (NijikaSimBehaviour::Silent, _) => Ok(()),
+            (NijikaSimBehaviour::EquivocatingProposer, Some(message))
+                if matches!(message.get_type(), NijikaPBFTMessageType::PrePrepare | NijikaPBFTMessageType::NewView) => self.equivocate(message),
+            (NijikaSimBehaviour::ConflictingReplies, Some(message))
+                if matches!(message.get_type(), NijikaPBFTMessageType::Reply) => self.equivocate(message),
+            (NijikaSimBehaviour::ForgedVotes, Some(message))
+                if matches!(message.get_type(), NijikaPBFTMessageType::Prepare | NijikaPBFTMessageType::Commit) => {
+                self.announce(data_type, hash, source)?;
+                self.forge_votes(message)
+            },
+            _ => self.announce(data_type, hash, source)
+        }
+    }
+
+    fn get_stake_registry(&self) -> Option<&NijikaStakeRegistry> {
+        Some(&self.stake)
+    }
+
+    fn get_stake_registry_mut(&mut self) -> Option<&mut NijikaStakeRegistry> {
+        Some(&mut self.stake)
+    }
+
+    fn get_account_owner(&self, account: &HashValue) -> Option<HashValue> {
+        self.directory.keys().find(|id| hash::serialized(*id).ok().as_ref() == Some(account)).copied()
+    }
+
+    fn get_evidence_pool(&self) -> Option<&NijikaEvidencePool<NijikaSimControlBlock, HashValue>> {
+        Some(&self.evidence)
+    }
+
+    fn get_evidence_pool_mut(&mut self) -> Option<&mut NijikaEvidencePool<NijikaSimControlBlock, HashValue>> {
+        Some(&mut self.evidence)
+    }
+
+    fn get_hash_queue(&self, identifier: Option<&str>) -> NijikaResult<&Vec<HashValue>> {
+        match identifier {
+            Some(NIJIKA_DATA_BLOCK_QUEUE) => Ok(&self.data_block_hash_queue),
+            Some(NIJIKA_PBFT_MSG_QUEUE) => Ok(&self.pbft_msg_hash_queue),
+            _ => Err(NijikaError::ParseError(String::from("unknown identifier")))
+        }
+    }
+
+    fn get_hash_queue_mut(&mut self, identifier: Option<&str>) -> NijikaResult<&mut Vec<HashValue>> {
+        match identifier {
+            Some(NIJIKA_DATA_BLOCK_QUEUE) => Ok(&mut self.data_block_hash_queue),
+            Some(NIJIKA_PBFT_MSG_QUEUE) => Ok(&mut self.pbft_msg_hash_queue),
+            _ => Err(NijikaError::ParseError(String::from("unknown identifier")))
+        }
+    }
+
+    fn get_vrf_seed(&self) -> u64 {
+        self.vrf_seed
+    }
+
+    fn set_vrf_seed(&mut self, seed: u64) {
+        self.vrf_seed = seed;
+    }
+
+    fn get_secret_key(&self) -> &[u8] {
+        &self.vrf_secret_key
+    }
+
+    fn get_public_key(&self) -> &[u8] {
+        &self.vrf_public_key
+    }
+
+    fn get_public_key_of(&self, id: HashValue) -> Option<&[u8]> {
+        self.directory.get(&id).map(|(vrf_public_key, _)| vrf_public_key.as_slice())
+    }
+
+    fn set_keys(&mut self, private_key: Vec<u8>, public_key: Vec<u8>) {
+        self.vrf_secret_key = private_key;
+        self.vrf_public_key = public_key;
+    }
+
+    fn update_proof(&mut self, _proof: Vec<u8>, _hash: Vec<u8>) -> NijikaResult<()> {
+        Ok(())
+    }
+
+    fn sign(&self, content: &[u8]) -> NijikaResult<Vec<u8>> {
+        let key = PKey::private_key_from_raw_bytes(&self.signing_key, Id::ED25519)
+            .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+        let mut signer = Signer::new_without_digest(&key)
+            .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+        signer.sign_oneshot_to_vec(content).map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))
+    }
+
+    fn verify_signature(&self, signer: HashValue, content: &[u8], signature: &[u8]) -> NijikaResult<bool> {
+        match self.directory.get(&signer) {
+            Some((_, raw)) => {
+                let key = PKey::public_key_from_raw_bytes(raw, Id::ED25519)
+                    .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+                let mut verifier = Verifier::new_without_digest(&key)
+                    .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+                Ok(verifier.verify_oneshot(signature, content).unwrap_or(false))
+            },
+            None => Ok(false)
+        }
+    }
+
+    fn set_round(&mut self, round: NijikaRound<NijikaSimControlBlock>) -> NijikaResult<()> {
+        self.round = round;
+        Ok(())
+    }
+
+    fn get_round(&self) -> &NijikaRound<NijikaSimControlBlock> {
+        &self.round
+    }
+
+    fn get_round_mut(&mut self) -> &mut NijikaRound<NijikaSimControlBlock> {
+        &mut self.round
+    }
+
+    fn get_round_num(&self) -> u64 {
+        self.round.get_round_num()
+    }
+
+    fn set_round_control_block(&mut self, block: NijikaSimControlBlock) -> NijikaResult<()> {
+        self.round.set_control_block(block);
+        Ok(())
+    }
+
+    fn get_round_control_block(&mut self) -> &NijikaSimControlBlock {
+        self.round.get_control_block().expect("empty block in the round")
+    }
+
+    fn new_control_block(&self, seed: u64, seed_proof: Vec<u8>) -> NijikaSimControlBlock {
+        let mut block = NijikaSimControlBlock::new(self.id, self.get_round_num(), self.ledger.get_tip(), seed, seed_proof);
+        block.set_timestamp(self.transport.get_network().now());
+        block
+    }
+
+    fn load_control_block(&mut self, block: &mut NijikaSimControlBlock) -> NijikaResult<()> {
+        let count = self.data_block_hash_queue.len().min(NIJIKA_SIM_MAX_POINTERS);
+        for data_block in self.data_block_hash_queue.drain(..count) {
+            block.push(data_block);
+        }
+        let signature = self.sign(&block.header_bytes()?)?;
+        block.set_signature(signature);
+        Ok(())
+    }
+
+    fn verify_control_block_signature(&self, block: &NijikaSimControlBlock) -> NijikaResult<bool> {
+        self.verify_signature(*block.get_proposer(), &block.header_bytes()?, block.get_signature())
+    }
+
+    fn get_ledger(&self) -> &NijikaLedger<NijikaSimControlBlock> {
+        &self.ledger
+    }
+
+    fn get_ledger_mut(&mut self) -> &mut NijikaLedger<NijikaSimControlBlock> {
+        &mut self.ledger
+    }
+
+    fn new_data_block(&self) -> NijikaSimDataBlock {
+        NijikaSimDataBlock::new(self.id, self.get_round_num())
+    }
+
+    fn load_data_block(&mut self, block: &mut NijikaSimDataBlock) -> NijikaResult<()> {
+        let transactions = self.mempool.select(NIJIKA_SIM_BLOCK_SIZE);
+        let ids = transactions.iter().map(|tx| tx.get_id()).collect();
+        block.set_transactions(transactions);
+        self.mempool.mark_packed(block.hash()?, ids);
+        Ok(())
+    }
+
+    fn release_packed_transactions(&mut self) -> NijikaResult<()> {
+        self.mempool.release_all();
+        Ok(())
+    }
+
+    fn evict_committed_transactions(&mut self, data_blocks: &[HashValue]) -> NijikaResult<()> {
+        for hash in data_blocks {
+            match self.data_block_pool.get(hash) {
+                Some(block) => {
+                    let ids = block.get_transactions().iter().map(|tx| tx.get_id()).collect::<Vec<HashValue>>();
+                    self.mempool.evict_committed(hash, &ids);
+                },
+                None => self.mempool.await_committed(*hash)
+            }
+        }
+        Ok(())
+    }
+
+    fn append_data_block_hash_queue(&mut self, hash: HashValue) -> NijikaResult<()> {
+        self.data_block_hash_queue.push(hash);
+        Ok(())
+    }
+
+    fn insert_data_block_pool(&mut self, hash: HashValue, block: NijikaSimDataBlock) -> NijikaResult<()> {
+        let ids = block.get_transactions().iter().map(|tx| tx.get_id()).collect::<Vec<HashValue>>();
+        if self.data_block_pool.insert(hash, block).is_some() {
+            return Err(NijikaError::HashCollision(hash));
+        }
+        // a data block committed before it arrived
+        if self.mempool.is_awaited(&hash) {
+            self.mempool.evict_committed(&hash, &ids);
+        }
+        Ok(())
+    }
+
+    fn get_data_block(&self, hash: &HashValue) -> Option<&NijikaSimDataBlock> {
+        self.data_block_pool.get(hash)
+    }
+
+    fn get_data_requests_mut(&mut self) -> &mut NijikaDataRequests<HashValue> {
+        &mut self.data_requests
+    }
+
+    fn append_pbft_message_queue(&mut self, hash: HashValue) -> NijikaResult<()> {
+        self.pbft_msg_hash_queue.push(hash);
+        Ok(())
+    }
+
+    fn insert_pbft_message_pool(&mut self, hash: HashValue, message: NijikaPBFTMessage<NijikaSimControlBlock, HashValue>) -> NijikaResult<()> {
+        match self.pbft_message_pool.insert(hash, message) {
+            Some(_) => Err(NijikaError::HashCollision(hash)),
+            None => Ok(())
+        }
+    }
+
+    fn get_pbft_message(&self, hash: &HashValue) -> Option<&NijikaPBFTMessage<NijikaSimControlBlock, HashValue>> {
+        self.pbft_message_pool.get(hash)
+    }
+}
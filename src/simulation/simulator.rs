@@ -0,0 +1,961 @@
+use std::{collections::HashMap, fmt::Debug, marker::PhantomData};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    network::NijikaGossipApi,
+    primitives::{HashValue, NijikaDigest, NijikaControlBlockT, NijikaDataBlockT, NijikaNodeRole, NijikaPBFTStage, NijikaIdT, NijikaResult, NijikaError, NIJIKA_DEFAULT_QUORUM},
+};
+
+use super::{NijikaSimNetwork, NijikaSimNode};
+
+/// idle timeouts in a row that leave every node in the same round and view before the simulation gives up
+pub const NIJIKA_SIM_MAX_IDLE: u64 = 8;
+
+/// the parameters of a simulated network of NijikaSimNode
+#[derive(Debug, Clone)]
+pub struct NijikaSimConfig {
+    /// drives the keys of the nodes, the seed of the genesis block and the latencies of the network
+    pub seed: u64,
+    pub nodes: usize,
+    /// the genesis stake of every node
+    pub weight: u64,
+    /// expected sub-users of each role in a round view
+    pub proposers: u64,
+    pub validators: u64,
+    pub packers: u64,
+    pub thresh: u64,
+    /// the latency of every message is drawn from min_latency..=max_latency virtual milliseconds
+    pub min_latency: u64,
+    pub max_latency: u64,
+    /// virtual milliseconds a committee member waits in a stage before asking for a view change, even while messages
+    /// are still in flight. None to time out only once the network is idle
+    pub stage_timeout: Option<u64>,
+}
+
+impl Default for NijikaSimConfig {
+    fn default() -> Self {
+        NijikaSimConfig {
+            seed: 0,
+            nodes: 4,
+            weight: 100,
+            proposers: 1,
+            validators: 200,
+            packers: 100,
+            thresh: NIJIKA_DEFAULT_QUORUM,
+            min_latency: 10,
+            max_latency: 100,
+            stage_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NijikaSimStats {
+    pub delivered: u64,
+    /// messages a node failed to handle
+    pub errors: u64,
+    /// how many times the network went idle before every node had ended its round
+    pub timeouts: u64,
+    /// stage deadlines that passed while messages were still in flight
+    pub early_timeouts: u64,
+    /// virtual milliseconds
+    pub elapsed: u64,
+}
+
+/// runs nodes in one thread over a NijikaSimNetwork, delivering one message at a time.
+/// A node starts its next round as soon as its current one ends, like NijikaRuntime does.
+/// When nothing is in flight but a round is still open, the stage deadlines are taken to have passed,
+/// and the committee members that are still waiting ask for a view change. With a stage timeout,
+/// a deadline may also pass before the next message is due.
+/// Every block a node commits is recorded, so that a block left behind by a fork switch still counts for safety
+pub struct NijikaSimulator<'a, CB, DB, ID, N>
+where
+    CB: NijikaControlBlockT + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    DB: NijikaDataBlockT<Hasher = CB::Hasher> + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned + 'a,
+    N: NijikaGossipApi<'a, CB, DB, ID>,
+{
+    network: NijikaSimNetwork,
+    nodes: Vec<N>,
+    /// transport id -> index in nodes
+    index: HashMap<HashValue, usize>,
+    thresh: u64,
+    stage_timeout: Option<u64>,
+    /// node index -> where it stands and since when, in virtual milliseconds
+    since: Vec<((u64, u64, NijikaPBFTStage), u64)>,
+    /// node index -> the (round, block) of every commit, in order
+    commits: Vec<Vec<(u64, NijikaDigest<CB>)>>,
+    stats: NijikaSimStats,
+    _marker: PhantomData<&'a (CB, DB, ID)>,
+}
+
+impl<'a, CB, DB, ID, N> NijikaSimulator<'a, CB, DB, ID, N>
+where
+    CB: NijikaControlBlockT + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    DB: NijikaDataBlockT<Hasher = CB::Hasher> + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned + 'a,
+    N: NijikaGossipApi<'a, CB, DB, ID>,
+{
+    /// the nodes must send through transports of the given network.
+    /// thresh is passed to enter_round for every round
+    pub fn new(network: NijikaSimNetwork, nodes: Vec<N>, thresh: u64) -> Self {
+        let index = nodes.iter().enumerate().map(|(i, node)| (node.get_transport().get_local_id(), i)).collect();
+        let since = nodes.iter().map(|node| (Self::position(node), network.now())).collect();
+        let commits = vec![vec![]; nodes.len()];
+        NijikaSimulator {
+            network, nodes, index, thresh, stage_timeout: None, since, commits, stats: NijikaSimStats::default(), _marker: PhantomData
+        }
+    }
+
+    /// let stage deadlines pass while messages are still in flight, see NijikaSimConfig::stage_timeout
+    pub fn set_stage_timeout(&mut self, stage_timeout: Option<u64>) {
+        self.stage_timeout = stage_timeout;
+    }
+
+    pub fn get_network(&self) -> &NijikaSimNetwork {
+        &self.network
+    }
+
+    pub fn get_nodes(&self) -> &[N] {
+        &self.nodes
+    }
+
+    pub fn get_node_mut(&mut self, index: usize) -> Option<&mut N> {
+        self.nodes.get_mut(index)
+    }
+
+    pub fn get_stats(&self) -> &NijikaSimStats {
+        &self.stats
+    }
+
+    /// start the next round of the node if it has ended the current one, up to last_round
+    fn advance(&mut self, index: usize, last_round: u64) -> NijikaResult<()> {
+        self.record(index)?;
+        while self.nodes[index].get_round().is_end() && self.nodes[index].get_round_num() < last_round {
+            let node = &mut self.nodes[index];
+            node.enter_round(node.get_round_num() + 1, self.thresh)?;
+            self.record(index)?;
+        }
+        let position = Self::position(&self.nodes[index]);
+        if self.since[index].0 != position {
+            self.since[index] = (position, self.network.now());
+        }
+        Ok(())
+    }
+
+    fn position(node: &N) -> (u64, u64, NijikaPBFTStage) {
+        (node.get_round_num(), node.get_round().get_view(), node.get_round().get_stage())
+    }
+
+    /// note the block of an ended round and the blocks the ledger gained, e.g. through sync, as commits of the node
+    fn record(&mut self, index: usize) -> NijikaResult<()> {
+        let node = &self.nodes[index];
+        let mut found = vec![];
+        if node.get_round().is_end() {
+            if let Some(block) = node.get_round().get_control_block() {
+                found.push((block.get_round(), block.header_hash()?));
+            }
+        }
+        let ledger = node.get_ledger();
+        let mut current = ledger.get_tip();
+        let mut chain = vec![];
+        while current != ledger.get_genesis() {
+            if self.commits[index].iter().any(|(_, hash)| *hash == current) {
+                break;
+            }
+            let block = match ledger.get(&current) {
+                Some(block) => block,
+                None => break
+            };
+            chain.push((block.get_round(), current));
+            current = *block.get_pre_hash();
+        }
+        found.extend(chain.into_iter().rev());
+        for commit in found {
+            if !self.commits[index].contains(&commit) {
+                self.commits[index].push(commit);
+            }
+        }
+        Ok(())
+    }
+
+    /// the committee member whose stage deadline passes first, and when, if it comes before the next delivery
+    fn next_timeout(&self) -> Option<(usize, u64)> {
+        let stage_timeout = self.stage_timeout?;
+        let next_at = self.network.next_at()?;
+        self.nodes.iter().enumerate()
+            .filter(|(_, node)| {
+                let committee = matches!(node.get_role(), NijikaNodeRole::VALIDATOR | NijikaNodeRole::PROPOSER);
+                committee && !node.get_round().is_end() && node.get_round().get_stage() != NijikaPBFTStage::Reply
+            })
+            .map(|(i, _)| (i, self.since[i].1.saturating_add(stage_timeout)))
+            .min_by_key(|(_, at)| *at)
+            .filter(|(_, at)| *at < next_at)
+    }
+
+    /// where every node stands, to tell whether a timeout moved anything
+    fn progress(&self) -> Vec<(u64, u64, bool)> {
+        self.nodes.iter().map(|node| (node.get_round_num(), node.get_round().get_view(), node.get_round().is_end())).collect()
+    }
+
+    fn is_done(&self, last_round: u64) -> bool {
+        self.nodes.iter().all(|node| node.get_round_num() >= last_round && node.get_round().is_end())
+    }
+
+    /// run every node from first_round until all of them have ended last_round.
+    /// Fails if that takes more than max_steps deliveries and timeouts, or if the nodes stop moving on
+    pub fn run(&mut self, first_round: u64, last_round: u64, max_steps: u64) -> NijikaResult<NijikaSimStats> {
+        for i in 0..self.nodes.len() {
+            self.nodes[i].enter_round(first_round, self.thresh)?;
+            self.advance(i, last_round)?;
+        }
+        self.run_until(last_round, max_steps)
+    }
+
+    /// go on from wherever the nodes stand, e.g. after healing the partition that stalled an earlier run
+    pub fn run_until(&mut self, last_round: u64, max_steps: u64) -> NijikaResult<NijikaSimStats> {
+        for i in 0..self.nodes.len() {
+            self.advance(i, last_round)?;
+        }
+        let mut steps = 0;
+        let mut idle = (0, self.progress());
+        while !self.is_done(last_round) {
+            if steps >= max_steps {
+                return Err(NijikaError::NetworkFail(format!("simulation did not end round {} in {} steps", last_round, max_steps)));
+            }
+            steps += 1;
+            if let Some((i, at)) = self.next_timeout() {
+                self.network.advance_to(at);
+                self.stats.early_timeouts += 1;
+                if let Err(e) = self.nodes[i].view_change() {
+                    println!("[Sim] node {} timeout error: {:?}", i, e);
+                    self.stats.errors += 1;
+                }
+                // the deadline is set again, whether the view changed or not
+                self.since[i].1 = at;
+                self.advance(i, last_round)?;
+                self.stats.elapsed = self.network.now();
+                continue;
+            }
+            match self.network.pop_next() {
+                Some(envelope) => {
+                    let i = match self.index.get(&envelope.to) {
+                        Some(i) => *i,
+                        None => continue
+                    };
+                    self.stats.delivered += 1;
+                    if let Err(e) = self.nodes[i].handle_message(envelope.message) {
+                        println!("[Sim] node {} message error: {:?}", i, e);
+                        self.stats.errors += 1;
+                    }
+                    self.advance(i, last_round)?;
+                },
+                None => {
+                    self.stats.timeouts += 1;
+                    let mut waiting = false;
+                    for (i, node) in self.nodes.iter_mut().enumerate() {
+                        let committee = matches!(node.get_role(), NijikaNodeRole::VALIDATOR | NijikaNodeRole::PROPOSER);
+                        if committee && !node.get_round().is_end() {
+                            waiting = true;
+                            if let Err(e) = node.view_change() {
+                                println!("[Sim] node {} timeout error: {:?}", i, e);
+                                self.stats.errors += 1;
+                            }
+                        }
+                    }
+                    for i in 0..self.nodes.len() {
+                        self.advance(i, last_round)?;
+                    }
+                    if !waiting {
+                        return Err(NijikaError::NetworkFail(String::from("simulation stalled: no committee member is waiting")));
+                    }
+                    let progress = self.progress();
+                    idle = if progress == idle.1 { (idle.0 + 1, progress) } else { (0, progress) };
+                    if idle.0 >= NIJIKA_SIM_MAX_IDLE {
+                        return Err(NijikaError::NetworkFail(format!("simulation stalled: {:?}", idle.1)));
+                    }
+                },
+            }
+            self.stats.elapsed = self.network.now();
+        }
+        Ok(self.stats.clone())
+    }
+
+    /// the round and hash of every control block the node committed, in order, those a fork switch left behind included
+    pub fn get_committed(&self, index: usize) -> NijikaResult<Vec<(u64, NijikaDigest<CB>)>> {
+        self.commits.get(index).cloned().ok_or_else(|| NijikaError::NetworkFail(format!("no node {}", index)))
+    }
+
+    /// safety: no two nodes committed different control blocks for the same round.
+    /// The byzantine nodes are left out, what they committed proves nothing
+    pub fn check_safety(&self, byzantine: &[usize]) -> NijikaResult<()> {
+        let mut seen: HashMap<u64, (usize, NijikaDigest<CB>)> = HashMap::new();
+        for i in (0..self.nodes.len()).filter(|i| !byzantine.contains(i)) {
+            for (round_num, hash) in self.get_committed(i)? {
+                match seen.get(&round_num) {
+                    Some((j, other)) if *other != hash => {
+                        return Err(NijikaError::InvalidControlBlock(format!(
+                            "round {}: node {} committed {} but node {} committed {}", round_num, j, other, i, hash
+                        )));
+                    },
+                    Some(_) => (),
+                    None => { seen.insert(round_num, (i, hash)); }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// liveness: every node but the byzantine ones has committed a control block of last_round or later
+    pub fn check_liveness(&self, last_round: u64, byzantine: &[usize]) -> NijikaResult<()> {
+        for (i, node) in self.nodes.iter().enumerate().filter(|(i, _)| !byzantine.contains(i)) {
+            let round_num = node.get_ledger().get_tip_block().get_round();
+            if round_num < last_round {
+                return Err(NijikaError::NetworkFail(format!("node {} has only committed up to round {} of {}", i, round_num, last_round)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> NijikaSimulator<'a, super::NijikaSimControlBlock, super::NijikaSimDataBlock, HashValue, NijikaSimNode> {
+    /// a network of NijikaSimNode built from the config
+    pub fn from_config(config: &NijikaSimConfig) -> NijikaResult<Self> {
+        let network = NijikaSimNetwork::new(config.seed, config.min_latency, config.max_latency);
+        let nodes = NijikaSimNode::new_nodes(config, &network)?;
+        let mut simulator = Self::new(network, nodes, config.thresh);
+        simulator.set_stage_timeout(config.stage_timeout);
+        Ok(simulator)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        consensus::{NijikaPBFTMessageApi, NijikaPBFTStageApi, NIJIKA_PENDING_ROUNDS},
+        primitives::{NijikaNodeT, NijikaBlockT, NijikaControlBlockT, NijikaPBFTMessage, NijikaPBFTMessageType, NijikaVRFCredential, NijikaStakeChange, NijikaSignedStakeChange, NijikaRound, NijikaRejectReason, NIJIKA_PBFT_MSG_QUEUE, NIJIKA_DATA_BLOCK_QUEUE},
+        hash::hash,
+        network::{NijikaSyncApi, NijikaBlocks, NijikaMessage, NijikaMessageType, NijikaMessageDataType},
+        simulation::{NijikaSimBehaviour, NijikaSimFaults, NijikaSimEnvelope, NijikaSimControlBlock, NijikaSimDataBlock, sim_node_id},
+        storage::{NijikaMemoryStorage, NijikaStorageApi},
+        vrf::NijikaVRFClientS
+    };
+    use crate::primitives::Transaction;
+    use super::*;
+
+    fn run(seed: u64) -> (NijikaSimStats, Vec<(HashValue, u64)>) {
+        let config = NijikaSimConfig { seed, ..Default::default() };
+        let mut simulator = NijikaSimulator::from_config(&config).unwrap();
+        let stats = simulator.run(1, 3, 100_000).unwrap();
+        let tips = simulator.get_nodes().iter()
+            .map(|node| (node.get_ledger().get_tip(), node.get_ledger().get_height()))
+            .collect();
+        (stats, tips)
+    }
+
+    #[test]
+    fn nodes_agree_on_every_round() {
+        let (stats, tips) = run(1);
+        assert!(stats.delivered > 0);
+        assert!(tips.iter().all(|tip| *tip == tips[0]), "{:?}", tips);
+        assert_eq!(tips[0].1, 3);
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        assert_eq!(run(2), run(2));
+    }
+
+    #[test]
+    fn committed_blocks_are_chained() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        simulator.run(1, 2, 100_000).unwrap();
+        let ledger = simulator.get_nodes()[0].get_ledger();
+        for height in 1..=ledger.get_height() {
+            let block = ledger.get_block_at(height).unwrap();
+            let parent = ledger.get_block_at(height - 1).unwrap();
+            assert_eq!(*block.get_pre_hash(), parent.header_hash().unwrap());
+        }
+    }
+
+    #[test]
+    fn safe_and_live_on_a_lossy_network() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        simulator.get_network().set_faults(NijikaSimFaults { drop_rate: 0.02, duplicate_rate: 0.05, delay_rate: 0.1, max_delay: 500 });
+        simulator.run(1, 3, 200_000).unwrap();
+        simulator.check_safety(&[]).unwrap();
+        simulator.check_liveness(3, &[]).unwrap();
+        let stats = simulator.get_network().get_stats();
+        assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.delayed > 0, "{:?}", stats);
+    }
+
+    #[test]
+    fn partition_stalls_until_healed() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        simulator.get_network().partition(&[vec![sim_node_id(0), sim_node_id(1)]]);
+        assert!(simulator.run(1, 2, 100_000).is_err());
+        simulator.check_safety(&[]).unwrap();
+        simulator.get_network().heal();
+        simulator.run_until(2, 100_000).unwrap();
+        simulator.check_safety(&[]).unwrap();
+        simulator.check_liveness(2, &[]).unwrap();
+    }
+
+    #[test]
+    fn equivocating_proposer_is_caught() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        simulator.get_node_mut(0).unwrap().set_behaviour(NijikaSimBehaviour::EquivocatingProposer);
+        simulator.run(1, 4, 200_000).unwrap();
+        simulator.check_safety(&[0]).unwrap();
+        simulator.check_liveness(4, &[0]).unwrap();
+        let caught = simulator.get_nodes()[1..].iter()
+            .all(|node| node.get_evidence_pool().unwrap().iter().any(|evidence| evidence.get_offender() == sim_node_id(0)));
+        assert!(caught);
+    }
+
+    #[test]
+    fn silent_and_forging_nodes_keep_it_safe() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig { nodes: 7, ..Default::default() }).unwrap();
+        simulator.get_node_mut(1).unwrap().set_behaviour(NijikaSimBehaviour::Silent);
+        simulator.get_node_mut(2).unwrap().set_behaviour(NijikaSimBehaviour::ForgedVotes);
+        let stats = simulator.run(1, 3, 200_000).unwrap();
+        simulator.check_safety(&[1, 2]).unwrap();
+        simulator.check_liveness(3, &[1, 2]).unwrap();
+        assert!(stats.errors > 0);
+    }
+
+    #[test]
+    fn future_messages_are_bounded_and_not_relayed() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let signed = |node: &NijikaSimNode, round_num| {
+            let mut message = NijikaPBFTMessage::new_view_change_message(node.get_id(), round_num, 1, NijikaVRFCredential::default());
+            message.set_signature(node.sign(&message.signing_bytes().unwrap()).unwrap());
+            message
+        };
+        let near = signed(&simulator.get_nodes()[0], 2);
+        let far = signed(&simulator.get_nodes()[0], 1 + NIJIKA_PENDING_ROUNDS);
+        let in_flight = simulator.get_network().in_flight();
+        let node = simulator.get_node_mut(1).unwrap();
+        node.handle_pbft_message(sim_node_id(0), &near).unwrap();
+        node.handle_pbft_message(sim_node_id(0), &far).unwrap();
+        assert!(node.get_pbft_message(&near.hash().unwrap()).is_some());
+        assert!(node.get_pbft_message(&far.hash().unwrap()).is_none());
+        assert_eq!(simulator.get_network().in_flight(), in_flight);
+    }
+
+    #[test]
+    fn view_changes_carry_the_prepared_block_into_the_new_view() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        simulator.run(1, 1, 100_000).unwrap();
+        let nodes = simulator.get_nodes();
+        let committed = nodes[0].get_ledger().get_tip();
+        let view = nodes[0].get_round().get_view();
+        let view_changes: Vec<_> = nodes.iter()
+            .filter_map(|node| node.get_round().get_vote_credential().map(|credential| (node, credential.clone())))
+            .map(|(node, credential)| {
+                let mut message = NijikaPBFTMessage::new_view_change_message(node.get_id(), 1, view, credential);
+                message.set_proof(node.prepared_certificate()).unwrap();
+                node.sign_pbft_message(&mut message).unwrap();
+                message
+            })
+            .collect();
+        let prepared = nodes[0].verify_prepared_certificate(&view_changes[0]).unwrap().unwrap();
+        assert_eq!(prepared.block_hash, committed);
+        // a certificate without its messages proves nothing
+        assert!(nodes[0].verify_prepared_certificate(&view_changes[0].without_proof()).is_err());
+
+        let block = nodes[0].get_ledger().get_tip_block().clone();
+        let proposer = &nodes[1];
+        let new_view = |block_hash, proof: Vec<_>| {
+            let mut message = NijikaPBFTMessage::new_control_block_message(
+                proposer.get_id(), 1, view + 1, NijikaPBFTMessageType::NewView, block_hash, block.clone(), proposer.get_round().get_credential().clone()
+            );
+            message.set_proof(proof).unwrap();
+            message
+        };
+        assert_eq!(nodes[0].verify_new_view(&new_view(committed, view_changes.clone())).unwrap().map(|p| p.block_hash), Some(committed));
+        assert!(nodes[0].verify_new_view(&new_view(committed, vec![])).is_err());
+        assert!(nodes[0].verify_new_view(&new_view(HashValue::random(), view_changes)).is_err());
+    }
+
+    #[test]
+    fn replies_carry_the_commit_quorum() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        simulator.run(1, 1, 100_000).unwrap();
+        let nodes = simulator.get_nodes();
+        // the proposer keeps the reply it sent
+        let reply = nodes.iter()
+            .flat_map(|node| node.get_hash_queue(Some(NIJIKA_PBFT_MSG_QUEUE)).unwrap().iter().filter_map(|hash| node.get_pbft_message(hash)))
+            .find(|message| matches!(message.get_type(), NijikaPBFTMessageType::Reply))
+            .unwrap()
+            .clone();
+        let node = &nodes[0];
+        node.verify_commit_certificate(&reply).unwrap();
+        assert!(node.verify_commit_certificate(&reply.without_proof()).is_err());
+        let mut one_vote = reply.clone();
+        one_vote.set_proof(reply.get_proof()[..1].to_vec()).unwrap();
+        assert!(node.verify_commit_certificate(&one_vote).is_err());
+    }
+
+    type NijikaSim<'a> = NijikaSimulator<'a, NijikaSimControlBlock, NijikaSimDataBlock, HashValue, NijikaSimNode>;
+
+    /// a block of round 1 by node 0 with the given seed, and node 1 waiting to vote on it
+    /// a signed block of round 1 by the given node with the given seed, and the node's VRF public key
+    fn proposed_by(simulator: &NijikaSim<'_>, index: usize, seed_offset: u64) -> (NijikaSimControlBlock, Vec<u8>) {
+        let proposer = &simulator.get_nodes()[index];
+        let tip = proposer.get_ledger().get_tip();
+        let (seed, seed_proof) = NijikaVRFClientS::new_raw()
+            .prove_seed(proposer.get_secret_key(), proposer.get_ledger().get_tip_block().get_seed(), 1)
+            .unwrap();
+        let mut block = NijikaSimControlBlock::new(proposer.get_id(), 1, tip, seed + seed_offset, seed_proof);
+        block.set_signature(proposer.sign(&block.header_bytes().unwrap()).unwrap());
+        (block, proposer.get_public_key().to_vec())
+    }
+
+    fn proposal(simulator: &mut NijikaSim<'_>, seed_offset: u64) -> NijikaSimControlBlock {
+        let (block, proposer_key) = proposed_by(simulator, 0, seed_offset);
+        let validator = simulator.get_node_mut(1).unwrap();
+        validator.set_round(NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 1, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::Prepare)).unwrap();
+        validator.get_round_mut().set_proposer_key(proposer_key);
+        block
+    }
+
+    fn resigned(simulator: &NijikaSim<'_>, signer: usize, mut block: NijikaSimControlBlock) -> NijikaSimControlBlock {
+        block.set_signature(simulator.get_nodes()[signer].sign(&block.header_bytes().unwrap()).unwrap());
+        block
+    }
+
+    #[test]
+    fn control_blocks_are_validated_before_the_vote() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let block = proposal(&mut simulator, 0);
+        let validator = &simulator.get_nodes()[1];
+        assert_eq!(validator.validate_control_block(&block).unwrap(), None);
+
+        let wrong_seed = proposal(&mut simulator, 1);
+        let validator = &simulator.get_nodes()[1];
+        assert_eq!(validator.validate_control_block(&wrong_seed).unwrap(), Some(NijikaRejectReason::WrongSeed));
+        let elsewhere = NijikaSimControlBlock::new(sim_node_id(0), 1, HashValue::random(), block.get_seed(), block.get_seed_proof().to_vec());
+        assert_eq!(validator.validate_control_block(&resigned(&simulator, 0, elsewhere)).unwrap(), Some(NijikaRejectReason::WrongPreHash));
+        let later = NijikaSimControlBlock::new(sim_node_id(0), 2, *block.get_pre_hash(), block.get_seed(), block.get_seed_proof().to_vec());
+        assert_eq!(validator.validate_control_block(&resigned(&simulator, 0, later)).unwrap(), Some(NijikaRejectReason::WrongRound));
+        // signed by another node than its proposer
+        assert_eq!(validator.validate_control_block(&resigned(&simulator, 2, block.clone())).unwrap(), Some(NijikaRejectReason::InvalidSignature));
+        let mut unsigned = block.clone();
+        unsigned.set_signature(vec![]);
+        assert_eq!(validator.validate_control_block(&unsigned).unwrap(), Some(NijikaRejectReason::InvalidSignature));
+        // the signature covers the data block pointers
+        let mut loaded = block.clone();
+        loaded.push(HashValue::random());
+        assert_eq!(validator.validate_control_block(&loaded).unwrap(), Some(NijikaRejectReason::InvalidSignature));
+        assert_eq!(validator.validate_control_block(&resigned(&simulator, 0, loaded)).unwrap(), Some(NijikaRejectReason::MissingDataBlock));
+    }
+
+    #[test]
+    fn missing_data_blocks_are_fetched_before_the_vote() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let data_block = NijikaSimDataBlock::new(sim_node_id(0), 1);
+        let mut block = proposal(&mut simulator, 0);
+        block.push(data_block.hash().unwrap());
+        let block = resigned(&simulator, 0, block);
+        let in_flight = simulator.get_network().in_flight();
+        let validator = simulator.get_node_mut(1).unwrap();
+        let voter = hash::serialized(&validator.get_id()).unwrap();
+        validator.set_round_control_block(block).unwrap();
+        validator.prepare().unwrap();
+        // no vote either way, a GetData went to every peer instead
+        assert!(validator.get_round().get_rejection().is_none());
+        assert!(validator.get_round().get_vote(NijikaPBFTStage::Prepare, &voter).is_none());
+        assert!(validator.get_round().is_awaiting_data());
+        let peers = validator.get_transport().get_peers().len();
+        assert_eq!(simulator.get_network().in_flight(), in_flight + peers);
+
+        let validator = simulator.get_node_mut(1).unwrap();
+        validator.store_data_block(data_block).unwrap();
+        assert!(!validator.get_round().is_awaiting_data());
+        assert!(validator.get_round().get_vote(NijikaPBFTStage::Prepare, &voter).is_some());
+    }
+
+    #[test]
+    fn a_second_proposal_in_the_view_does_not_replace_the_first() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        // two proposers drawn in the same view, each proposing its own valid block
+        let (first, first_key) = proposed_by(&simulator, 0, 0);
+        let (second, second_key) = proposed_by(&simulator, 2, 0);
+        let validator = simulator.get_node_mut(1).unwrap();
+        let voter = hash::serialized(&validator.get_id()).unwrap();
+        validator.set_round(NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 1, 1, NijikaNodeRole::VALIDATOR, NijikaPBFTStage::WaitPrePrepare)).unwrap();
+        validator.handle_proposal(first.clone(), first_key).unwrap();
+        let voted = validator.get_round().get_vote(NijikaPBFTStage::Prepare, &voter).map(|(hash, _)| *hash);
+        assert_eq!(voted, Some(first.header_hash().unwrap()));
+        validator.handle_proposal(second.clone(), second_key.clone()).unwrap();
+        assert_eq!(validator.get_round_control_block().header_hash().unwrap(), first.header_hash().unwrap());
+        assert_eq!(validator.get_round().get_vote(NijikaPBFTStage::Prepare, &voter).map(|(hash, _)| *hash), voted);
+
+        // nor for a node outside the committee, which only learns the block
+        let packer = simulator.get_node_mut(3).unwrap();
+        packer.set_round(NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 1, 1, NijikaNodeRole::PACKER, NijikaPBFTStage::WaitReply)).unwrap();
+        packer.handle_proposal(first.clone(), vec![]).unwrap();
+        packer.handle_proposal(second, second_key).unwrap();
+        assert_eq!(packer.get_round_control_block().header_hash().unwrap(), first.header_hash().unwrap());
+    }
+
+    #[test]
+    fn two_proposers_in_one_view_keep_it_safe() {
+        let config = NijikaSimConfig { nodes: 6, proposers: 3, ..Default::default() };
+        let mut simulator = NijikaSimulator::from_config(&config).unwrap();
+        simulator.run(1, 3, 200_000).unwrap();
+        simulator.check_safety(&[]).unwrap();
+        simulator.check_liveness(3, &[]).unwrap();
+    }
+
+    #[test]
+    fn nays_arriving_before_the_block_abort_the_view() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let block = proposal(&mut simulator, 0);
+        let validator = simulator.get_node_mut(1).unwrap();
+        let voter = hash::serialized(&validator.get_id()).unwrap();
+        validator.handle_nay(NijikaPBFTStage::Prepare, HashValue::random(), block.header_hash().unwrap(), 1).unwrap();
+        assert!(!validator.get_round().is_aborted());
+        validator.handle_pre_prepare(block).unwrap();
+        assert!(validator.get_round().is_aborted());
+        assert!(validator.get_round().get_vote(NijikaPBFTStage::Prepare, &voter).is_none());
+    }
+
+    fn signed_change(node: &NijikaSimNode, change: NijikaStakeChange, round: u64) -> NijikaSignedStakeChange {
+        let signature = node.sign(&NijikaSignedStakeChange::signing_bytes(&change, round).unwrap()).unwrap();
+        NijikaSignedStakeChange { change, signature }
+    }
+
+    #[test]
+    fn stake_changes_need_their_owner_and_enough_stake() {
+        let simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let (owner, other) = (&simulator.get_nodes()[0], &simulator.get_nodes()[1]);
+        let account = hash::serialized(&owner.get_id()).unwrap();
+        let block_with = |signed| {
+            let mut block = NijikaSimControlBlock::new(owner.get_id(), 1, owner.get_ledger().get_tip(), 0, vec![]);
+            block.push_stake_change(signed);
+            block
+        };
+        let withdraw = |amount| NijikaStakeChange::Withdraw { account, amount };
+        assert!(other.verify_stake_changes(&block_with(signed_change(owner, withdraw(10), 1))).unwrap());
+        assert!(!other.verify_stake_changes(&block_with(signed_change(other, withdraw(10), 1))).unwrap());
+        assert!(!other.verify_stake_changes(&block_with(signed_change(owner, withdraw(10), 2))).unwrap());
+        assert!(!other.verify_stake_changes(&block_with(NijikaSignedStakeChange { change: withdraw(10), signature: vec![] })).unwrap());
+        // more than the owner holds could not be applied once committed
+        assert!(!other.verify_stake_changes(&block_with(signed_change(owner, withdraw(1000), 1))).unwrap());
+    }
+
+    #[test]
+    fn self_signed_deposits_are_rejected() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let account = hash::serialized(&sim_node_id(0)).unwrap();
+        let with_change = |simulator: &NijikaSim<'_>, change| {
+            let (mut block, _) = proposed_by(simulator, 0, 0);
+            block.push_stake_change(signed_change(&simulator.get_nodes()[0], change, 1));
+            resigned(simulator, 0, block)
+        };
+        let withdrawal = with_change(&simulator, NijikaStakeChange::Withdraw { account, amount: 10 });
+        let deposit = with_change(&simulator, NijikaStakeChange::Deposit { account, amount: 1000 });
+        proposal(&mut simulator, 0);
+        let validator = simulator.get_node_mut(1).unwrap();
+        assert_eq!(validator.validate_control_block(&withdrawal).unwrap(), None);
+        assert_eq!(validator.validate_control_block(&deposit).unwrap(), Some(NijikaRejectReason::InvalidStakeChange));
+    }
+
+    #[test]
+    fn a_fork_switch_reverts_the_stake_of_the_blocks_left_behind() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let owner = &simulator.get_nodes()[0];
+        let account = hash::serialized(&owner.get_id()).unwrap();
+        let genesis = owner.get_ledger().get_tip();
+        let mut a1 = NijikaSimControlBlock::new(sim_node_id(0), 1, genesis, 0, vec![]);
+        a1.push_stake_change(signed_change(owner, NijikaStakeChange::Withdraw { account, amount: 10 }, 1));
+        let b1 = NijikaSimControlBlock::new(sim_node_id(1), 1, genesis, 0, vec![]);
+        let b2 = NijikaSimControlBlock::new(sim_node_id(1), 2, b1.header_hash().unwrap(), 0, vec![]);
+        let node = simulator.get_node_mut(1).unwrap();
+        node.commit_control_block(a1).unwrap();
+        assert_eq!(node.get_stake_registry().unwrap().get_balance(&account), 90);
+        // the fork of the same height leaves the tip where it is
+        node.commit_control_block(b1).unwrap();
+        assert_eq!(node.get_stake_registry().unwrap().get_balance(&account), 90);
+        node.commit_control_block(b2.clone()).unwrap();
+        let registry = node.get_stake_registry().unwrap();
+        assert_eq!(registry.get_balance(&account), 100);
+        assert_eq!(registry.get_last_applied(), Some((2, b2.header_hash().unwrap())));
+    }
+
+    #[test]
+    fn own_credentials_verify_after_a_stake_change() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let owner = &simulator.get_nodes()[0];
+        let account = hash::serialized(&owner.get_id()).unwrap();
+        let mut block = NijikaSimControlBlock::new(sim_node_id(0), 1, owner.get_ledger().get_tip(), 0, vec![]);
+        block.push_stake_change(signed_change(owner, NijikaStakeChange::Withdraw { account, amount: 60 }, 1));
+        let node = simulator.get_node_mut(0).unwrap();
+        node.commit_control_block(block).unwrap();
+        // still in round 2, whose sortition sees the genesis stake, while round 3 sees the withdrawal
+        node.set_round(NijikaRound::new(NIJIKA_DEFAULT_QUORUM, 1, 2, NijikaNodeRole::NORMAL, NijikaPBFTStage::WaitReply)).unwrap();
+        assert_ne!(node.get_weight(), node.get_weight_of_at(node.get_id(), 3));
+        let mut credentials = vec![];
+        for view in 0..20 {
+            let sortition = node.vrf_selection(3, view).unwrap();
+            for role in [NijikaNodeRole::PACKER, NijikaNodeRole::PROPOSER, NijikaNodeRole::VALIDATOR] {
+                if let Some(credential) = sortition.get(role) {
+                    credentials.push((view, credential.clone()));
+                }
+            }
+        }
+        assert!(!credentials.is_empty());
+        for (view, credential) in credentials {
+            node.verify_credential(node.get_id(), 3, view, &credential).unwrap();
+        }
+    }
+
+    /// the Blocks answer of the node to a request from the tip, with or without the commit votes of each block
+    fn blocks_from(node: &NijikaSimNode, tip: HashValue, with_commits: bool) -> NijikaMessage {
+        let ledger = node.get_ledger();
+        let blocks = (1..=ledger.get_height()).map(|height| ledger.get_block_at(height).unwrap().clone()).collect::<Vec<_>>();
+        let commits = match with_commits {
+            true => node.collect_commits(&blocks).unwrap(),
+            false => vec![vec![]; blocks.len()]
+        };
+        let response = NijikaBlocks { tip, blocks, commits, round_num: node.get_round_num() };
+        NijikaMessage::new_message(node.get_id(), NijikaMessageType::Blocks, NijikaMessageDataType::ControlBlocks, bincode::serialize(&response).unwrap())
+    }
+
+    fn in_flight(simulator: &NijikaSim<'_>) -> Vec<NijikaSimEnvelope> {
+        std::iter::from_fn(|| simulator.get_network().pop_next()).collect()
+    }
+
+    #[test]
+    fn data_blocks_spread_by_invite_get_data_and_data() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let peers = simulator.get_nodes().len() - 1;
+        let (a, b) = (sim_node_id(0), sim_node_id(1));
+        let data_block = NijikaSimDataBlock::new(a, 1);
+        let hash = data_block.hash().unwrap();
+        let node = simulator.get_node_mut(0).unwrap();
+        node.store_data_block(data_block).unwrap();
+        node.broadcast_hash_message(NijikaMessageDataType::DataBlockHash, hash, None).unwrap();
+        let invites = in_flight(&simulator);
+        assert_eq!(invites.len(), peers);
+        assert!(invites.iter().all(|e| e.message.get_type() == NijikaMessageType::Invite && e.message.get_content() == hash.as_bytes()));
+        let invite = invites.into_iter().find(|e| e.to == b).unwrap().message;
+
+        // b misses the body and asks the announcing peer for it
+        simulator.get_node_mut(1).unwrap().handle_message(invite.clone()).unwrap();
+        let mut get_data = in_flight(&simulator);
+        assert_eq!(get_data.len(), 1);
+        let get_data = get_data.remove(0);
+        assert_eq!((get_data.to, get_data.message.get_type(), get_data.message.get_data_type()), (a, NijikaMessageType::GetData, NijikaMessageDataType::DataBlock));
+        simulator.get_node_mut(0).unwrap().handle_message(get_data.message).unwrap();
+        let mut data = in_flight(&simulator);
+        assert_eq!(data.len(), 1);
+        let data = data.remove(0);
+        assert_eq!((data.to, data.message.get_type()), (b, NijikaMessageType::Data));
+
+        // b keeps the block and announces it to everyone but the peer it came from
+        simulator.get_node_mut(1).unwrap().handle_message(data.message.clone()).unwrap();
+        assert!(simulator.get_nodes()[1].get_data_block(&hash).is_some());
+        let relayed = in_flight(&simulator);
+        assert_eq!(relayed.len(), peers - 1);
+        assert!(relayed.iter().all(|e| e.to != a && e.message.get_type() == NijikaMessageType::Invite));
+
+        // a known body is neither asked for nor relayed again
+        let node = simulator.get_node_mut(1).unwrap();
+        node.handle_message(invite).unwrap();
+        node.handle_message(data.message).unwrap();
+        assert_eq!(simulator.get_network().in_flight(), 0);
+    }
+
+    /// a Data message carrying the data block, as sent by node 0
+    fn data_message(block: &NijikaSimDataBlock) -> NijikaMessage {
+        NijikaMessage::new_message(sim_node_id(0), NijikaMessageType::Data, NijikaMessageDataType::DataBlock, bincode::serialize(block).unwrap())
+    }
+
+    #[test]
+    fn only_data_blocks_asked_for_and_intact_are_taken() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let mut data_block = NijikaSimDataBlock::new(sim_node_id(0), 1);
+        data_block.set_transactions(vec![Transaction::random(), Transaction::random()]);
+        let hash = data_block.hash().unwrap();
+        // the last transaction changed on the way, so the block no longer matches its Merkle root
+        let mut bytes = bincode::serialize(&data_block).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        let tampered: NijikaSimDataBlock = bincode::deserialize(&bytes).unwrap();
+        let tampered_hash = tampered.hash().unwrap();
+
+        let node = simulator.get_node_mut(1).unwrap();
+        assert!(node.handle_message(data_message(&data_block)).is_err());
+        assert!(node.get_data_block(&hash).is_none());
+        node.get_data_requests_mut().insert(tampered_hash);
+        assert!(node.handle_message(data_message(&tampered)).is_err());
+        assert!(node.get_data_block(&tampered_hash).is_none());
+        node.request_data_block(hash, None).unwrap();
+        node.handle_message(data_message(&data_block)).unwrap();
+        assert!(node.get_data_block(&hash).is_some());
+        assert!(node.get_data_requests_mut().is_empty());
+    }
+
+    #[test]
+    fn a_lagging_node_takes_only_proven_answers_to_its_requests() {
+        let config = NijikaSimConfig::default();
+        let mut simulator = NijikaSimulator::from_config(&config).unwrap();
+        simulator.run(1, 2, 100_000).unwrap();
+        let mut lagging = NijikaSimNode::new_nodes(&config, &NijikaSimNetwork::new(config.seed, 1, 1)).unwrap().remove(0);
+        lagging.enter_round(1, config.thresh).unwrap();
+        let genesis = lagging.get_ledger().get_tip();
+        let (peer, other) = (&simulator.get_nodes()[1], &simulator.get_nodes()[2]);
+        // nothing was asked yet
+        assert!(lagging.handle_blocks(blocks_from(peer, genesis, true)).is_err());
+        lagging.request_blocks(Some(peer.get_id())).unwrap();
+        assert!(lagging.handle_blocks(blocks_from(other, genesis, true)).is_err());
+        assert!(lagging.handle_blocks(blocks_from(peer, genesis, false)).is_err());
+        assert_eq!(lagging.get_ledger().get_height(), 0);
+        lagging.request_blocks(Some(peer.get_id())).unwrap();
+        lagging.handle_blocks(blocks_from(peer, genesis, true)).unwrap();
+        assert_eq!(lagging.get_ledger().get_tip(), peer.get_ledger().get_tip());
+        // the request was answered
+        assert!(lagging.handle_blocks(blocks_from(peer, genesis, true)).is_err());
+    }
+
+    #[test]
+    fn a_lagging_node_catches_up_no_further_than_the_proven_blocks() {
+        let config = NijikaSimConfig::default();
+        let mut simulator = NijikaSimulator::from_config(&config).unwrap();
+        simulator.run(1, 2, 100_000).unwrap();
+        let mut lagging = NijikaSimNode::new_nodes(&config, &NijikaSimNetwork::new(config.seed, 1, 1)).unwrap().remove(0);
+        lagging.enter_round(1, config.thresh).unwrap();
+        let genesis = lagging.get_ledger().get_tip();
+        let peer = &simulator.get_nodes()[1];
+        let message = blocks_from(peer, genesis, true);
+        let mut response: NijikaBlocks<NijikaSimControlBlock, HashValue> = bincode::deserialize(message.get_content()).unwrap();
+        response.round_num = 1000;
+        let inflated = NijikaMessage::new_message(peer.get_id(), NijikaMessageType::Blocks, NijikaMessageDataType::ControlBlocks, bincode::serialize(&response).unwrap());
+        lagging.request_blocks(Some(peer.get_id())).unwrap();
+        lagging.handle_blocks(inflated).unwrap();
+        assert_eq!(lagging.get_ledger().get_tip(), peer.get_ledger().get_tip());
+        assert_eq!(lagging.get_round_num(), lagging.get_ledger().get_tip_block().get_round() + 1);
+    }
+
+    #[test]
+    fn a_restarted_node_restores_what_it_persisted() {
+        let config = NijikaSimConfig::default();
+        let mut simulator = NijikaSimulator::from_config(&config).unwrap();
+        simulator.get_node_mut(0).unwrap().set_storage(NijikaMemoryStorage::new());
+        simulator.run(1, 2, 100_000).unwrap();
+        let node = &simulator.get_nodes()[0];
+        let storage = node.get_storage().unwrap().clone();
+        assert!(node.get_ledger().get_height() >= 2);
+
+        // the same node started again, from nothing but its storage
+        let network = NijikaSimNetwork::new(config.seed, config.min_latency, config.max_latency);
+        let mut restarted = NijikaSimNode::new_nodes(&config, &network).unwrap().remove(0);
+        restarted.set_storage(storage.clone());
+        assert_eq!(restarted.restore().unwrap(), storage.len());
+        assert_eq!(restarted.get_ledger().get_tip(), node.get_ledger().get_tip());
+        assert_eq!(restarted.get_ledger().get_height(), node.get_ledger().get_height());
+        for hash in node.get_hash_queue(Some(NIJIKA_PBFT_MSG_QUEUE)).unwrap() {
+            assert_eq!(restarted.get_pbft_message(hash).map(|m| m.hash().unwrap()), Some(*hash));
+        }
+        for hash in node.get_hash_queue(Some(NIJIKA_DATA_BLOCK_QUEUE)).unwrap() {
+            assert!(restarted.get_data_block(hash).is_some());
+        }
+        // replaying writes nothing again
+        assert_eq!(restarted.get_storage().unwrap().len(), storage.len());
+    }
+
+    #[test]
+    fn committed_transactions_leave_every_mempool() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let transactions = (0..4).map(|_| Transaction::random()).collect::<Vec<_>>();
+        for index in 0..simulator.get_nodes().len() {
+            for tx in transactions.iter() {
+                simulator.get_node_mut(index).unwrap().get_mempool_mut().admit(*tx).unwrap();
+            }
+        }
+        simulator.run(1, 2, 100_000).unwrap();
+        // whoever packed them, the transactions are gone from the nodes that did not
+        for node in simulator.get_nodes() {
+            assert!(node.get_mempool().is_empty(), "{} keeps {} transactions", node.get_name(), node.get_mempool().len());
+        }
+    }
+
+    #[test]
+    fn conflicting_replies_are_caught() {
+        // light nodes and small committees, so that some nodes are still waiting for the reply of the round
+        let config = NijikaSimConfig { seed: 2, nodes: 10, weight: 1, proposers: 2, validators: 7, ..Default::default() };
+        let mut simulator = NijikaSimulator::from_config(&config).unwrap();
+        simulator.get_node_mut(0).unwrap().set_behaviour(NijikaSimBehaviour::ConflictingReplies);
+        simulator.run(1, 4, 200_000).unwrap();
+        simulator.check_safety(&[0]).unwrap();
+        simulator.check_liveness(4, &[0]).unwrap();
+        let caught = simulator.get_nodes()[1..].iter()
+            .all(|node| node.get_evidence_pool().unwrap().iter().any(|evidence| {
+                evidence.get_offender() == sim_node_id(0) && matches!(evidence.get_first().get_type(), NijikaPBFTMessageType::Reply)
+            }));
+        assert!(caught);
+    }
+
+    #[test]
+    fn timeouts_racing_live_messages_keep_it_safe() {
+        let config = NijikaSimConfig { stage_timeout: Some(300), ..Default::default() };
+        let mut simulator = NijikaSimulator::from_config(&config).unwrap();
+        simulator.get_network().set_faults(NijikaSimFaults { delay_rate: 0.1, max_delay: 400, ..Default::default() });
+        let stats = simulator.run(1, 4, 100_000).unwrap();
+        assert!(stats.early_timeouts > 0);
+        simulator.check_safety(&[]).unwrap();
+        simulator.check_liveness(4, &[]).unwrap();
+    }
+
+    #[test]
+    fn blocks_left_behind_by_a_fork_switch_still_count_for_safety() {
+        let mut simulator = NijikaSimulator::from_config(&NijikaSimConfig::default()).unwrap();
+        let genesis = simulator.get_nodes()[0].get_ledger().get_tip();
+        let a1 = NijikaSimControlBlock::new(sim_node_id(0), 1, genesis, 0, vec![]);
+        let b1 = NijikaSimControlBlock::new(sim_node_id(1), 1, genesis, 1, vec![]);
+        let b2 = NijikaSimControlBlock::new(sim_node_id(1), 2, b1.header_hash().unwrap(), 0, vec![]);
+        simulator.get_node_mut(0).unwrap().commit_control_block(b1.clone()).unwrap();
+        simulator.record(0).unwrap();
+        let node = simulator.get_node_mut(1).unwrap();
+        node.commit_control_block(a1).unwrap();
+        simulator.record(1).unwrap();
+        // node 1 switches to the chain of node 0, which hides the other block it committed in round 1
+        let node = simulator.get_node_mut(1).unwrap();
+        node.commit_control_block(b1).unwrap();
+        node.commit_control_block(b2).unwrap();
+        simulator.record(1).unwrap();
+        assert_eq!(simulator.get_committed(1).unwrap().len(), 3);
+        assert!(simulator.check_safety(&[]).is_err());
+    }
+
+    #[test]
+    fn only_validator_seats_vote() {
+        let config = NijikaSimConfig::default();
+        let mut simulator = NijikaSimulator::from_config(&config).unwrap();
+        simulator.run(1, 1, 100_000).unwrap();
+        let nodes = simulator.get_nodes();
+        // the quorum is a share of the expected validators, whatever the caller of enter_round had in mind
+        assert!(nodes.iter().all(|node| node.get_round().get_expected() == config.validators));
+        let votes = nodes.iter()
+            .flat_map(|node| node.get_hash_queue(Some(NIJIKA_PBFT_MSG_QUEUE)).unwrap().iter().filter_map(|hash| node.get_pbft_message(hash)))
+            .filter(|message| matches!(message.get_type(), NijikaPBFTMessageType::Prepare | NijikaPBFTMessageType::Commit | NijikaPBFTMessageType::ViewChange))
+            .collect::<Vec<_>>();
+        assert!(!votes.is_empty());
+        assert!(votes.iter().all(|vote| vote.get_credential().role == NijikaNodeRole::VALIDATOR));
+        // a commit claiming the weight of a proposer seat is refused
+        let proposer = nodes.iter().find(|node| node.get_round().has_role(NijikaNodeRole::PROPOSER)).unwrap();
+        let mut commit = votes.iter().find(|vote| vote.get_source() == proposer.get_id() && matches!(vote.get_type(), NijikaPBFTMessageType::Commit))
+            .map(|vote| NijikaPBFTMessage::new_vote_message(
+                proposer.get_id(), 1, vote.get_view(), NijikaPBFTMessageType::Commit, vote.get_control_block_hash(),
+                vote.get_vote().unwrap(), proposer.get_round().get_credential().clone()
+            ))
+            .unwrap();
+        proposer.sign_pbft_message(&mut commit).unwrap();
+        let refused = nodes[0].verify_commits(1, commit.get_view(), commit.get_control_block_hash(), nodes[0].get_vrf_seed(), &[commit]);
+        assert!(matches!(refused, Err(NijikaError::InvalidCredential(_))), "{:?}", refused);
+    }
+}
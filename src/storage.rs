@@ -0,0 +1,8 @@
+mod record;
+pub use record::*;
+mod memory;
+pub use memory::*;
+mod file;
+pub use file::*;
+mod restore;
+pub use restore::*;
@@ -0,0 +1,142 @@
+use std::{fs::{File, OpenOptions}, io::{Read, Write}, path::{Path, PathBuf}};
+
+use crate::{hash::{hash, NijikaDigestT}, primitives::{HashValue, NijikaResult, NijikaError}};
+
+use super::{NijikaRecord, NijikaRecordKind, NijikaStorageT};
+
+/// kind, content length and checksum in front of every record
+const NIJIKA_RECORD_HEADER_SIZE: usize = 1 + 4 + HashValue::LEN;
+
+fn checksum(kind: NijikaRecordKind, content: &[u8]) -> HashValue {
+    let mut bytes = Vec::with_capacity(content.len() + 1);
+    bytes.push(kind.as_byte());
+    bytes.extend_from_slice(content);
+    hash::new(&bytes)
+}
+
+/// read the records from the front of the bytes, and return them with the length they take.
+/// Stops at the first record that is cut short or does not match its checksum
+fn parse_records(bytes: &[u8]) -> (Vec<NijikaRecord>, usize) {
+    let mut records = vec![];
+    let mut offset = 0;
+    while bytes.len() - offset >= NIJIKA_RECORD_HEADER_SIZE {
+        let header = &bytes[offset..offset + NIJIKA_RECORD_HEADER_SIZE];
+        let kind = match NijikaRecordKind::from_byte(header[0]) {
+            Ok(kind) => kind,
+            Err(_) => break
+        };
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let start = offset + NIJIKA_RECORD_HEADER_SIZE;
+        if bytes.len() - start < len {
+            break;
+        }
+        let content = &bytes[start..start + len];
+        if checksum(kind, content).as_bytes() != &header[5..] {
+            break;
+        }
+        records.push(NijikaRecord { kind, content: content.to_vec() });
+        offset = start + len;
+    }
+    (records, offset)
+}
+
+/// append-only log file of records.
+/// Every append is synced to disk before it returns, and a record torn by a crash is cut off when the file is opened again
+#[derive(Debug)]
+pub struct NijikaFileStorage {
+    path: PathBuf,
+    file: File,
+}
+
+impl NijikaFileStorage {
+    /// open the log at the given path, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> NijikaResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)
+            .map_err(|e| NijikaError::StorageError(format!("{}", e)))?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes).map_err(|e| NijikaError::StorageError(format!("{}", e)))?;
+        let (_, valid_len) = parse_records(&bytes);
+        if valid_len < bytes.len() {
+            println!("storage error: drop {} broken bytes at the end of {}", bytes.len() - valid_len, path.display());
+            file.set_len(valid_len as u64).map_err(|e| NijikaError::StorageError(format!("{}", e)))?;
+            file.sync_all().map_err(|e| NijikaError::StorageError(format!("{}", e)))?;
+        }
+        Ok(NijikaFileStorage { path, file })
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl NijikaStorageT for NijikaFileStorage {
+    fn append(&mut self, kind: NijikaRecordKind, content: &[u8]) -> NijikaResult<()> {
+        let len: u32 = content.len().try_into()
+            .map_err(|_| NijikaError::StorageError(format!("record of {} bytes is too large", content.len())))?;
+        let mut bytes = Vec::with_capacity(NIJIKA_RECORD_HEADER_SIZE + content.len());
+        bytes.push(kind.as_byte());
+        bytes.extend_from_slice(&len.to_be_bytes());
+        bytes.extend_from_slice(checksum(kind, content).as_bytes());
+        bytes.extend_from_slice(content);
+        // one write per record, so a crash can only tear the last one
+        self.file.write_all(&bytes).map_err(|e| NijikaError::StorageError(format!("{}", e)))?;
+        self.file.sync_data().map_err(|e| NijikaError::StorageError(format!("{}", e)))
+    }
+
+    fn records(&self) -> NijikaResult<Vec<NijikaRecord>> {
+        let mut bytes = vec![];
+        File::open(&self.path).and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|e| NijikaError::StorageError(format!("{}", e)))?;
+        Ok(parse_records(&bytes).0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("nijika-storage-{}.log", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn records_survive_reopen() {
+        let path = temp_path();
+        {
+            let mut storage = NijikaFileStorage::open(&path).unwrap();
+            storage.append(NijikaRecordKind::ControlBlock, b"block").unwrap();
+            storage.append(NijikaRecordKind::PBFTMessage, b"").unwrap();
+        }
+        let storage = NijikaFileStorage::open(&path).unwrap();
+        let records = storage.records().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records, vec![
+            NijikaRecord { kind: NijikaRecordKind::ControlBlock, content: b"block".to_vec() },
+            NijikaRecord { kind: NijikaRecordKind::PBFTMessage, content: vec![] },
+        ]);
+    }
+
+    #[test]
+    fn torn_record_is_cut_off() {
+        let path = temp_path();
+        {
+            let mut storage = NijikaFileStorage::open(&path).unwrap();
+            storage.append(NijikaRecordKind::DataBlock, b"first").unwrap();
+            storage.append(NijikaRecordKind::DataBlock, b"second").unwrap();
+        }
+        // lose the last two bytes, as if the node crashed while writing
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+
+        let mut storage = NijikaFileStorage::open(&path).unwrap();
+        assert_eq!(storage.records().unwrap().len(), 1);
+        storage.append(NijikaRecordKind::DataBlock, b"third").unwrap();
+        let records = storage.records().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records.iter().map(|r| r.content.clone()).collect::<Vec<_>>(), vec![b"first".to_vec(), b"third".to_vec()]);
+    }
+}
@@ -0,0 +1,34 @@
+use crate::primitives::NijikaResult;
+
+use super::{NijikaRecord, NijikaRecordKind, NijikaStorageT};
+
+/// keeps the records in memory, e.g. for tests and simulations
+#[derive(Debug, Default, Clone)]
+pub struct NijikaMemoryStorage {
+    records: Vec<NijikaRecord>,
+}
+
+impl NijikaMemoryStorage {
+    pub fn new() -> Self {
+        NijikaMemoryStorage::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+impl NijikaStorageT for NijikaMemoryStorage {
+    fn append(&mut self, kind: NijikaRecordKind, content: &[u8]) -> NijikaResult<()> {
+        self.records.push(NijikaRecord { kind, content: content.to_vec() });
+        Ok(())
+    }
+
+    fn records(&self) -> NijikaResult<Vec<NijikaRecord>> {
+        Ok(self.records.clone())
+    }
+}
@@ -0,0 +1,43 @@
+use crate::primitives::{NijikaResult, NijikaError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NijikaRecordKind {
+    ControlBlock,
+    DataBlock,
+    PBFTMessage,
+}
+
+impl NijikaRecordKind {
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            NijikaRecordKind::ControlBlock => 0,
+            NijikaRecordKind::DataBlock => 1,
+            NijikaRecordKind::PBFTMessage => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> NijikaResult<Self> {
+        match byte {
+            0 => Ok(NijikaRecordKind::ControlBlock),
+            1 => Ok(NijikaRecordKind::DataBlock),
+            2 => Ok(NijikaRecordKind::PBFTMessage),
+            _ => Err(NijikaError::ParseError(format!("unknown record kind {}", byte)))
+        }
+    }
+}
+
+/// one stored item, its content is the bincode encoding of a block or a pbft message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NijikaRecord {
+    pub kind: NijikaRecordKind,
+    pub content: Vec<u8>,
+}
+
+/// where a node keeps its committed control blocks, data blocks and pbft messages across restarts.
+/// Records are only ever appended, and read back in the order they were written
+pub trait NijikaStorageT {
+    /// the record must be durable once this returns Ok
+    fn append(&mut self, kind: NijikaRecordKind, content: &[u8]) -> NijikaResult<()>;
+
+    fn records(&self) -> NijikaResult<Vec<NijikaRecord>>;
+}
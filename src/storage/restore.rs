@@ -0,0 +1,53 @@
+use std::fmt::Debug;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    consensus::NijikaPBFTMessageApi,
+    primitives::{NijikaResult, NijikaError, NijikaPBFTMessage, NijikaControlBlockT, NijikaDataBlockT, NijikaIdT},
+};
+
+use super::NijikaRecordKind;
+
+/// load what a node has persisted back into its ledger and pools, e.g. after a restart
+pub trait NijikaStorageApi<'a,
+    CB: NijikaControlBlockT + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    DB: NijikaDataBlockT<Hasher = CB::Hasher> + Serialize + DeserializeOwned + Debug + Clone + 'a,
+    ID: Clone + Copy + Debug + Serialize + NijikaIdT + DeserializeOwned + 'a
+>: NijikaPBFTMessageApi<'a, CB, DB, ID> {
+    /// replay every record of the node's storage in the order they were written, without writing them again.
+    /// Returns the number of records replayed
+    fn restore(&mut self) -> NijikaResult<usize> {
+        let records = match self.get_storage_mut() {
+            Some(storage) => storage.records()?,
+            None => return Ok(0)
+        };
+        for record in records.iter() {
+            match record.kind {
+                NijikaRecordKind::ControlBlock => {
+                    let block: CB = bincode::deserialize(&record.content)
+                        .map_err(|e| NijikaError::StorageError(format!("{}", e)))?;
+                    self.commit_control_block(block)?;
+                },
+                NijikaRecordKind::DataBlock => {
+                    let block: DB = bincode::deserialize(&record.content)
+                        .map_err(|e| NijikaError::StorageError(format!("{}", e)))?;
+                    let hash = block.hash()?;
+                    if self.get_data_block(&hash).is_none() {
+                        self.append_data_block_hash_queue(hash)?;
+                        self.insert_data_block_pool(hash, block)?;
+                    }
+                },
+                NijikaRecordKind::PBFTMessage => {
+                    let message: NijikaPBFTMessage<CB, ID> = NijikaPBFTMessage::decode(&record.content)?;
+                    let hash = message.hash()?;
+                    if self.get_pbft_message(&hash).is_none() {
+                        self.append_pbft_message_queue(hash)?;
+                        self.insert_pbft_message_pool(hash, message)?;
+                    }
+                },
+            }
+        }
+        Ok(records.len())
+    }
+}
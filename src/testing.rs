@@ -0,0 +1,91 @@
+use std::{fmt::{Debug, Formatter, Result as FmtResult}, marker::PhantomData};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{hash::{NijikaHasher, NijikaSha512}, primitives::{
+    NijikaBlockType,
+    NijikaBlockT,
+    NijikaControlBlockT,
+    NijikaDigest,
+    NijikaResult,
+    NijikaError,
+    HashValue
+}};
+
+/// the control block the unit tests build chains and messages from, hashed with H
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TestBlockOf<H: NijikaHasher> {
+    block_type: NijikaBlockType,
+    pub round: u64,
+    pre_hash: H::Output,
+    /// tells apart siblings of the same round, as the header's timestamp
+    nonce: u64,
+    proposer: HashValue,
+    #[serde(skip)]
+    hasher: PhantomData<H>,
+}
+
+/// the sha-512 test block most tests use
+pub(crate) type TestBlock = TestBlockOf<NijikaSha512>;
+
+impl<H: NijikaHasher> Clone for TestBlockOf<H> {
+    fn clone(&self) -> Self {
+        TestBlockOf { block_type: self.block_type.clone(), hasher: PhantomData, ..*self }
+    }
+}
+
+impl<H: NijikaHasher> Debug for TestBlockOf<H> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "TestBlock {{ round: {}, pre_hash: {}, nonce: {} }}", self.round, self.pre_hash, self.nonce)
+    }
+}
+
+impl<H: NijikaHasher> NijikaBlockT for TestBlockOf<H> {
+    type Hasher = H;
+    fn get_type(&self) -> &NijikaBlockType {
+        &self.block_type
+    }
+    fn get_round(&self) -> u64 {
+        self.round
+    }
+    fn as_bytes(&self) -> NijikaResult<Vec<u8>> {
+        bincode::serialize(self).map_err(|e| NijikaError::ParseError(format!("{}", e)))
+    }
+    fn hash(&self) -> NijikaResult<H::Output> {
+        Ok(H::digest(&self.as_bytes()?))
+    }
+}
+
+impl<H: NijikaHasher> NijikaControlBlockT for TestBlockOf<H> {
+    fn get_seed(&self) -> u64 {
+        0
+    }
+    fn get_seed_proof(&self) -> &[u8] {
+        &[]
+    }
+    fn get_pre_hash(&self) -> &NijikaDigest<Self> {
+        &self.pre_hash
+    }
+    fn get_data_block_pointers(&self) -> &[NijikaDigest<Self>] {
+        &[]
+    }
+    fn get_timestamp(&self) -> u64 {
+        self.nonce
+    }
+    fn get_proposer(&self) -> &HashValue {
+        &self.proposer
+    }
+}
+
+impl<H: NijikaHasher> TestBlockOf<H> {
+    pub fn new(round: u64, pre_hash: H::Output) -> Self {
+        TestBlockOf { block_type: NijikaBlockType::CONTROL, round, pre_hash, nonce: 0, proposer: HashValue::default(), hasher: PhantomData }
+    }
+    pub fn genesis() -> Self {
+        Self::new(0, H::Output::default())
+    }
+    /// a block of the given round on top of the parent
+    pub fn child(parent: &Self, round: u64, nonce: u64) -> Self {
+        TestBlockOf { nonce, ..Self::new(round, parent.header_hash().unwrap()) }
+    }
+}
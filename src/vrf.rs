@@ -1,54 +1,123 @@
-use bincode;
-use rug::{Float, Integer};
-use rug::ops::Pow;
+use std::{collections::HashMap, sync::{Arc, Mutex, OnceLock}};
+
 use serde::Serialize;
 use vrf::openssl::{CipherSuite, ECVRF, Error};
 use vrf::VRF;
 use rand::prelude::Rng;
 use rand_chacha::{ChaCha8Rng, rand_core::SeedableRng};
-use crate::primitives::NijikaNodeRole;
+use crate::{hash::hash, primitives::NijikaNodeRole};
+
+/// sortition tables kept at most; the cache is emptied when it grows past this
+pub const NIJIKA_SORTITION_CACHE: usize = 4096;
+
+/// a binomial term below this, past the mean, ends the table: the rest of the tail cannot move an f64 CDF
+const NIJIKA_SORTITION_TAIL: f64 = 1e-20;
 
 pub struct NijikaVRFClientS {
     client: ECVRF,
-    binomial_bounds: Vec<Float>
+    binomial_bounds: Arc<Vec<f64>>,
+    weight: u64,
+}
+
+type NijikaSortitionKey = (u64, u64, u64);
+
+fn sortition_cache() -> &'static Mutex<HashMap<NijikaSortitionKey, Arc<Vec<f64>>>> {
+    static CACHE: OnceLock<Mutex<HashMap<NijikaSortitionKey, Arc<Vec<f64>>>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// the CDF of Binomial(weight, expected / total): bounds[j + 1] = P(X <= j), bounds[0] = 0.
+/// The terms are built one from the previous in log space, so that (1 - p)^weight cannot underflow,
+/// and the table stops once the remaining terms are negligible, so a large weight costs about as much as a small one.
+/// When p is 1 every sub-user is won: the table is [0, 0], which no value is below
+fn binomial_bounds(weight: u64, expected: u64, total: u64) -> Vec<f64> {
+    let p = if total == 0 { 0.0 } else { (expected as f64 / total as f64).min(1.0) };
+    let mut bounds = vec![0.0];
+    if p == 0.0 || weight == 0 {
+        bounds.push(1.0);
+        return bounds;
+    }
+    if p == 1.0 {
+        bounds.push(0.0);
+        return bounds;
+    }
+    let mean = weight as f64 * p;
+    let log_odds = p.ln() - (-p).ln_1p();
+    let mut log_term = weight as f64 * (-p).ln_1p();
+    let mut cdf = 0.0;
+    for j in 0..=weight {
+        let term = log_term.exp();
+        cdf += term;
+        bounds.push(cdf.min(1.0));
+        if j as f64 > mean && term < NIJIKA_SORTITION_TAIL {
+            break;
+        }
+        log_term += ((weight - j) as f64).ln() - ((j + 1) as f64).ln() + log_odds;
+    }
+    // the rounding of the sum must not leave a value past the last bound
+    if let Some(last) = bounds.last_mut() {
+        *last = 1.0;
+    }
+    bounds
 }
 
 #[derive(Serialize, Debug)]
 pub struct NijikaVRFParams {
     pub weight: u64,
     pub round: u64,
+    pub view: u64,
     pub seed: u64,
     pub role: NijikaNodeRole,
 }
 
+/// what the proposer of a round proves to derive the round's seed from the previous one
+#[derive(Serialize, Debug)]
+pub struct NijikaSeedParams {
+    pub seed: u64,
+    pub round: u64,
+}
+
+/// the seed of a round: the hash of the previous seed, the round number and the proposer's VRF output over both
+pub fn derive_seed(previous_seed: u64, round: u64, vrf_output: &[u8]) -> u64 {
+    let mut content = bincode::serialize(&NijikaSeedParams { seed: previous_seed, round }).unwrap();
+    content.extend_from_slice(vrf_output);
+    let digest = hash::new(&content);
+    u64::from_be_bytes(digest.as_bytes()[..8].try_into().expect("a 64-byte hash"))
+}
+
 impl NijikaVRFClientS {
     pub fn new_raw() -> Self {
         let vrf = ECVRF::from_suite(CipherSuite::SECP256K1_SHA256_TAI).unwrap();
-        NijikaVRFClientS { client: vrf, binomial_bounds: vec![] }
+        NijikaVRFClientS { client: vrf, binomial_bounds: Arc::new(vec![]), weight: 0 }
     }
+    /// the sortition table is shared by every client with the same (weight, expected, total)
     pub fn new(own_units:u64, expect_units: u64, total_unit: u64) -> Self {
         let vrf = ECVRF::from_suite(CipherSuite::SECP256K1_SHA256_TAI).unwrap();
-        let /*p*/prospect = Float::with_val(113, expect_units) / Float::with_val(113, total_unit);
-        let tmp_res = Float::with_val(113, 0);
-        let mut res = vec![tmp_res];
-        for index in (0..own_units) {
-            let l1 = prospect.clone().pow(index);
-            let l2 = (Float::with_val(113, 1)-prospect.clone()).pow(own_units - index);
-            let multi = l1 * l2 * Integer::from(own_units).binomial(index.try_into().unwrap());
-            res.push(multi + res.last().expect("cannot be a empty vec"));
-        }
-        Self {client: vrf, binomial_bounds: res}
+        let key = (own_units, expect_units, total_unit);
+        let mut cache = sortition_cache().lock().expect("sortition cache poisoned");
+        let bounds = match cache.get(&key) {
+            Some(bounds) => bounds.clone(),
+            None => {
+                if cache.len() >= NIJIKA_SORTITION_CACHE {
+                    cache.clear();
+                }
+                let bounds = Arc::new(binomial_bounds(own_units, expect_units, total_unit));
+                cache.insert(key, bounds.clone());
+                bounds
+            }
+        };
+        Self {client: vrf, binomial_bounds: bounds, weight: own_units}
     }
     pub fn gen_keys(&mut self, seed: u64) -> Result<(Vec<u8>, Vec<u8>), Error> {
         let mut rng = ChaCha8Rng::seed_from_u64(seed);
         let size: u64 = rng.gen_range(10..256);
-        let mut secret_key = vec![0 as u8; size as usize];
+        let mut secret_key = vec![0u8; size as usize];
         for i in secret_key.iter_mut() {
             *i = rng.gen();
         }
         match self.client.derive_public_key(&secret_key) {
             Ok(public_key) => {
-                return Ok((secret_key, public_key));
+                Ok((secret_key, public_key))
             },
             Err(e) => Err(e)
         }
@@ -71,38 +140,47 @@ impl NijikaVRFClientS {
         let nijika_vrf_params = bincode::serialize(data).unwrap();
         match self.client.verify(public_key, proof, &nijika_vrf_params) {
             Ok(beta) => {
-                if beta == hash {
-                    return Ok(true);
-                } else {
-                    return Ok(false);
-                }
+                Ok(beta == hash)
             },
             Err(e) => Err(e)
         }
     }
-    pub fn sortition(&self, bytes: &[u8]) -> (u64, Float) {
-        let divisor = Integer::from(Integer::i_pow_u(2, 256));
-        let dividend = Float::with_val(256, Integer::from_digits(bytes, rug::integer::Order::Lsf));
-        let val = dividend / divisor;
-        let len = self.binomial_bounds.len();
-        for i in (1..len) {
-            if val < self.binomial_bounds[i] {
-                return (i as u64 - 1, val);
-            }
-        }
-        return (len as u64, val);
+    /// the seed of the given round and the proof that it follows from the previous seed
+    pub fn prove_seed(&mut self, secret_key: &[u8], previous_seed: u64, round: u64) -> Result<(u64, Vec<u8>), Error> {
+        let params = bincode::serialize(&NijikaSeedParams { seed: previous_seed, round }).unwrap();
+        let proof = self.client.prove(secret_key, &params)?;
+        let output = self.client.proof_to_hash(&proof)?;
+        Ok((derive_seed(previous_seed, round, &output), proof))
+    }
+    /// check that the seed was derived by the owner of the public key from the previous seed
+    pub fn verify_seed(&mut self, public_key: &[u8], proof: &[u8], previous_seed: u64, round: u64, seed: u64) -> Result<bool, Error> {
+        let params = bincode::serialize(&NijikaSeedParams { seed: previous_seed, round }).unwrap();
+        let output = self.client.verify(public_key, proof, &params)?;
+        Ok(derive_seed(previous_seed, round, &output) == seed)
+    }
+    /// the number of sub-users won with the given VRF hash, and the hash as a fraction of [0, 1).
+    /// The hash is read least significant byte first; its 8 most significant bytes are all an f64 can tell apart.
+    /// Never more than the weight is won
+    pub fn sortition(&self, bytes: &[u8]) -> (u64, f64) {
+        let val = bytes.iter().rev().take(8).enumerate()
+            .fold(0.0, |acc, (i, byte)| acc + *byte as f64 / 256f64.powi(i as i32 + 1));
+        // the first bound above the value, i.e. the smallest j with val < P(X <= j); none means every sub-user
+        let won = match self.binomial_bounds.partition_point(|bound| *bound <= val) {
+            found if found < self.binomial_bounds.len() => found.saturating_sub(1) as u64,
+            _ => self.weight,
+        };
+        (won.min(self.weight), val)
     }
 }
 
+#[cfg(test)]
 mod tests {
-    use std::ops::Div;
-
-    use rug::{Integer, Float};
+    use std::sync::Arc;
 
     use crate::primitives::ByteArray;
 
-    use super::{NijikaNodeRole, NijikaVRFClientS, NijikaVRFParams};
-    type my_hash = ByteArray<32>;
+    use super::{binomial_bounds, NijikaNodeRole, NijikaVRFClientS, NijikaVRFParams};
+    type MyHash = ByteArray<32>;
     #[test]
     fn work() {
         let mut vrf = NijikaVRFClientS::new(10, 100, 1000);
@@ -110,6 +188,7 @@ mod tests {
         let data = NijikaVRFParams {
             weight: 10,
             round: 12,
+            view: 0,
             seed: 128,
             role: NijikaNodeRole::NORMAL
         };
@@ -123,10 +202,51 @@ mod tests {
                 println!("verify failed");
             }
         }
-        let a = my_hash::random();
+        let (seed, seed_proof) = vrf.prove_seed(&s, 128, 13).unwrap();
+        assert!(vrf.verify_seed(&p, &seed_proof, 128, 13, seed).unwrap());
+        assert!(!vrf.verify_seed(&p, &seed_proof, 128, 13, seed ^ 1).unwrap());
+        assert!(!vrf.verify_seed(&p, &seed_proof, 128, 14, seed).unwrap_or(false));
+        let a = MyHash::random();
         let (i,val) = vrf.sortition(a.as_bytes());
         println!("gen sortition array: {:#?}", vrf.binomial_bounds);
         println!("gen hash value: {}", val);
         println!("told that is in index: {}", i);
     }
+
+    #[test]
+    fn sortition_table_matches_the_binomial() {
+        let (n, p) = (10u64, 0.1f64);
+        let bounds = binomial_bounds(n, 100, 1000);
+        let mut cdf = 0.0;
+        for k in 0..=n {
+            let choose = (0..k).fold(1.0, |acc, i| acc * (n - i) as f64 / (i + 1) as f64);
+            cdf += choose * p.powi(k as i32) * (1.0 - p).powi((n - k) as i32);
+            if let Some(bound) = bounds.get(k as usize + 1) {
+                assert!((bound - cdf).abs() < 1e-12, "P(X <= {}) = {} but got {}", k, cdf, bound);
+            }
+        }
+        let client = NijikaVRFClientS::new(n, 100, 1000);
+        assert_eq!(client.sortition(&[0u8; 32]).0, 0);
+        let most = client.sortition(&[0xffu8; 32]).0;
+        assert_eq!(most as usize, bounds.len() - 2);
+        assert!(most <= n);
+        // the whole stake is the committee: a two-entry table, and exactly the weight is won
+        let certain = binomial_bounds(n, 1000, 1000);
+        assert_eq!(certain.len(), 2);
+        let client = NijikaVRFClientS::new(n, 1000, 1000);
+        assert_eq!(client.sortition(&[0u8; 32]).0, n);
+        assert_eq!(client.sortition(&[0xffu8; 32]).0, n);
+    }
+
+    #[test]
+    fn large_stake_uses_a_short_cached_table() {
+        let a = NijikaVRFClientS::new(1_000_000_000, 20, 10_000_000_000);
+        let b = NijikaVRFClientS::new(1_000_000_000, 20, 10_000_000_000);
+        assert!(Arc::ptr_eq(&a.binomial_bounds, &b.binomial_bounds));
+        assert!(a.binomial_bounds.len() < 100);
+        assert!((a.binomial_bounds.last().unwrap() - 1.0).abs() < 1e-12);
+        // a stake far larger than the committee wins about all of it
+        let all = NijikaVRFClientS::new(1_000_000, 1_000_000, 1_000_000);
+        assert_eq!(all.sortition(&[0x80u8; 32]).0, 1_000_000);
+    }
 }
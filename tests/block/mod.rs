@@ -1,13 +1,13 @@
 use std::{collections::HashMap};
 
 use serde::{Serialize, Deserialize};
-use serde_big_array::BigArray;
-use bincode;
 
 use nijika::{NijikaBlockType, HashValue, Signature, Transaction, NijikaBlockT, NijikaResult, NijikaError};
 use nijika::{NijikaControlBlockT, NijikaDataBlockT};
-use nijika::hash::hash;
+use nijika::hash::{hash, NijikaSha512};
+use nijika::merkle;
 
+/// transactions a data block carries at most
 pub const M: usize = 1820 * 4;
 #[derive(Debug, Serialize,Clone, Deserialize)]
 pub struct NijikaTestControlBlock {
@@ -17,11 +17,13 @@ pub struct NijikaTestControlBlock {
     seed: u64,
     seed_proof: Vec<u8>,
     proposer_id: HashValue,
-    signature: Signature,
+    /// the proposer's signature on the header_bytes
+    signature: Vec<u8>,
     data_block_pointers: Vec<HashValue>,
 }
 
 impl NijikaBlockT for NijikaTestControlBlock {
+    type Hasher = NijikaSha512;
     fn get_type(&self) -> &NijikaBlockType {
         &self.block_type
     }
@@ -48,9 +50,18 @@ impl NijikaControlBlockT for NijikaTestControlBlock {
     fn get_seed(&self) -> u64 {
         self.seed
     }
+    fn get_seed_proof(&self) -> &[u8] {
+        &self.seed_proof
+    }
     fn get_pre_hash(&self) -> &HashValue {
         &self.pre_hash
     }
+    fn get_data_block_pointers(&self) -> &[HashValue] {
+        &self.data_block_pointers
+    }
+    fn get_proposer(&self) -> &HashValue {
+        &self.proposer_id
+    }
 }
 
 impl NijikaTestControlBlock {
@@ -58,20 +69,27 @@ impl NijikaTestControlBlock {
         NijikaTestControlBlock {
             block_type: NijikaBlockType::CONTROL,
             round_num: round,
-            pre_hash: pre_hash,
+            pre_hash,
             seed: 0,
             seed_proof: vec![0],
             proposer_id: node_id,
-            signature: Signature::default(),
+            signature: vec![],
             data_block_pointers: vec![],
         }
     }
-    pub fn set_seed(&mut self, seed: u64) {
+    pub fn set_seed(&mut self, seed: u64, seed_proof: Vec<u8>) {
         self.seed = seed;
+        self.seed_proof = seed_proof;
     }
     pub fn push(&mut self, data: HashValue) {
         self.data_block_pointers.push(data);
     }
+    pub fn get_signature(&self) -> &[u8] {
+        &self.signature
+    }
+    pub fn set_signature(&mut self, signature: Vec<u8>) {
+        self.signature = signature;
+    }
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NijikaTestDataBlock {
@@ -79,13 +97,12 @@ pub struct NijikaTestDataBlock {
     round_num: u64,
     packer_id: HashValue,
     signature: Signature,
-    #[serde(with = "BigArray")]
-    merkle_route: [HashValue; 2*M - 1],
-    #[serde(with = "BigArray")]
-    transaction: [Transaction; M],
+    merkle_root: HashValue,
+    transaction: Vec<Transaction>,
 }
 
 impl NijikaBlockT for NijikaTestDataBlock {
+    type Hasher = NijikaSha512;
     fn get_type(&self) -> &NijikaBlockType {
         &self.block_type
     }
@@ -107,19 +124,30 @@ impl NijikaBlockT for NijikaTestDataBlock {
     }
 }
 
-impl NijikaDataBlockT for NijikaTestDataBlock {}
+impl NijikaDataBlockT for NijikaTestDataBlock {
+    fn get_merkle_root(&self) -> &HashValue {
+        &self.merkle_root
+    }
+}
 
 impl NijikaTestDataBlock {
     pub fn new(node_id: HashValue, round_num: u64) -> Self {
         NijikaTestDataBlock {
             block_type: NijikaBlockType::DATA,
-            round_num: round_num,
+            round_num,
             packer_id: node_id,
             signature: Signature::default(),
-            merkle_route: [HashValue::default();2*M-1],
-            transaction: [Transaction::default();M],
+            merkle_root: merkle::root(&[]),
+            transaction: vec![],
         }
     }
+    pub fn set_transactions(&mut self, transaction: Vec<Transaction>) {
+        self.merkle_root = merkle::root(&transaction);
+        self.transaction = transaction;
+    }
+    pub fn get_transactions(&self) -> &[Transaction] {
+        &self.transaction
+    }
 }
 
 pub type DataBlockPool = HashMap<HashValue, NijikaTestDataBlock>;
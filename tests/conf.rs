@@ -1,2 +1,2 @@
 
-pub const TotalWeights: u64 = 1800000;
\ No newline at end of file
+pub const TOTAL_WEIGHTS: u64 = 1800000;
\ No newline at end of file
@@ -1,5 +1,4 @@
 mod node;
-mod network;
 mod conf;
 mod block;
 
@@ -7,7 +6,7 @@ use node::NijikaTestNode;
 
 
 async fn main() {
-    let mut node = NijikaTestNode::new(19).expect("fail to create a new node");
+    let node = NijikaTestNode::new(19).expect("fail to create a new node");
     println!("running");
     node.start();
 }
\ No newline at end of file
@@ -1,8 +0,0 @@
-use crate::network::Message;
-
-#[derive(Debug)]
-pub enum Event {
-    RoundEnd(u64),
-    IncomingMessage(Message),
-    OutgoingMessage(String, Message),
-}
\ No newline at end of file
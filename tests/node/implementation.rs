@@ -1,12 +1,27 @@
 use std::collections::HashMap;
+use openssl::{pkey::{PKey, Id}, sign::{Signer, Verifier}};
 use super::*;
 use nijika::{NijikaPBFTStageApi, NijikaPBFTMessageApi};
+use nijika::network::{NijikaTransportT, NijikaGossipApi, NijikaSyncApi, NijikaSyncRequests, NijikaDataRequests};
+use nijika::storage::{NijikaStorageT, NijikaStorageApi};
 
-use crate::{block::{NijikaTestControlBlock, NijikaTestDataBlock}, conf::TotalWeights};
+use nijika::{NIJIKA_DATA_BLOCK_QUEUE, NIJIKA_PBFT_MSG_QUEUE};
+
+use nijika::{NijikaBlockT, NijikaControlBlockT, NijikaTransactionT};
+
+use nijika::{hash::hash, NijikaStakeRegistry};
+use crate::block::{NijikaTestControlBlock, NijikaTestDataBlock, M};
 
 
 impl<'a> NijikaPBFTStageApi<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue> for NijikaTestNode {}
 impl<'a> NijikaPBFTMessageApi<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue> for NijikaTestNode {}
+impl<'a> NijikaSyncApi<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue> for NijikaTestNode {
+    fn get_sync_requests_mut(&mut self) -> &mut NijikaSyncRequests<HashValue> {
+        &mut self.sync_requests
+    }
+}
+impl<'a> NijikaGossipApi<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue> for NijikaTestNode {}
+impl<'a> NijikaStorageApi<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue> for NijikaTestNode {}
 
 impl<'a> NijikaNodeT<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue> for NijikaTestNode {
     fn get_name(&self) -> &str {
@@ -26,40 +41,74 @@ impl<'a> NijikaNodeT<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue>
     }
 
     fn get_weight(&self) -> u64 {
-        let round_num = self.get_round_num();
-        let len = self.moneys.len();
-        if len as u64 <= round_num {
-            0
-        } else {
-            self.moneys[round_num as usize]
+        self.get_weight_of(self.id)
+    }
+
+    fn get_weight_of(&self, id: HashValue) -> u64 {
+        match hash::serialized(&id) {
+            Ok(account) => self.stake.get_weight_at(self.get_round_num(), &account),
+            Err(_) => 0
         }
     }
 
     fn get_total_weight(&self) -> u64 {
-        TotalWeights
+        self.stake.get_total_at(self.get_round_num())
     }
 
-    fn get_vrf_params(&self) -> (u64, u64) {
-        (self.nijika_round.get_expected(), self.total_weight)
+    fn get_vrf_params(&self, role: NijikaNodeRole) -> (u64, u64) {
+        // about one proposer per view, the whole committee for the other roles
+        let expected = match role {
+            NijikaNodeRole::PROPOSER => 1,
+            _ => EXPECTED_VALIDATORS,
+        };
+        (expected, self.get_total_weight())
     }
 
     fn get_peer_info_mut(&mut self) -> &mut HashMap<HashValue, (String, String)> {
         &mut self.peer_nodes
     }
 
+    fn get_transport(&self) -> &dyn NijikaTransportT {
+        &self.transport
+    }
+
+    fn get_stake_registry(&self) -> Option<&NijikaStakeRegistry> {
+        Some(&self.stake)
+    }
+
+    fn get_stake_registry_mut(&mut self) -> Option<&mut NijikaStakeRegistry> {
+        Some(&mut self.stake)
+    }
+
+    fn get_account_owner(&self, account: &HashValue) -> Option<HashValue> {
+        self.peer_signing_keys.keys().find(|id| hash::serialized(*id).ok().as_ref() == Some(account)).copied()
+    }
+
+    fn get_evidence_pool(&self) -> Option<&NijikaEvidencePool<NijikaTestControlBlock, HashValue>> {
+        Some(&self.evidence)
+    }
+
+    fn get_evidence_pool_mut(&mut self) -> Option<&mut NijikaEvidencePool<NijikaTestControlBlock, HashValue>> {
+        Some(&mut self.evidence)
+    }
+
+    fn get_storage_mut(&mut self) -> Option<&mut dyn NijikaStorageT> {
+        Some(&mut self.storage)
+    }
+
     fn get_hash_queue(&self, identifier: Option<&str>) -> NijikaResult<&Vec<HashValue>> {
         match identifier {
-            Some("data_block") => Ok(&self.data_block_hash_queue),
-            Some("pbft_msg") => Ok(&self.pbft_msg_hash_queue),
-            _ => Err(NijikaError::ParseError(format!("unknown identifier")))
+            Some(NIJIKA_DATA_BLOCK_QUEUE) => Ok(&self.data_block_hash_queue),
+            Some(NIJIKA_PBFT_MSG_QUEUE) => Ok(&self.pbft_msg_hash_queue),
+            _ => Err(NijikaError::ParseError("unknown identifier".to_string()))
         }
     }
 
     fn get_hash_queue_mut(&mut self, identifier: Option<&str>) -> NijikaResult<&mut Vec<HashValue>> {
         match identifier {
-            Some("data_block") => Ok(&mut self.data_block_hash_queue),
-            Some("pbft_msg") => Ok(&mut self.pbft_msg_hash_queue),
-            _ => Err(NijikaError::ParseError(format!("unknown identifier")))
+            Some(NIJIKA_DATA_BLOCK_QUEUE) => Ok(&mut self.data_block_hash_queue),
+            Some(NIJIKA_PBFT_MSG_QUEUE) => Ok(&mut self.pbft_msg_hash_queue),
+            _ => Err(NijikaError::ParseError("unknown identifier".to_string()))
         }
     }
 
@@ -67,7 +116,7 @@ impl<'a> NijikaNodeT<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue>
         self.vrf_seed
     }
 
-    fn set_vrf_seed(&mut self, seed: u64) -> () {
+    fn set_vrf_seed(&mut self, seed: u64) {
         self.vrf_seed = seed;
     }
 
@@ -79,7 +128,15 @@ impl<'a> NijikaNodeT<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue>
         &self.vrf_public_key
     }
 
-    fn set_keys(&mut self, private_key: Vec<u8>, public_key: Vec<u8>) -> () {
+    fn get_public_key_of(&self, id: HashValue) -> Option<&[u8]> {
+        if id == self.id {
+            Some(&self.vrf_public_key)
+        } else {
+            self.peer_vrf_keys.get(&id).map(|key| key.as_slice())
+        }
+    }
+
+    fn set_keys(&mut self, private_key: Vec<u8>, public_key: Vec<u8>) {
         self.vrf_public_key = public_key;
         self.vrf_secret_key = private_key;
     }
@@ -90,6 +147,27 @@ impl<'a> NijikaNodeT<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue>
         Ok(())
     }
 
+    fn sign(&self, content: &[u8]) -> NijikaResult<Vec<u8>> {
+        let key = PKey::private_key_from_raw_bytes(&self.signing_key, Id::ED25519)
+            .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+        let mut signer = Signer::new_without_digest(&key)
+            .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+        signer.sign_oneshot_to_vec(content).map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))
+    }
+
+    fn verify_signature(&self, signer: HashValue, content: &[u8], signature: &[u8]) -> NijikaResult<bool> {
+        match self.peer_signing_keys.get(&signer) {
+            Some(raw) => {
+                let key = PKey::public_key_from_raw_bytes(raw, Id::ED25519)
+                    .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+                let mut verifier = Verifier::new_without_digest(&key)
+                    .map_err(|e| NijikaError::InvalidSignature(format!("{}", e)))?;
+                Ok(verifier.verify_oneshot(signature, content).unwrap_or(false))
+            },
+            None => Ok(false)
+        }
+    }
+
     fn set_round(&mut self, round: NijikaRound<NijikaTestControlBlock>) -> NijikaResult<()> {
         self.nijika_round = round;
         Ok(())
@@ -116,11 +194,10 @@ impl<'a> NijikaNodeT<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue>
         self.nijika_round.get_control_block().expect("empty block in the round")
     }
 
-    fn new_control_block(&self) -> NijikaTestControlBlock {
-        let last_block = self.ledger.last().expect("unable to access to latest control block");
-        let pre_hash = last_block.hash().unwrap();
+    fn new_control_block(&self, seed: u64, seed_proof: Vec<u8>) -> NijikaTestControlBlock {
+        let pre_hash = self.ledger.get_tip();
         let mut current_block = NijikaTestControlBlock::new(self.id, self.get_round_num(), pre_hash);
-        current_block.set_seed(self.get_vrf_seed());
+        current_block.set_seed(seed, seed_proof);
         current_block
     }
 
@@ -128,33 +205,80 @@ impl<'a> NijikaNodeT<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue>
         let len = self.data_block_hash_queue.len();
         let num = if len > 300 {300} else {len};
         for _i in 0..num {
-            match self.data_block_hash_queue.pop() {
-                Some(b) => block.push(b),
-                _ => ()
+            if let Some(b) = self.data_block_hash_queue.pop() {
+                block.push(b)
             }
         }
+        let signature = self.sign(&block.header_bytes()?)?;
+        block.set_signature(signature);
         Ok(())
     }
 
-    fn commit_control_block(&mut self, block: NijikaTestControlBlock) -> NijikaResult<()> {
-        self.ledger.push(block);
-        Ok(())
+    fn verify_control_block_signature(&self, block: &NijikaTestControlBlock) -> NijikaResult<bool> {
+        self.verify_signature(*block.get_proposer(), &block.header_bytes()?, block.get_signature())
+    }
+
+    fn get_ledger(&self) -> &NijikaLedger<NijikaTestControlBlock> {
+        &self.ledger
+    }
+
+    fn get_ledger_mut(&mut self) -> &mut NijikaLedger<NijikaTestControlBlock> {
+        &mut self.ledger
     }
 
     fn new_data_block(&self) -> NijikaTestDataBlock {
         NijikaTestDataBlock::new(self.id, self.get_round_num())
     }
 
+    fn load_data_block(&mut self, block: &mut NijikaTestDataBlock) -> NijikaResult<()> {
+        let transactions = self.mempool.select((M * 512) as u64);
+        let ids = transactions.iter().map(|tx| tx.get_id()).collect();
+        block.set_transactions(transactions);
+        self.mempool.mark_packed(block.hash()?, ids);
+        Ok(())
+    }
+
+    fn release_packed_transactions(&mut self) -> NijikaResult<()> {
+        self.mempool.release_all();
+        Ok(())
+    }
+
+    fn evict_committed_transactions(&mut self, data_blocks: &[HashValue]) -> NijikaResult<()> {
+        for hash in data_blocks {
+            match self.safe_data_block_pool.get(hash) {
+                Some(block) => {
+                    let ids = block.get_transactions().iter().map(|tx| tx.get_id()).collect::<Vec<HashValue>>();
+                    self.mempool.evict_committed(hash, &ids);
+                },
+                None => self.mempool.await_committed(*hash)
+            }
+        }
+        Ok(())
+    }
+
     fn append_data_block_hash_queue(&mut self, hash: HashValue) -> NijikaResult<()> {
         self.data_block_hash_queue.push(hash);
         Ok(())
     }
 
     fn insert_data_block_pool(&mut self, hash: HashValue, block: NijikaTestDataBlock) -> NijikaResult<()> {
-        match self.safe_data_block_pool.insert(hash, block) {
-            Some(b) => Err(NijikaError::HashCollision(hash)),
-            None => Ok(())
+        let ids = block.get_transactions().iter().map(|tx| tx.get_id()).collect::<Vec<HashValue>>();
+        if self.safe_data_block_pool.insert(hash, block).is_some() {
+            return Err(NijikaError::HashCollision(hash));
         }
+        // a data block committed before it arrived
+        if self.mempool.is_awaited(&hash) {
+            self.mempool.evict_committed(&hash, &ids);
+        }
+        Ok(())
+    }
+
+    fn get_data_block(&self, hash: &HashValue) -> Option<&NijikaTestDataBlock> {
+        self.safe_data_block_pool.get(hash)
+    }
+
+    fn get_data_requests_mut(&mut self) -> &mut NijikaDataRequests<HashValue> {
+        &mut self.data_requests
     }
 
     fn append_pbft_message_queue(&mut self, hash: HashValue) -> NijikaResult<()> {
@@ -164,12 +288,12 @@ impl<'a> NijikaNodeT<'a, NijikaTestControlBlock, NijikaTestDataBlock, HashValue>
 
     fn insert_pbft_message_pool(&mut self, hash: HashValue, message: NijikaPBFTMessage<NijikaTestControlBlock, HashValue>) -> NijikaResult<()> {
         match self.safe_pbft_message_pool.insert(hash, message) {
-            Some(b) => Err(NijikaError::HashCollision(hash)),
+            Some(_) => Err(NijikaError::HashCollision(hash)),
             None => Ok(())
         }
     }
 
-    fn broadcast_hash_message(&self, hash: HashValue, source: Option<HashValue>) -> NijikaResult<()> {
-        todo!()
+    fn get_pbft_message(&self, hash: &HashValue) -> Option<&NijikaPBFTMessage<NijikaTestControlBlock, HashValue>> {
+        self.safe_pbft_message_pool.get(hash)
     }
 }
\ No newline at end of file
@@ -1,18 +1,20 @@
-mod event;
 mod implementation;
 
 use std::{collections::HashMap};
 
-use nijika::{HashValue, NijikaRound, NijikaPBFTMessage, NijikaError, NijikaResult, NijikaNodeRole, NijikaVRFClientS, NijikaNodeT, NijikaBlockT, NijikaPBFTStageApi};
-use tokio::net::TcpListener;
-use tokio::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
-use tokio::{spawn, select};
+use nijika::{HashValue, Transaction, NijikaLedger, NijikaRound, NIJIKA_DEFAULT_QUORUM, NijikaPBFTMessage, NijikaError, NijikaResult, NijikaNodeRole, NijikaVRFClientS, NijikaNodeT, NijikaPBFTStageApi, NijikaRuntime, NijikaStakeRegistry, NIJIKA_STAKE_LOOKBACK};
+use nijika::hash::hash;
+use openssl::pkey::PKey;
 
 use crate::block::{DataBlockPool, NijikaTestControlBlock};
-use crate::conf::{TotalWeights};
-use crate::network::{Message, MessageType, tcp_send};
+use crate::conf::TOTAL_WEIGHTS;
+use nijika::network::{NijikaTcpTransport, NijikaSyncRequests, NijikaDataRequests};
+use nijika::storage::{NijikaMemoryStorage, NijikaStorageApi};
+use nijika::mempool::{NijikaMempool, NijikaMempoolConfig};
+use nijika::evidence::NijikaEvidencePool;
 
-use self::event::Event;
+/// expected validator sub-users of a round view, the quorum is a share of it
+const EXPECTED_VALIDATORS: u64 = 3;
 
 type NijikaMessagePool = HashMap<HashValue, NijikaPBFTMessage<NijikaTestControlBlock, HashValue>>;
 type PeerNodeMap = HashMap<HashValue, (String, String)>;
@@ -25,9 +27,8 @@ pub struct NijikaTestNode {
     id: HashValue,
     /* key: identity::Keypair,
     topic: IdentTopic, */
-    moneys: Vec<u64>,
-    total_weight: u64,
-    ledger: Vec<NijikaTestControlBlock>,
+    stake: NijikaStakeRegistry,
+    ledger: NijikaLedger<NijikaTestControlBlock>,
     peer_nodes: PeerNodeMap,
     data_block_hash_queue: Vec<HashValue>,
     safe_data_block_pool: DataBlockPool,
@@ -39,7 +40,15 @@ pub struct NijikaTestNode {
     vrf_hash: Vec<u8>,
     vrf_public_key: Vec<u8>,
     vrf_secret_key: Vec<u8>,
-    channel: (UnboundedSender<Event>, UnboundedReceiver<Event>),
+    signing_key: Vec<u8>,
+    peer_signing_keys: HashMap<HashValue, Vec<u8>>,
+    peer_vrf_keys: HashMap<HashValue, Vec<u8>>,
+    transport: NijikaTcpTransport,
+    storage: NijikaMemoryStorage,
+    mempool: NijikaMempool<Transaction>,
+    evidence: NijikaEvidencePool<NijikaTestControlBlock, HashValue>,
+    sync_requests: NijikaSyncRequests<HashValue>,
+    data_requests: NijikaDataRequests<HashValue>,
 }
 
 impl NijikaTestNode {
@@ -47,91 +56,61 @@ impl NijikaTestNode {
         let mut vrf_client = NijikaVRFClientS::new_raw();
         if let Ok((p1, p2)) = vrf_client.gen_keys(seed) {
             let rndm = rand::random::<u64>();
+            let id = HashValue::random();
+            let signing_key = PKey::generate_ed25519().ok()?;
+            let mut peer_signing_keys = HashMap::new();
+            peer_signing_keys.insert(id, signing_key.raw_public_key().ok()?);
             Some(Self {
                 name: format!("nijika-node-{}", rndm),
                 ip: String::from("127.0.0.1:13000"),
-                id: HashValue::random(),
-                moneys: vec![1000],
-                total_weight: TotalWeights,
-                ledger: vec![],
+                id,
+                // this node's stake, the rest is held by peers not known yet
+                stake: NijikaStakeRegistry::new(NIJIKA_STAKE_LOOKBACK, [
+                    (hash::serialized(&id).ok()?, 1000),
+                    (HashValue::default(), TOTAL_WEIGHTS - 1000),
+                ]).ok()?,
+                // every node starts from the same genesis block
+                ledger: NijikaLedger::new(NijikaTestControlBlock::new(HashValue::default(), 0, HashValue::default())).ok()?,
                 peer_nodes: PeerNodeMap::new(),
                 data_block_hash_queue: vec![],
                 safe_data_block_pool: DataBlockPool::new(),
                 pbft_msg_hash_queue: vec![],
                 safe_pbft_message_pool: NijikaMessagePool::new(),
                 nijika_round: NijikaRound::default(),
-                vrf_seed: rndm,
+                // the seed of the genesis block
+                vrf_seed: 0,
                 vrf_hash: vec![],
                 vrf_proof: vec![],
                 vrf_public_key: p2,
                 vrf_secret_key: p1,
-                channel: mpsc::unbounded_channel(),
+                signing_key: signing_key.raw_private_key().ok()?,
+                peer_signing_keys,
+                peer_vrf_keys: HashMap::new(),
+                transport: NijikaTcpTransport::new(id),
+                storage: NijikaMemoryStorage::new(),
+                mempool: NijikaMempool::new(NijikaMempoolConfig::default()),
+                evidence: NijikaEvidencePool::new(),
+                sync_requests: NijikaSyncRequests::new(),
+                data_requests: NijikaDataRequests::new(),
             })
         } else {
             None
         }
     }
     fn genesis(&mut self) -> NijikaResult<()> {
-        let genesis = self.new_control_block();
-        self.commit_control_block(genesis);
         let db = self.new_data_block();
-        let hash = db.hash()?;
-        self.append_data_block_hash_queue(hash)?;
-        self.insert_data_block_pool(hash, db)?;
+        self.store_data_block(db)?;
         Ok(())
     }
-    fn handle_message(&mut self, m: Message) {
-        let msg_type = m.get_type();
-        match msg_type {
-            MessageType::Invite => todo!(),
-            MessageType::GetData => todo!(),
-            MessageType::Data => todo!(),
-        }
-    }
     #[tokio::main]
-    pub async fn start(&mut self) {
-        let listener = TcpListener::bind("127.0.0.1:10019").await.unwrap();
-        let network_sender = self.channel.0.clone();
-        spawn(async move {
-            let loop_sender = network_sender.clone();
-            loop {
-                let owned_sender = loop_sender.clone();
-                let (socket, _) = listener.accept().await.unwrap();
-                spawn(async move {
-                    socket.readable().await.unwrap();
-                    let mut buf = [0u8; 2048];
-                    socket.try_read(&mut buf).unwrap();
-                    let msg = bincode::deserialize::<Message>(&buf).unwrap();
-                    owned_sender.send(Event::IncomingMessage(msg)).unwrap();
-                });
-            }
-        });
+    pub async fn start(mut self) {
+        self.restore().unwrap();
         self.genesis().unwrap();
-        let mut round_num = 1;
-        loop {
-            self.start_a_new_round(round_num, 3, 3).unwrap();
-            round_num += 1;
-            select! {
-                Some(e) = self.channel.1.recv() => {
-                    match e {
-                        Event::IncomingMessage(m) => {
-                            self.handle_message(m);
-                        },
-                        Event::RoundEnd(n) => {
-                            if round_num != n {
-                                panic!("wtf");
-                            } else {
-                                println!("round end with {:#?}", self.get_round_control_block())
-                            }
-                        },
-                        Event::OutgoingMessage(t, m) => {
-                            let bytes = bincode::serialize(&m).unwrap();
-                            tcp_send(bytes, t)
-                        }
-                    }
-                }
-            }
+        let transport = self.transport.clone();
+        let mut runtime = NijikaRuntime::new(self, NIJIKA_DEFAULT_QUORUM);
+        runtime.listen(&transport, "127.0.0.1:10019").await.unwrap();
+        if let Err(e) = runtime.run(1, None).await {
+            println!("runtime error: {:?}", e);
         }
     }
 }
-